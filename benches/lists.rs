@@ -0,0 +1,93 @@
+//! Measures time and memory allocated by programs that pass lists and
+//! strings around, which share their contents rather than copy them.
+//!
+//! Run with `cargo bench --bench lists`.
+
+use std::{
+  alloc::{GlobalAlloc, Layout, System},
+  sync::atomic::{AtomicUsize, Ordering},
+  time::Instant,
+};
+
+use tlisp::{tlisp_eval_with_runtime, Runtime};
+
+/// The system allocator, counting the bytes it hands out.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout)
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+    System.realloc(ptr, layout, new_size)
+  }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Defines `xs` as a quoted list of the numbers below `n`.
+fn numbers(n: usize) -> String {
+  let items = (0..n).map(|i| i.to_string()).collect::<Vec<_>>();
+  format!("(define xs '({}))", items.join(" "))
+}
+
+fn programs() -> Vec<(String, String)> {
+  let mut programs = Vec::new();
+
+  for n in [1000, 2000, 4000] {
+    programs.push((
+      format!("sum {} items", n),
+      format!(
+        "{} (defun sum (l acc) (cond (car l) (sum (cdr l) (+ acc (car l))) #t acc)) (sum xs 0)",
+        numbers(n)
+      ),
+    ));
+  }
+
+  programs.push((
+    "pass a string".to_string(),
+    format!(
+      "(define s \"{}\") (defun f (s n) (cond (> n 0) (f s (- n 1)) #t 0)) (f s 20000)",
+      "x".repeat(10000)
+    ),
+  ));
+
+  programs.push((
+    "make closures".to_string(),
+    "(defun adder (n) (lambda (x) \"Adds n to x.\" (+ x n) (+ x n) (+ x n) (+ x n)))
+     (let loop ((i 0)) (cond (< i 20000) (begin (adder i) (loop (+ i 1))) #t i))"
+      .to_string(),
+  ));
+
+  programs
+}
+
+fn main() {
+  println!("{:<16} {:>10} {:>14}", "program", "time", "allocated");
+
+  for (name, program) in programs() {
+    let runtime = Runtime::new();
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    tlisp_eval_with_runtime(&program, "bench.tl", runtime).expect("benchmark failed");
+    let elapsed = start.elapsed();
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - before;
+
+    println!(
+      "{:<16} {:>8.1}ms {:>11.1}MiB",
+      name,
+      elapsed.as_secs_f64() * 1000.0,
+      allocated as f64 / (1024.0 * 1024.0)
+    );
+  }
+}
@@ -0,0 +1,72 @@
+//! Times programs on the tree-walker and on the bytecode VM.
+//!
+//! Run with `cargo bench --bench vm`.
+
+use std::time::{Duration, Instant};
+
+use tlisp::{tlisp_eval_with_runtime, Backend, Runtime};
+
+const RUNS: u32 = 5;
+
+const PROGRAMS: [(&str, &str); 5] = [
+  (
+    "fib",
+    "(defun fib (n) (cond (< n 2) n #t (+ (fib (- n 1)) (fib (- n 2))))) (fib 22)",
+  ),
+  (
+    "sum loop",
+    "(let loop ((i 0) (acc 0)) (cond (< i 300000) (loop (+ i 1) (+ acc i)) #t acc))",
+  ),
+  (
+    "closures",
+    "(defun compose (f g) (lambda (x) (f (g x))))
+     (define inc2 (compose (lambda (x) (+ x 1)) (lambda (x) (+ x 1))))
+     (let loop ((i 0) (acc 0)) (cond (< i 100000) (loop (+ i 1) (inc2 acc)) #t acc))",
+  ),
+  (
+    "let bindings",
+    "(defun f (n) (let* ((a (* n 2)) (b (+ a 1))) (let ((c (- b a))) c)))
+     (let loop ((i 0) (acc 0)) (cond (< i 100000) (loop (+ i 1) (+ acc (f i))) #t acc))",
+  ),
+  (
+    "macros",
+    "(defmacro inc (x) `(+ ,x 1))
+     (let loop ((i 0) (acc 0)) (cond (< i 100000) (loop (inc i) (inc acc)) #t acc))",
+  ),
+];
+
+/// The fastest of a few runs of `program`, and its result.
+fn time(program: &str, backend: Backend) -> (Duration, String) {
+  let mut best = Duration::MAX;
+  let mut result = String::new();
+
+  for _ in 0..RUNS {
+    let runtime = Runtime::new().with_backend(backend);
+    let start = Instant::now();
+    result = tlisp_eval_with_runtime(program, "bench.tl", runtime).expect("benchmark failed");
+    best = best.min(start.elapsed());
+  }
+
+  (best, result)
+}
+
+fn main() {
+  println!(
+    "{:<14} {:>12} {:>12} {:>8}",
+    "program", "tree-walker", "bytecode", "speedup"
+  );
+
+  for (name, program) in PROGRAMS {
+    let (walked, expected) = time(program, Backend::TreeWalker);
+    let (compiled, result) = time(program, Backend::Bytecode);
+    assert_eq!(result, expected, "{} gave a different result", name);
+
+    println!(
+      "{:<14} {:>10.1}ms {:>10.1}ms {:>7.2}x",
+      name,
+      walked.as_secs_f64() * 1000.0,
+      compiled.as_secs_f64() * 1000.0,
+      walked.as_secs_f64() / compiled.as_secs_f64()
+    );
+  }
+}
@@ -0,0 +1,621 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::environment::Environment;
+use crate::eval::{
+  alias_renames, assigned_symbol, eval_lambda, eval_let_bindings, eval_symbol, expand_macro,
+  map_call, vector_call,
+};
+use crate::object::{Lambda, List, Object, Params, Pattern};
+use crate::span::Span;
+use crate::syntax_rules::Renames;
+
+/// How many macro calls deep the compiler expands calls of macros found in
+/// expansions, so that a macro that expands to a call of itself does not
+/// expand forever. Deeper calls are expanded when they run.
+const MAX_EXPANSIONS: usize = 16;
+
+/// An instruction of the stack machine in `vm`. Instructions that can fail
+/// carry the span of the innermost form they were compiled from.
+#[derive(Clone)]
+pub enum Op {
+  Const(Object),
+  /// Pushes the value of a symbol.
+  Load(String, Span),
+  /// Pushes the value in a slot of the environment `depth` scopes up, or
+  /// that of the symbol if a scope on the way has defined it. Looks the
+  /// symbol up by name if the slot is not bound yet.
+  Local(usize, usize, String, Span),
+  /// Pops a value and defines a symbol to it, pushing `#nil`.
+  Define(String),
+  /// Pops a value and assigns it to a bound symbol, pushing `#nil`.
+  Set(String, Span),
+  /// Like `Set`, for a symbol resolved to a slot as in `Local`.
+  SetLocal(usize, usize, String, Span),
+  Pop,
+  Jump(usize),
+  /// Pops a value and jumps if it is false or `#nil`.
+  JumpIfFalse(usize),
+  /// Jumps if the value on top is false or `#nil`, keeping it as the value
+  /// of the `and`; pops it otherwise.
+  And(usize),
+  /// Jumps unless the value on top is false or `#nil`, keeping it as the
+  /// value of the `or`; pops it otherwise.
+  Or(usize),
+  /// Applies an operator to that many values on top.
+  Operator(String, usize, Span),
+  /// If the value on top is the macro the call was expanded with when it
+  /// was compiled, pops it and goes on with the compiled expansion that
+  /// follows, recording what it renamed; jumps to the call otherwise.
+  Expanded {
+    macro_: Object,
+    renames: Renames,
+    target: usize,
+  },
+  /// If the value on top is a macro, pops it and runs the expansion of the
+  /// call `form` instead, then goes on at `target`. The expansion is
+  /// compiled once and kept for as long as the macro stays the same.
+  Expand {
+    form: Object,
+    target: usize,
+    tail: bool,
+    span: Span,
+    cache: RefCell<Option<Rc<Expansion>>>,
+  },
+  /// Calls the function below `argc` arguments; a tail call replaces the
+  /// running function. `name` is what the call is reported as in errors.
+  Call {
+    argc: usize,
+    tail: bool,
+    name: String,
+    span: Span,
+  },
+  /// Moves the value on top below that many values.
+  Insert(usize),
+  /// Pushes a copy of the lambda closing over the current environment.
+  Closure(Rc<Lambda>),
+  PushScope,
+  PopScope,
+  /// Pops a value and binds a pattern to it in the current environment.
+  Bind(Pattern, Span),
+  Return,
+  /// Evaluates a form with the tree-walker in the current environment.
+  Walk(Object, Span),
+}
+
+/// The compiled body of a function or a top-level form.
+pub struct Code {
+  pub ops: Vec<Op>,
+  /// Whether it, or a function in it, leaves a form to the tree-walker.
+  pub walks: bool,
+  /// Whether it, or a function in it, refers to `call/cc`.
+  pub captures: bool,
+}
+
+/// The compiled expansion of a macro call.
+pub struct Expansion {
+  pub macro_: Object,
+  pub renames: Renames,
+  pub code: Rc<Code>,
+}
+
+/// Compiles forms to bytecode. What it does not compile is left to the
+/// tree-walker through `Walk`: `try`, `let/ec`, `generator`, `defmacro`,
+/// `define-syntax`, quasiquotes and forms that are malformed, so that they
+/// fail the same way.
+///
+/// Calls of macros already defined are expanded as they are compiled, and
+/// run as long as the head of the call stays that macro.
+///
+/// Symbols bound by the functions and scopes being compiled are resolved to
+/// slots, as their environments bind them in order. A `define`, whether
+/// compiled or run by `eval` or a macro expansion, never takes a slot, so
+/// the slots stay put; `Local` still finds one that shadows its symbol.
+struct Compiler<'a> {
+  env: &'a Rc<RefCell<Environment>>,
+  ops: Vec<Op>,
+  scopes: Vec<Scope>,
+  /// How many expansions the form being compiled is nested in.
+  expansions: usize,
+  walks: bool,
+  captures: bool,
+}
+
+/// What the compiler knows of a scope of the environment at runtime.
+#[derive(Default)]
+struct Scope {
+  /// The symbols the scope binds, in the order it binds them.
+  names: Vec<String>,
+}
+
+/// Compiles the top-level form `form`, found at `span`. `env` is where the
+/// lambdas it contains are parsed; they close over the environment they are
+/// created in when run.
+pub fn compile(form: &Object, span: &Span, env: &Rc<RefCell<Environment>>) -> Rc<Code> {
+  let mut compiler = Compiler {
+    env,
+    ops: Vec::new(),
+    scopes: Vec::new(),
+    expansions: 0,
+    walks: false,
+    captures: false,
+  };
+  compiler.form(form, false, span);
+  compiler.ops.push(Op::Return);
+
+  Rc::new(Code {
+    ops: compiler.ops,
+    walks: compiler.walks,
+    captures: compiler.captures,
+  })
+}
+
+fn is_call_cc(symbol: &str) -> bool {
+  symbol == "call/cc" || symbol == "call-with-current-continuation"
+}
+
+/// Whether `obj` refers to `call/cc` anywhere.
+fn mentions_call_cc(obj: &Object) -> bool {
+  match obj {
+    Object::Symbol(s) => is_call_cc(s),
+    Object::List(list, _) => list.iter().any(mentions_call_cc),
+    Object::Quote(o) | Object::Quasiquote(o) | Object::Unquote(o) | Object::UnquoteSplicing(o) => {
+      mentions_call_cc(o)
+    }
+    Object::Pair(pair) => {
+      mentions_call_cc(&pair.car.borrow()) || mentions_call_cc(&pair.cdr.borrow())
+    }
+    Object::Vector(items) => items.borrow().iter().any(mentions_call_cc),
+    _ => false,
+  }
+}
+
+/// Collects the symbols a pattern binds, in the order `bind_pattern` binds
+/// them.
+fn pattern_names(pattern: &Pattern, names: &mut Vec<String>) {
+  match pattern {
+    Pattern::Symbol(s) => names.push(s.clone()),
+    Pattern::List(patterns, rest) => {
+      for pattern in patterns {
+        pattern_names(pattern, names);
+      }
+      if let Some(rest) = rest {
+        pattern_names(rest, names);
+      }
+    }
+  }
+}
+
+impl Scope {
+  fn declare(&mut self, names: Vec<String>) {
+    for name in names {
+      if !self.names.contains(&name) {
+        self.names.push(name);
+      }
+    }
+  }
+}
+
+impl Compiler<'_> {
+  fn form(&mut self, obj: &Object, tail: bool, span: &Span) {
+    match obj {
+      Object::Symbol(s) if s.len() > 1 && s.starts_with(':') => {
+        self.ops.push(Op::Const(obj.clone()))
+      }
+      Object::Symbol(s) => match self.resolve(s) {
+        None if is_call_cc(s) => {
+          self.captures = true;
+          self.ops.push(Op::Load(s.clone(), span.clone()))
+        }
+        Some((depth, index)) => self
+          .ops
+          .push(Op::Local(depth, index, s.clone(), span.clone())),
+        None => self.ops.push(Op::Load(s.clone(), span.clone())),
+      },
+      Object::List(list, own) if !list.is_empty() => {
+        let span = match own.is_known() {
+          true => own,
+          false => span,
+        };
+        if !self.list(obj, list, tail, span) {
+          self.walk(obj, span);
+        }
+      }
+      Object::Quasiquote(_) | Object::Unquote(_) | Object::UnquoteSplicing(_) | Object::Pair(_) => {
+        self.walk(obj, span)
+      }
+      Object::Vector(items) => {
+        let call = Object::List(vector_call(&items.borrow()), span.clone());
+        self.form(&call, tail, span)
+      }
+      Object::Map(map) if !map.is_empty() => {
+        let call = Object::List(map_call(map), span.clone());
+        self.form(&call, tail, span)
+      }
+      _ => self.ops.push(Op::Const(obj.clone())),
+    }
+  }
+
+  /// Compiles the item at `index` of `list`, found at its own span if it is
+  /// known and within `span` otherwise.
+  fn item(&mut self, list: &List, index: usize, tail: bool, span: &Span) {
+    self.form(&list[index], tail, list.span_of(index).unwrap_or(span));
+  }
+
+  /// Finds the slot a symbol is bound in, if the scopes being compiled bind
+  /// it.
+  fn resolve(&self, symbol: &str) -> Option<(usize, usize)> {
+    self
+      .scopes
+      .iter()
+      .rev()
+      .enumerate()
+      .find_map(|(depth, scope)| {
+        let index = scope.names.iter().position(|name| name == symbol)?;
+        Some((depth, index))
+      })
+  }
+
+  /// Starts compiling a scope binding `names` first.
+  fn push_scope(&mut self, names: Vec<String>) {
+    let mut scope = Scope::default();
+    scope.declare(names);
+    self.scopes.push(scope);
+  }
+
+  fn scope(&mut self) -> &mut Scope {
+    self.scopes.last_mut().expect("no scope is being compiled")
+  }
+
+  fn walk(&mut self, obj: &Object, span: &Span) {
+    self.walks = true;
+    self.captures |= mentions_call_cc(obj);
+    self.ops.push(Op::Walk(obj.clone(), span.clone()));
+  }
+
+  /// Points the jump at `at` to the next instruction.
+  fn patch(&mut self, at: usize) {
+    let next = self.ops.len();
+
+    match &mut self.ops[at] {
+      Op::Jump(target)
+      | Op::JumpIfFalse(target)
+      | Op::And(target)
+      | Op::Or(target)
+      | Op::Expanded { target, .. }
+      | Op::Expand { target, .. } => *target = next,
+      _ => {}
+    }
+  }
+
+  fn sequence(&mut self, forms: &List, tail: bool, span: &Span) {
+    if forms.is_empty() {
+      return self.ops.push(Op::Const(Object::Void));
+    }
+
+    for i in 0..forms.len() - 1 {
+      self.item(forms, i, false, span);
+      self.ops.push(Op::Pop);
+    }
+    self.item(forms, forms.len() - 1, tail, span);
+  }
+
+  /// Compiles the call or special form `list`. Returns `false`, having
+  /// compiled nothing, for what is left to the tree-walker.
+  fn list(&mut self, form: &Object, list: &List, tail: bool, span: &Span) -> bool {
+    match &list[0] {
+      Object::Operator(_) if list.len() < 2 => return false,
+      Object::Operator(op) if op == "and" || op == "or" => self.logic(op, list, tail, span),
+      Object::Operator(op) => {
+        for i in 1..list.len() {
+          self.item(list, i, false, span);
+        }
+        self
+          .ops
+          .push(Op::Operator(op.clone(), list.len() - 1, span.clone()));
+      }
+      Object::Keyword(k) => return self.keyword(k, list, tail, span),
+      Object::Cond if list.len() % 2 != 1 => return false,
+      Object::Cond => self.cond(list, tail, span),
+      head => {
+        self.item(list, 0, false, span);
+
+        let end = match self.expansion(head, list) {
+          Some((macro_, expansion, renames)) => {
+            let guard = self.ops.len();
+            self.ops.push(Op::Expanded {
+              macro_,
+              renames,
+              target: 0,
+            });
+            self.expansions += 1;
+            self.form(&expansion, tail, span);
+            self.expansions -= 1;
+
+            let end = self.ops.len();
+            self.ops.push(Op::Jump(0));
+            self.patch(guard);
+            Some(end)
+          }
+          None => None,
+        };
+
+        let expand = self.ops.len();
+        self.ops.push(Op::Expand {
+          form: form.clone(),
+          target: 0,
+          tail,
+          span: span.clone(),
+          cache: RefCell::new(None),
+        });
+
+        for i in 1..list.len() {
+          self.item(list, i, false, span);
+        }
+
+        let name = match head {
+          Object::Symbol(s) => s.clone(),
+          _ => "lambda".to_string(),
+        };
+        self.ops.push(Op::Call {
+          argc: list.len() - 1,
+          tail,
+          name,
+          span: span.clone(),
+        });
+        self.patch(expand);
+        if let Some(end) = end {
+          self.patch(end);
+        }
+      }
+    }
+
+    true
+  }
+
+  /// Expands the call `list` if `head` names a macro already, returning the
+  /// macro with the expansion and what it renamed. The renames are recorded
+  /// in the environment the expansion is parsed in; the `Expanded` guard
+  /// records them again wherever it runs.
+  fn expansion(&self, head: &Object, list: &List) -> Option<(Object, Object, Renames)> {
+    let macro_ = match head {
+      Object::Symbol(s) if self.expansions < MAX_EXPANSIONS && self.resolve(s).is_none() => {
+        eval_symbol(s, self.env).ok()?
+      }
+      _ => return None,
+    };
+
+    if !matches!(macro_, Object::Macro(_) | Object::Syntax(_, _)) {
+      return None;
+    }
+
+    let (expansion, renames) = expand_macro(&macro_, list).ok()?;
+    alias_renames(&macro_, &renames, self.env);
+    Some((macro_, expansion, renames))
+  }
+
+  fn keyword(&mut self, keyword: &str, list: &List, tail: bool, span: &Span) -> bool {
+    match keyword {
+      "begin" => self.sequence(&list.tail(1), tail, span),
+      "do" => {
+        self.ops.push(Op::PushScope);
+        self.push_scope(Vec::new());
+        self.sequence(&list.tail(1), tail, span);
+        self.scopes.pop();
+        self.ops.push(Op::PopScope);
+      }
+      "define" | "set!" => {
+        let symbol = match assigned_symbol(list, keyword) {
+          Ok(symbol) => symbol.to_string(),
+          Err(_) => return false,
+        };
+
+        self.item(list, 2, false, span);
+        match (keyword, self.resolve(&symbol)) {
+          ("define", _) => self.ops.push(Op::Define(symbol)),
+          (_, Some((depth, index))) => {
+            self
+              .ops
+              .push(Op::SetLocal(depth, index, symbol, span.clone()))
+          }
+          _ => self.ops.push(Op::Set(symbol, span.clone())),
+        }
+      }
+      "defun" => {
+        let name = match list.get(1) {
+          Some(Object::Symbol(name)) if list.len() >= 4 => name,
+          _ => return false,
+        };
+
+        match eval_lambda(Some(name), &list[2], list.tail(3), self.env) {
+          Ok(lambda) => self.closure(lambda),
+          Err(_) => return false,
+        }
+        self.ops.push(Op::Define(name.clone()));
+      }
+      "lambda" if list.len() >= 3 => match eval_lambda(None, &list[1], list.tail(2), self.env) {
+        Ok(lambda) => self.closure(lambda),
+        Err(_) => return false,
+      },
+      "let" | "let*" | "letrec" => return self.let_form(keyword, list, tail, span),
+      _ => return false,
+    }
+
+    true
+  }
+
+  /// Compiles the body of a function, its last form in tail position, in a
+  /// scope of its parameters.
+  fn closure(&mut self, lambda: Lambda) {
+    let params = &lambda.params;
+    let mut names = Vec::new();
+    for pattern in params.required.iter() {
+      pattern_names(pattern, &mut names);
+    }
+    names.extend(params.optional.iter().map(|(name, _)| name.clone()));
+    names.extend(params.keys.iter().map(|(name, _)| name.clone()));
+    names.extend(params.rest.clone());
+
+    let ops = std::mem::take(&mut self.ops);
+    let walks = std::mem::take(&mut self.walks);
+    let captures = std::mem::take(&mut self.captures);
+    self.push_scope(names);
+    self.sequence(&lambda.body, true, &Span::default());
+    self.ops.push(Op::Return);
+    self.scopes.pop();
+
+    let code = Code {
+      ops: std::mem::replace(&mut self.ops, ops),
+      walks: self.walks,
+      captures: self.captures,
+    };
+    self.walks |= walks;
+    self.captures |= captures;
+
+    self.ops.push(Op::Closure(Rc::new(Lambda {
+      code: Some(Rc::new(code)),
+      ..lambda
+    })));
+  }
+
+  fn logic(&mut self, op: &str, list: &List, tail: bool, span: &Span) {
+    let mut jumps = Vec::new();
+
+    for i in 1..list.len() {
+      let last = i + 1 == list.len();
+      self.item(list, i, tail && last, span);
+
+      if !last {
+        jumps.push(self.ops.len());
+        self.ops.push(match op {
+          "and" => Op::And(0),
+          _ => Op::Or(0),
+        });
+      }
+    }
+
+    for jump in jumps {
+      self.patch(jump);
+    }
+  }
+
+  fn cond(&mut self, list: &List, tail: bool, span: &Span) {
+    let mut ends = Vec::new();
+
+    for test in (1..list.len()).step_by(2) {
+      self.item(list, test, false, span);
+      let skip = self.ops.len();
+      self.ops.push(Op::JumpIfFalse(0));
+
+      self.item(list, test + 1, tail, span);
+      ends.push(self.ops.len());
+      self.ops.push(Op::Jump(0));
+      self.patch(skip);
+    }
+
+    self.ops.push(Op::Const(Object::Void));
+    for end in ends {
+      self.patch(end);
+    }
+  }
+
+  /// `let` and `letrec` push every value before binding them, `let*` binds
+  /// each as soon as it is computed. A named `let` binds its loop function
+  /// in a new scope and calls it with values computed outside of it.
+  fn let_form(&mut self, form: &str, list: &List, tail: bool, span: &Span) -> bool {
+    let named = match (form, list.get(1)) {
+      ("let", Some(Object::Symbol(name))) => Some(name),
+      _ => None,
+    };
+
+    let (bindings, body) = match named {
+      Some(_) if list.len() >= 4 => (&list[2], list.tail(3)),
+      None if list.len() >= 3 => (&list[1], list.tail(2)),
+      _ => return false,
+    };
+
+    let bindings = match eval_let_bindings(bindings, form) {
+      Ok(bindings) => bindings,
+      Err(_) => return false,
+    };
+
+    let argc = bindings.len();
+
+    if let Some(name) = named {
+      for (_, value, span) in bindings.iter() {
+        self.form(value, false, span);
+      }
+
+      self.ops.push(Op::PushScope);
+      self.push_scope(vec![name.clone()]);
+      self.closure(Lambda {
+        name: Some(name.clone()),
+        params: Params {
+          required: bindings.into_iter().map(|(pattern, ..)| pattern).collect(),
+          ..Default::default()
+        },
+        body: list.tail(3),
+        env: self.env.clone(),
+        doc: None,
+        code: None,
+      });
+      self
+        .ops
+        .push(Op::Bind(Pattern::Symbol(name.clone()), span.clone()));
+      self.ops.push(Op::Local(0, 0, name.clone(), span.clone()));
+      self.ops.push(Op::Insert(argc));
+      self.ops.push(Op::Call {
+        argc,
+        tail,
+        name: name.clone(),
+        span: span.clone(),
+      });
+      self.scopes.pop();
+      self.ops.push(Op::PopScope);
+      return true;
+    }
+
+    let names = |pattern: &Pattern| {
+      let mut names = Vec::new();
+      pattern_names(pattern, &mut names);
+      names
+    };
+
+    match form {
+      "let*" => {
+        self.ops.push(Op::PushScope);
+        self.push_scope(Vec::new());
+        for (pattern, value, value_span) in bindings {
+          self.form(&value, false, &value_span);
+          self.ops.push(Op::Bind(pattern.clone(), span.clone()));
+          self.scope().declare(names(&pattern));
+        }
+      }
+      _ => {
+        let outside = form == "let";
+        if !outside {
+          self.ops.push(Op::PushScope);
+          self.push_scope(Vec::new());
+          for (pattern, ..) in bindings.iter().rev() {
+            self.scope().declare(names(pattern));
+          }
+        }
+        for (_, value, value_span) in bindings.iter() {
+          self.form(value, false, value_span);
+        }
+        if outside {
+          self.ops.push(Op::PushScope);
+          self.push_scope(Vec::new());
+        }
+        for (pattern, ..) in bindings.into_iter().rev() {
+          self.scope().declare(names(&pattern));
+          self.ops.push(Op::Bind(pattern, span.clone()));
+        }
+      }
+    }
+
+    self.sequence(&body, tail, span);
+    self.scopes.pop();
+    self.ops.push(Op::PopScope);
+    true
+  }
+}
@@ -1,24 +1,37 @@
 use crate::{object::Object, runtime};
 use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
 
+/// Frames with more names than this look them up through a hash map
+/// rather than by comparing them one by one.
+const INDEXED: usize = 8;
+
+/// A frame of bindings. Values of parameters and `let` variables sit in the
+/// order their names were first bound, so compiled code that knows that
+/// order reads them by position. What `define` adds is kept by name apart
+/// from them, as it may run anywhere and would move the positions.
 #[derive(Debug)]
 pub struct Environment {
   parent: Option<Rc<RefCell<Environment>>>,
-  vars: HashMap<String, Object>,
+  names: Vec<String>,
+  values: Vec<Object>,
+  /// The position of every name, kept once there are more than `INDEXED`.
+  index: HashMap<String, usize>,
+  defined: HashMap<String, Object>,
+  /// The symbols `syntax-rules` expansions evaluated here renamed, with
+  /// their original names and the environments of the macros.
+  aliases: HashMap<String, (String, Rc<RefCell<Environment>>)>,
   runtime: runtime::Runtime,
 }
 
-impl PartialEq for Environment {
-  fn eq(&self, other: &Self) -> bool {
-    self.vars == other.vars
-  }
-}
-
 impl Environment {
   pub fn new(runtime: runtime::Runtime) -> Self {
     Environment {
       parent: None,
-      vars: HashMap::new(),
+      names: Vec::new(),
+      values: Vec::new(),
+      index: HashMap::new(),
+      defined: HashMap::new(),
+      aliases: HashMap::new(),
       runtime,
     }
   }
@@ -26,40 +39,160 @@ impl Environment {
   pub fn extend(parent: Rc<RefCell<Self>>) -> Environment {
     let runtime = parent.borrow().runtime.clone();
     Environment {
-      vars: HashMap::new(),
       parent: Some(parent),
+      names: Vec::new(),
+      values: Vec::new(),
+      index: HashMap::new(),
+      defined: HashMap::new(),
+      aliases: HashMap::new(),
       runtime,
     }
   }
 
-  pub fn get_runtime_fn(&self, name: &str) -> Option<Rc<runtime::RuntimeFn>> {
+  pub fn get_runtime_fn(&self, name: &str) -> Option<runtime::NativeFn> {
     let runtime = self.runtime.clone();
 
-    match runtime.get_method(name) {
-      Some(f) => Some(f.clone()),
-      None => None,
+    runtime.get_method(name).cloned()
+  }
+
+  pub fn max_depth(&self) -> usize {
+    self.runtime.max_depth()
+  }
+
+  pub fn backend(&self) -> runtime::Backend {
+    self.runtime.backend()
+  }
+
+  fn position(&self, name: &str) -> Option<usize> {
+    match self.names.len() > INDEXED {
+      true => self.index.get(name).copied(),
+      false => self.names.iter().position(|n| n == name),
     }
   }
 
   pub fn get(&self, name: &str) -> Option<Object> {
-    match self.vars.get(name) {
-      Some(value) => Some(value.clone()),
-      None => self.parent.as_ref().and_then(|o| o.borrow().get(name)),
+    match self.position(name) {
+      Some(i) => Some(self.values[i].clone()),
+      None => match self.defined.get(name) {
+        Some(value) => Some(value.clone()),
+        None => self.parent.as_ref().and_then(|o| o.borrow().get(name)),
+      },
+    }
+  }
+
+  /// The value at position `index` of the frame `depth` parents up, or
+  /// that of `name` if a frame on the way has defined it.
+  pub fn get_at(&self, depth: usize, index: usize, name: &str) -> Option<Object> {
+    match depth {
+      0 => self.values.get(index).cloned(),
+      _ => match self.defined.get(name) {
+        Some(value) => Some(value.clone()),
+        None => self
+          .parent
+          .as_ref()?
+          .borrow()
+          .get_at(depth - 1, index, name),
+      },
+    }
+  }
+
+  /// Changes the value at position `index` of the frame `depth` parents
+  /// up, or that of `name` if a frame on the way has defined it, giving the
+  /// value back if there is no such position.
+  pub fn assign_at(
+    &mut self,
+    depth: usize,
+    index: usize,
+    name: &str,
+    val: Object,
+  ) -> Result<(), Object> {
+    if depth > 0 {
+      if let Some(var) = self.defined.get_mut(name) {
+        *var = val;
+        return Ok(());
+      }
+    }
+
+    match depth {
+      0 => match self.values.get_mut(index) {
+        Some(var) => {
+          *var = val;
+          Ok(())
+        }
+        None => Err(val),
+      },
+      _ => match self.parent {
+        Some(ref parent) => parent.borrow_mut().assign_at(depth - 1, index, name, val),
+        None => Err(val),
+      },
     }
   }
 
+  /// Binds `name` in this frame, in its old position if it is bound here
+  /// already and after the other names otherwise.
+  pub fn bind(&mut self, name: &str, val: Object) {
+    if let Some(i) = self.position(name) {
+      self.values[i] = val;
+      return;
+    }
+
+    self.defined.remove(name);
+    self.names.push(name.to_string());
+    self.values.push(val);
+
+    if self.names.len() > INDEXED {
+      if self.index.is_empty() {
+        self.index = (self.names.iter().cloned()).zip(0..).collect();
+      } else {
+        self.index.insert(name.to_string(), self.names.len() - 1);
+      }
+    }
+  }
+
+  /// Defines `name` in this frame, changing its value if it is bound here
+  /// already and adding it by name without a position otherwise.
   pub fn set(&mut self, name: &str, val: Object) {
-    self.vars.insert(name.to_string(), val);
+    match self.position(name) {
+      Some(i) => self.values[i] = val,
+      None => {
+        self.defined.insert(name.to_string(), val);
+      }
+    }
   }
 
-  pub fn update(&mut self, data: Rc<RefCell<Self>>) {
-    self.vars.extend(
-      data
-        .borrow()
-        .vars
-        .iter()
-        .map(|(k, v)| (k.clone(), v.clone())),
-    );
+  /// Records that `alias` was renamed from `name` by a macro defined in
+  /// `env`.
+  pub fn alias(&mut self, alias: &str, name: &str, env: Rc<RefCell<Environment>>) {
+    self
+      .aliases
+      .insert(alias.to_string(), (name.to_string(), env));
+  }
+
+  /// The original name of `alias` and the environment of the macro that
+  /// renamed it, if an expansion evaluated here or in a parent did.
+  pub fn dealias(&self, alias: &str) -> Option<(String, Rc<RefCell<Environment>>)> {
+    match self.aliases.get(alias) {
+      Some(original) => Some(original.clone()),
+      None => self.parent.as_ref()?.borrow().dealias(alias),
+    }
+  }
+
+  /// Changes an existing binding of `name`, in this frame or the closest
+  /// parent defining it. Returns `false` if `name` is unbound.
+  pub fn assign(&mut self, name: &str, val: Object) -> bool {
+    if let Some(i) = self.position(name) {
+      self.values[i] = val;
+      return true;
+    }
+
+    match (self.defined.get_mut(name), &self.parent) {
+      (Some(var), _) => {
+        *var = val;
+        true
+      }
+      (None, Some(parent)) => parent.borrow_mut().assign(name, val),
+      (None, None) => false,
+    }
   }
 }
 
@@ -67,11 +200,16 @@ impl fmt::Display for Environment {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     let mut vars_str = String::new();
 
-    for (k, v) in self.vars.iter() {
+    for (k, v) in self
+      .names
+      .iter()
+      .zip(self.values.iter())
+      .chain(&self.defined)
+    {
       vars_str.push_str(&format!("{}: {}\n", k, v));
     }
 
-    let _ = match self.parent {
+    match self.parent {
       Some(ref parent) => {
         vars_str.push_str(&format!("parent: {}\n", parent.borrow()));
       }
@@ -0,0 +1,182 @@
+use std::{error::Error, fmt, num::NonZeroUsize};
+
+use crate::object::Object;
+use crate::span::{locate, Span};
+
+/// What kind of failure an [`EvalError`] is.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+  /// A symbol with no binding was evaluated or assigned; holds its name.
+  UnboundSymbol(String),
+  /// A value of the wrong type was given, e.g. adding a string to a number
+  /// or calling something that is not a function.
+  TypeError,
+  /// A function or macro was called with the wrong number of arguments.
+  ArityError,
+  DivisionByZero,
+  /// Evaluation nested deeper than the runtime's maximum depth.
+  RecursionLimit,
+  /// A special form, lambda list or pattern is malformed.
+  SyntaxError,
+  /// The source could not be tokenized or parsed.
+  ParseError,
+  /// Raised by the program itself with `error`.
+  User,
+  /// `next` was called on a generator whose body has returned.
+  GeneratorExhausted,
+  /// An index was outside of a vector.
+  IndexOutOfRange,
+}
+
+impl ErrorKind {
+  /// The keyword `error-kind` returns for errors of this kind.
+  pub fn keyword(&self) -> &'static str {
+    match self {
+      ErrorKind::UnboundSymbol(_) => ":unbound-symbol",
+      ErrorKind::TypeError => ":type-error",
+      ErrorKind::ArityError => ":arity-error",
+      ErrorKind::DivisionByZero => ":division-by-zero",
+      ErrorKind::RecursionLimit => ":recursion-limit",
+      ErrorKind::SyntaxError => ":syntax-error",
+      ErrorKind::ParseError => ":parse-error",
+      ErrorKind::User => ":user",
+      ErrorKind::GeneratorExhausted => ":generator-exhausted",
+      ErrorKind::IndexOutOfRange => ":index-out-of-range",
+    }
+  }
+}
+
+/// A function call that was active when an error occurred.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+  pub function: String,
+  /// Where the call was made.
+  pub span: Span,
+}
+
+/// An error raised while reading or evaluating a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalError {
+  pub kind: ErrorKind,
+  pub message: String,
+  /// The value given to `error` along with the message, `#nil` otherwise.
+  pub data: Box<Object>,
+  /// The innermost form being evaluated when the error occurred.
+  pub span: Span,
+  /// The calls active when the error occurred, innermost first.
+  pub stack: Vec<Frame>,
+  /// Set when this is not a failure but a call of a continuation on its
+  /// way out to the evaluation that captured it; see `eval::Unwind`.
+  pub(crate) unwind: Option<NonZeroUsize>,
+}
+
+/// How many frames of the stack are shown when an error is displayed.
+const SHOWN_FRAMES: usize = 10;
+
+impl EvalError {
+  pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+    EvalError {
+      kind,
+      message: message.into(),
+      data: Box::default(),
+      span: Span::default(),
+      stack: Vec::new(),
+      unwind: None,
+    }
+  }
+
+  pub fn unbound(name: &str) -> Self {
+    Self::new(
+      ErrorKind::UnboundSymbol(name.to_string()),
+      format!("Unbound symbol: {}", name),
+    )
+  }
+
+  pub fn type_error(message: impl Into<String>) -> Self {
+    Self::new(ErrorKind::TypeError, message)
+  }
+
+  pub fn arity(message: impl Into<String>) -> Self {
+    Self::new(ErrorKind::ArityError, message)
+  }
+
+  pub fn syntax(message: impl Into<String>) -> Self {
+    Self::new(ErrorKind::SyntaxError, message)
+  }
+
+  pub fn user(message: impl Into<String>, data: Object) -> Self {
+    EvalError {
+      data: Box::new(data),
+      ..Self::new(ErrorKind::User, message)
+    }
+  }
+
+  /// Points the error at `span`, unless it already points somewhere more
+  /// precise.
+  pub fn at(mut self, span: &Span) -> Self {
+    if !self.span.is_known() {
+      self.span = span.clone();
+    }
+    self
+  }
+
+  /// Records that the error passed through a call of `function` made at
+  /// `span`.
+  pub fn called_from(mut self, function: &str, span: &Span) -> Self {
+    self.stack.push(Frame {
+      function: function.to_string(),
+      span: span.clone(),
+    });
+    self
+  }
+}
+
+impl Error for EvalError {}
+
+impl fmt::Display for EvalError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", locate(self.message.clone(), &self.span))?;
+
+    for frame in self.stack.iter().take(SHOWN_FRAMES) {
+      match frame.span.is_known() {
+        true => write!(f, "\n  in {} at {}", frame.function, frame.span)?,
+        false => write!(f, "\n  in {}", frame.function)?,
+      }
+    }
+
+    if self.stack.len() > SHOWN_FRAMES {
+      write!(f, "\n  ... {} more calls", self.stack.len() - SHOWN_FRAMES)?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use super::*;
+
+  #[test]
+  fn test_display() {
+    let span = |line| Span::new(Rc::from("a.tl"), line, 1);
+    let err = EvalError::unbound("x")
+      .at(&span(3))
+      .at(&span(1))
+      .called_from("f", &span(5))
+      .called_from("lambda", &Span::default());
+
+    assert_eq!(err.kind, ErrorKind::UnboundSymbol("x".to_string()));
+    assert_eq!(
+      err.to_string(),
+      "Unbound symbol: x\n  --> a.tl:3:1\n  in f at a.tl:5:1\n  in lambda"
+    );
+
+    let mut err = EvalError::new(ErrorKind::RecursionLimit, "Too deep");
+    for _ in 0..12 {
+      err = err.called_from("g", &Span::default());
+    }
+    assert!(err.to_string().ends_with("\n  in g\n  ... 2 more calls"));
+  }
+}
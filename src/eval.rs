@@ -1,15 +1,96 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+  cell::{Cell, RefCell},
+  collections::HashMap,
+  fmt,
+  num::NonZeroUsize,
+  rc::Rc,
+};
 
 use crate::environment::Environment;
-use crate::object::Object;
+use crate::error::{ErrorKind, EvalError};
+use crate::object::{Arity, Lambda, List, Map, Object, Pair, Params, Pattern};
 use crate::operators;
-use crate::parser::parse;
+use crate::parser::parse_program;
+use crate::runtime::Backend;
+use crate::span::Span;
+use crate::syntax_rules::{Renames, SyntaxRules};
+use crate::vm;
+
+thread_local! {
+  /// How many calls and evaluations are nested on this thread.
+  pub(crate) static DEPTH: Cell<usize> = const { Cell::new(0) };
+  /// The ids of the machines running on this thread, innermost last.
+  static RUNNING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+  static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+  /// The continuation calls unwinding on this thread, by the ids of the
+  /// errors carrying them.
+  static UNWINDS: RefCell<HashMap<NonZeroUsize, Unwind>> = RefCell::new(HashMap::new());
+}
+
+/// A number not handed out before on this thread.
+fn next_id() -> usize {
+  NEXT_ID.with(|id| {
+    id.set(id.get() + 1);
+    id.get()
+  })
+}
+
+fn is_running(id: usize) -> bool {
+  RUNNING.with(|running| running.borrow().contains(&id))
+}
+
+pub(crate) fn new_scope(env: &Rc<RefCell<Environment>>) -> Rc<RefCell<Environment>> {
+  Rc::new(RefCell::new(Environment::extend(env.clone())))
+}
+
+/// The symbol a `define` or `set!` form assigns.
+pub(crate) fn assigned_symbol<'a>(list: &'a [Object], form: &str) -> Result<&'a str, EvalError> {
+  if list.len() != 3 {
+    return Err(EvalError::syntax(format!(
+      "Invalid number of forms for {}",
+      form
+    )));
+  }
+
+  match &list[1] {
+    Object::Symbol(s) => Ok(s),
+    _ => Err(EvalError::syntax(format!("Invalid symbol for {}", form))),
+  }
+}
+
+pub(crate) fn define(symbol: &str, value: Object, env: &Rc<RefCell<Environment>>) {
+  let value = match value {
+    Object::Lambda(lambda) if lambda.name.is_none() => Object::Lambda(Rc::new(Lambda {
+      name: Some(symbol.to_string()),
+      ..(*lambda).clone()
+    })),
+    value => value,
+  };
+  env.borrow_mut().set(symbol, value);
+}
+
+pub(crate) fn assign(
+  symbol: &str,
+  value: Object,
+  env: &Rc<RefCell<Environment>>,
+) -> Result<(), EvalError> {
+  if env.borrow_mut().assign(symbol, value.clone()) {
+    return Ok(());
+  }
+
+  let original = env.borrow().dealias(symbol);
+  match original {
+    Some((name, env)) => assign(&name, value, &env),
+    None => Err(EvalError::new(
+      ErrorKind::UnboundSymbol(symbol.to_string()),
+      format!("Cannot set! unbound symbol: {}", symbol),
+    )),
+  }
+}
 
-fn eval_symbol(s: &str, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
+pub(crate) fn eval_symbol(s: &str, env: &Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
   let val = match s {
-    "#t" => return Ok(Object::Bool(true)),
-    "#f" => return Ok(Object::Bool(false)),
-    "#nil" => return Ok(Object::Void),
+    s if s.len() > 1 && s.starts_with(':') => return Ok(Object::Symbol(s.to_string())),
     _ => env.borrow().get(s),
   };
 
@@ -17,674 +98,3234 @@ fn eval_symbol(s: &str, env: &mut Rc<RefCell<Environment>>) -> Result<Object, St
     Some(val) => val,
     None => match env.borrow().get_runtime_fn(s) {
       Some(_f) => Object::Native(s.to_string()),
-      None => return Err(format!("Unbound symbol: {}", s)),
+      None => {
+        let original = env.borrow().dealias(s);
+        return match original {
+          Some((name, env)) => eval_symbol(&name, &env),
+          None => Err(EvalError::unbound(s)),
+        };
+      }
     },
   };
 
   Ok(val.clone())
 }
 
-fn eval_do(list: &Vec<Object>, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let mut result = Object::Void;
-  let mut new_env = Rc::new(RefCell::new(Environment::extend(env.clone())));
-
-  for obj in list[1..].iter() {
-    result = eval_object(obj, &mut new_env)?;
+/// `obj` if it is a `(name ...)` clause.
+fn clause<'a>(obj: &'a Object, name: &str) -> Option<&'a List> {
+  match obj {
+    Object::List(list, _) => match list.first() {
+      Some(Object::Symbol(s)) if s == name => Some(list),
+      _ => None,
+    },
+    _ => None,
   }
-  Ok(result)
 }
 
-fn eval_define(list: &Vec<Object>, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  if list.len() != 3 {
-    return Err(format!("Invalid number of forms for define"));
+/// Parses a binding target: a symbol, or a list of patterns optionally
+/// ending with `. rest`.
+fn eval_pattern(obj: &Object) -> Result<Pattern, EvalError> {
+  match obj {
+    Object::Symbol(s) if s != "." => Ok(Pattern::Symbol(s.clone())),
+    Object::List(list, _) => {
+      let dot = list
+        .iter()
+        .position(|o| matches!(o, Object::Symbol(s) if s == "."));
+
+      let (items, rest) = match dot {
+        Some(dot) if dot + 2 == list.len() => (&list[..dot], Some(&list[dot + 1])),
+        Some(_) => {
+          return Err(EvalError::syntax(format!(
+            "Invalid pattern {}: expected one pattern after .",
+            obj
+          )))
+        }
+        None => (&list[..], None),
+      };
+
+      let patterns = items.iter().map(eval_pattern).collect::<Result<_, _>>()?;
+      let rest = match rest {
+        Some(rest) => Some(Box::new(eval_pattern(rest)?)),
+        None => None,
+      };
+
+      Ok(Pattern::List(patterns, rest))
+    }
+    Object::Pair(_) => {
+      let mut patterns = Vec::new();
+      let mut rest = obj.clone();
+
+      while let Object::Pair(pair) = rest {
+        patterns.push(eval_pattern(&pair.car.borrow())?);
+        rest = pair.cdr.borrow().clone();
+      }
+
+      Ok(Pattern::List(
+        patterns,
+        Some(Box::new(eval_pattern(&rest)?)),
+      ))
+    }
+    _ => Err(EvalError::syntax(format!("Invalid pattern {}", obj))),
   }
+}
 
-  let symbol = match &list[1] {
-    Object::Symbol(s) => s,
-    _ => return Err(format!("Invalid symbol for define")),
+/// Binds `value` to `pattern` in `env`. A list pattern takes a list value
+/// apart the way `car` and `cdr` would; names get the value through `bound`.
+pub(crate) fn bind_pattern(
+  pattern: &Pattern,
+  value: Object,
+  bound: &dyn Fn(Object) -> Object,
+  env: &mut Rc<RefCell<Environment>>,
+) -> Result<(), EvalError> {
+  let (patterns, rest) = match pattern {
+    Pattern::Symbol(s) => {
+      env.borrow_mut().bind(s, bound(value));
+      return Ok(());
+    }
+    Pattern::List(patterns, rest) => (patterns, rest),
   };
 
-  let value = eval_object(&list[2], env)?;
-  env.borrow_mut().set(symbol, value);
-  Ok(Object::Void)
+  let mismatch = || EvalError::type_error(format!("Pattern {} does not match {}", pattern, value));
+
+  let mut rest_value = match &value {
+    Object::Quote(o) => (**o).clone(),
+    o => o.clone(),
+  };
+  if !matches!(rest_value, Object::List(..) | Object::Pair(_)) {
+    return Err(mismatch());
+  }
+
+  for pattern in patterns.iter() {
+    let (item, tail) = rest_value.split_first().ok_or_else(mismatch)?;
+    bind_pattern(pattern, item, bound, env)?;
+    rest_value = tail;
+  }
+
+  match rest {
+    Some(rest) => bind_pattern(rest, rest_value, bound, env),
+    None
+      if rest_value
+        .list_items()
+        .is_some_and(|items| items.is_empty()) =>
+    {
+      Ok(())
+    }
+    None => Err(mismatch()),
+  }
+}
+
+/// Which part of a lambda list the parameters being read belong to.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum ParamSection {
+  Required,
+  Optional,
+  Rest,
+  Key,
 }
 
-fn eval_defun(list: &Vec<Object>, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  if list.len() != 4 {
-    return Err(format!("Invalid number of forms for defun"));
+/// Reads an `&optional` or `&key` parameter: `name` or `(name default)`.
+fn eval_param_with_default(obj: &Object) -> Result<(String, Object), EvalError> {
+  match obj {
+    Object::Symbol(s) => Ok((s.clone(), Object::Void)),
+    Object::List(list, _) => match &list[..] {
+      [Object::Symbol(s), default] => Ok((s.clone(), default.clone())),
+      _ => Err(EvalError::syntax(format!(
+        "Invalid lambda parameter {}",
+        obj
+      ))),
+    },
+    _ => Err(EvalError::syntax(format!(
+      "Invalid lambda parameter {}",
+      obj
+    ))),
   }
+}
 
-  let name = match &list[1] {
-    Object::Symbol(s) => s,
-    _ => return Err(format!("Invalid symbol for defun")),
+/// Parses a lambda list: required parameters, then optionally `&optional`,
+/// `&rest` and `&key` sections, in that order.
+fn eval_params(obj: &Object) -> Result<Params, EvalError> {
+  let list = match obj {
+    Object::List(list, _) => list,
+    _ => return Err(EvalError::syntax("Expected list of parameters")),
   };
 
-  let params = match &list[2] {
-    Object::List(l) => {
-      let mut params = Vec::new();
+  let mut params = Params::default();
+  let mut section = ParamSection::Required;
 
-      for obj in l {
-        match obj {
-          Object::Symbol(s) => params.push(s.clone()),
-          _ => return Err(format!("Invalid lambda parameter {:?}", params)),
-        }
+  for obj in list.iter() {
+    let marker = match obj {
+      Object::Symbol(s) if s == "&optional" => Some(ParamSection::Optional),
+      Object::Symbol(s) if s == "&rest" => Some(ParamSection::Rest),
+      Object::Symbol(s) if s == "&key" => Some(ParamSection::Key),
+      _ => None,
+    };
+
+    if let Some(marker) = marker {
+      if marker <= section {
+        return Err(EvalError::syntax(format!(
+          "Misplaced {} in lambda list {}",
+          obj,
+          Object::list(list.to_vec())
+        )));
+      }
+      if section == ParamSection::Rest && params.rest.is_none() {
+        return Err(EvalError::syntax("Expected a parameter after &rest"));
       }
 
-      params
+      section = marker;
+      continue;
     }
-    _ => return Err(format!("Expected list of parameters")),
-  };
 
-  let body = list.get(3).unwrap().to_owned();
+    match section {
+      ParamSection::Required => params.required.push(eval_pattern(obj)?),
+      ParamSection::Optional => params.optional.push(eval_param_with_default(obj)?),
+      ParamSection::Rest => match obj {
+        Object::Symbol(s) if params.rest.is_none() => params.rest = Some(s.clone()),
+        Object::Symbol(_) => return Err(EvalError::syntax("Only one parameter may follow &rest")),
+        _ => {
+          return Err(EvalError::syntax(format!(
+            "Invalid lambda parameter {}",
+            obj
+          )))
+        }
+      },
+      ParamSection::Key => params.keys.push(eval_param_with_default(obj)?),
+    }
+  }
 
-  let lambda = Object::Lambda(params, Box::new(body), env.clone());
-  env.borrow_mut().set(&name, lambda);
+  if section == ParamSection::Rest && params.rest.is_none() {
+    return Err(EvalError::syntax("Expected a parameter after &rest"));
+  }
 
-  Ok(Object::Void)
+  Ok(params)
 }
 
-fn eval_cond(
-  list: &Vec<Object>,
+/// Binds the arguments `args` of a call of `name` to the lambda list
+/// `params` in `env`. Missing optional and keyword arguments take their
+/// default, evaluated in `env` so it can refer to earlier parameters.
+/// When `forms` is set the arguments are the unevaluated forms of a macro
+/// call and are bound quoted, the rest parameter as one quoted list.
+pub(crate) fn bind_params(
+  name: &str,
+  params: &Params,
+  args: Vec<Object>,
+  forms: bool,
   env: &mut Rc<RefCell<Environment>>,
-) -> Result<Box<Object>, String> {
-  if (list.len() % 2) != 1 {
-    return Err(format!("Cond requires an even number of forms"));
-  }
+) -> Result<(), EvalError> {
+  params.arity().check(name, args.len())?;
 
-  let args_pairs = list[1..].chunks(2);
-  for args_pair in args_pairs {
-    let cond_obj = args_pair.get(0).unwrap_or(&Object::Void);
-    let body_ob = args_pair.get(1).unwrap_or(&Object::Void);
+  let bound = |arg: Object| match forms {
+    true => quote_form(&arg),
+    false => arg,
+  };
+  let is_key = |obj: &Object, key: &str| match obj {
+    Object::Symbol(s) => s.strip_prefix(':') == Some(key),
+    _ => false,
+  };
 
-    let cond_result = match eval_object(cond_obj, env)? {
-      Object::Bool(b) => b,
-      Object::Void => false,
-      _ => true,
-    };
+  let mut args = args.into_iter();
 
-    if cond_result {
-      return Ok(Box::new(body_ob.clone()));
-    }
+  for pattern in params.required.iter() {
+    bind_pattern(pattern, args.next().unwrap_or_default(), &bound, env)?;
   }
 
-  Ok(Box::new(Object::Void))
-}
+  for (param, default) in params.optional.iter() {
+    let value = match args.next() {
+      Some(value) => bound(value),
+      None => eval_object(default, env)?,
+    };
+    env.borrow_mut().bind(param, value);
+  }
 
-fn eval_let(list: &Vec<Object>, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let mut result = Object::Void;
-  let mut bindings_env = Rc::new(RefCell::new(Environment::extend(env.clone())));
+  let rest = args.collect::<Vec<Object>>();
 
-  if list.len() < 3 {
-    return Err(format!("Invalid number of forms for let"));
-  }
+  if !params.keys.is_empty() {
+    if rest.len() % 2 != 0 {
+      return Err(EvalError::arity(format!(
+        "Odd number of keyword arguments for {}",
+        name
+      )));
+    }
 
-  let bindings = match list[1].clone() {
-    Object::List(list) => list,
-    _ => return Err(format!("Invalid bindings for let")),
-  };
+    for pair in rest.chunks(2) {
+      if !params.keys.iter().any(|(key, _)| is_key(&pair[0], key)) {
+        return Err(EvalError::arity(format!(
+          "Unknown keyword argument {} for {}",
+          pair[0], name
+        )));
+      }
+    }
 
-  for binding in bindings {
-    let binding = match binding {
-      Object::List(list) => list,
-      _ => return Err(format!("Invalid binding for let")),
-    };
+    for (param, default) in params.keys.iter() {
+      let given = rest.chunks(2).find(|pair| is_key(&pair[0], param));
 
-    if binding.len() != 2 {
-      return Err(format!("Invalid binding for let"));
+      let value = match given {
+        Some(pair) => bound(pair[1].clone()),
+        None => eval_object(default, env)?,
+      };
+      env.borrow_mut().bind(param, value);
     }
+  }
 
-    let symbol = match &binding[0] {
-      Object::Symbol(s) => s,
-      _ => return Err(format!("Invalid symbol for let")),
+  if let Some(param) = &params.rest {
+    let value = match forms {
+      true => quote_form(&Object::list(rest)),
+      false => Object::list(rest),
     };
-
-    let value = eval_object(&binding[1], &mut bindings_env)?;
-    bindings_env.borrow_mut().set(symbol, value);
+    env.borrow_mut().bind(param, value);
   }
 
-  let mut new_env = Rc::new(RefCell::new(Environment::extend(env.clone())));
-  new_env.borrow_mut().update(bindings_env);
+  Ok(())
+}
 
-  for obj in list[2..].iter() {
-    result = eval_object(obj, &mut new_env)?;
-  }
-  Ok(result)
+/// Builds a lambda from its parameter list and body forms. A string that
+/// is followed by more forms is the docstring, not part of the body.
+pub(crate) fn eval_lambda(
+  name: Option<&str>,
+  params: &Object,
+  body: List,
+  env: &Rc<RefCell<Environment>>,
+) -> Result<Lambda, EvalError> {
+  let params = eval_params(params)?;
+
+  let (doc, body) = match &body[..] {
+    [Object::String(doc), rest @ ..] if !rest.is_empty() => (Some(doc.clone()), body.tail(1)),
+    _ => (None, body),
+  };
+
+  Ok(Lambda {
+    name: name.map(str::to_string),
+    params,
+    body,
+    env: env.clone(),
+    doc,
+    code: None,
+  })
 }
 
-fn eval_function_definition(
-  list: &Vec<Object>,
-  env: &mut Rc<RefCell<Environment>>,
-) -> Result<Object, String> {
-  let params = match &list[1] {
-    Object::List(list) => {
-      let mut params = Vec::new();
-
-      for obj in list {
-        match obj {
-          Object::Symbol(s) => params.push(s.clone()),
-          _ => return Err(format!("Invalid lambda parameter {:?}", params)),
-        }
-      }
+fn eval_defun(list: &List, env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  if list.len() < 4 {
+    return Err(EvalError::syntax("Invalid number of forms for defun"));
+  }
 
-      params
-    }
-    _ => return Err(format!("Expected list of parameters")),
+  let name = match &list[1] {
+    Object::Symbol(s) => s,
+    _ => return Err(EvalError::syntax("Invalid symbol for defun")),
   };
 
-  let body = list.get(2).unwrap().to_owned();
+  let lambda = eval_lambda(Some(name), &list[2], list.tail(3), env)?;
+  env.borrow_mut().set(name, Object::Lambda(Rc::new(lambda)));
 
-  Ok(Object::Lambda(params, Box::new(body), env.clone()))
+  Ok(Object::Void)
 }
 
-fn eval_operator(list: &Vec<Object>, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  if list.len() < 2 {
-    let operator = list.get(0).unwrap_or(&Object::Void);
-    return Err(format!(
-      "Invalid number of arguments for operator {}",
-      operator
-    ));
-  }
-  let operator = list[0].clone();
-
-  let mut params = list[1..].into_iter().map(|o| eval_object(&o, env));
-
-  match operator {
-    Object::Operator(s) => match s.as_str() {
-      "+" => operators::sum(&mut params),
-      "-" => operators::sub(&mut params),
-      "*" => operators::mult(&mut params),
-      "/" => operators::div(&mut params),
-      "%" => operators::mod_(&mut params),
-      "<" => operators::lt(&mut params),
-      ">" => operators::gt(&mut params),
-      "=" => operators::eq(&mut params),
-      "==" => operators::strict_eq(&mut params),
-      "and" => operators::and(&mut params),
-      "or" => operators::or(&mut params),
-      _ => Err(format!("Invalid infix operator: {}", s)),
-    },
-    _ => Err(format!("Operator must be a symbol")),
+fn eval_defmacro(list: &List, env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  if list.len() < 4 {
+    return Err(EvalError::syntax("Invalid number of forms for defmacro"));
   }
+
+  let name = match &list[1] {
+    Object::Symbol(s) => s,
+    _ => return Err(EvalError::syntax("Invalid symbol for defmacro")),
+  };
+
+  let lambda = eval_lambda(Some(name), &list[2], list.tail(3), env)?;
+  env.borrow_mut().set(name, Object::Macro(Rc::new(lambda)));
+
+  Ok(Object::Void)
 }
 
-fn eval_function_call(
-  s: &str,
-  list: &Vec<Object>,
+fn eval_define_syntax(
+  list: &[Object],
   env: &mut Rc<RefCell<Environment>>,
-) -> Result<(Box<Object>, Rc<RefCell<Environment>>), String> {
-  let symbol = env.borrow().get(s);
-
-  if symbol.is_none() {
-    return Err(format!("Unbound symbol: {}", s));
+) -> Result<Object, EvalError> {
+  if list.len() != 3 {
+    return Err(EvalError::syntax(
+      "Invalid number of forms for define-syntax",
+    ));
   }
 
-  let lambda = symbol.unwrap();
-  match lambda {
-    Object::Lambda(params, body, func_env) => {
-      let new_env = Rc::new(RefCell::new(Environment::extend(func_env.clone())));
+  let name = match &list[1] {
+    Object::Symbol(s) => s,
+    _ => return Err(EvalError::syntax("Invalid symbol for define-syntax")),
+  };
 
-      for (i, param) in params.iter().enumerate() {
-        let arg = list.get(i + 1);
+  let rules = SyntaxRules::new(&list[2])?;
+  let syntax = Object::Syntax(Rc::new(rules), env.clone());
+  env.borrow_mut().set(name, syntax);
 
-        match arg {
-          Some(arg) => {
-            let val = eval_object(&arg, env)?;
-            new_env.borrow_mut().set(param, val);
-          }
-          None => return Err(format!("Invalid number of arguments for {}", s)),
-        }
-      }
+  Ok(Object::Void)
+}
 
-      Ok((body, new_env.clone()))
-    }
-    _ => {
-      return Err(format!("{} is not a function", s));
-    }
+/// Turns an unevaluated argument form into the value a macro parameter is
+/// bound to: code is quoted, self-evaluating literals are passed as is.
+fn quote_form(form: &Object) -> Object {
+  match form {
+    Object::Integer(_) | Object::Float(_) | Object::String(_) | Object::Bool(_) => form.clone(),
+    Object::Void => Object::Void,
+    _ => Object::Quote(Rc::new(form.clone())),
   }
 }
 
-fn eval_anonymus_function_call(
-  list: &Vec<Object>,
-  env: &mut Rc<RefCell<Environment>>,
-) -> Result<(Box<Object>, Rc<RefCell<Environment>>), String> {
-  let lambda = &list[0];
-  match lambda {
-    Object::Lambda(params, body, func_env) => {
-      let new_env = Rc::new(RefCell::new(Environment::extend(func_env.clone())));
-
-      for (i, param) in params.iter().enumerate() {
-        let object = match list.get(i + 1) {
-          Some(o) => o,
-          None => return Err(format!("Invalid number of arguments for lambda")),
-        };
-        let val = eval_object(object, env)?;
-        new_env.borrow_mut().set(param, val);
-      }
-      Ok((body.to_owned(), new_env))
+/// Expands one call of the macro `macro_`. A `defmacro` macro has its
+/// parameters bound to the unevaluated argument forms and its body computes
+/// the replacement form; a `define-syntax` one rewrites the call by pattern,
+/// also returning what it renamed, for `alias_renames`.
+pub(crate) fn expand_macro(
+  macro_: &Object,
+  list: &[Object],
+) -> Result<(Object, Renames), EvalError> {
+  match macro_ {
+    Object::Macro(lambda) => {
+      let mut new_env = Rc::new(RefCell::new(Environment::extend(lambda.env.clone())));
+      let name = format!("macro {}", list[0]);
+      let forms = list[1..].to_vec();
+      bind_params(&name, &lambda.params, forms, true, &mut new_env)?;
+
+      let expansion = match eval_body(lambda.body.clone(), &new_env)? {
+        Object::Quote(o) => pairs_to_lists(&o),
+        o => pairs_to_lists(&o),
+      };
+      Ok((expansion, Renames::new()))
     }
-    _ => return Err(format!("Not a lambda")),
+    Object::Syntax(rules, _) => rules.expand(list),
+    _ => Err(EvalError::type_error("Not a macro")),
   }
 }
 
-fn eval_keyword(list: &Vec<Object>, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let head = &list[0];
-  match head {
-    Object::Keyword(s) => match s.as_str() {
-      "define" => eval_define(&list, env),
-      "defun" => eval_defun(&list, env),
-      "lambda" => eval_function_definition(&list, env),
-      "let" => eval_let(&list, env),
-      "do" => eval_do(&list, env),
-      _ => Err(format!("Unknown keyword: {}", s)),
-    },
-    _ => {
-      return Err(format!("Invalid keyword: {}", head));
+/// Records in `env`, where an expansion of `macro_` runs, the symbols it
+/// renamed, so that they resolve where the macro was defined.
+pub(crate) fn alias_renames(macro_: &Object, renames: &Renames, env: &Rc<RefCell<Environment>>) {
+  if let Object::Syntax(_, definition) = macro_ {
+    for (name, alias) in renames {
+      env.borrow_mut().alias(alias, name, definition.clone());
     }
   }
 }
 
-fn eval_native(
-  s: &str,
-  list: &Vec<Object>,
+/// Expands `form` once if it is a call of a macro, `None` otherwise.
+pub fn macroexpand_1(
+  form: &Object,
   env: &mut Rc<RefCell<Environment>>,
-) -> Result<Object, String> {
-  let f = env.borrow().get_runtime_fn(&s).unwrap();
-  let mut params = Vec::new();
+) -> Result<Option<Object>, EvalError> {
+  let list = match form {
+    Object::List(list, _) => list,
+    _ => return Ok(None),
+  };
 
-  let rest_params = list.get(1..).unwrap_or_default();
+  let macro_ = match list.first() {
+    Some(Object::Symbol(s)) => eval_symbol(s, env).ok(),
+    _ => None,
+  };
 
-  for obj in rest_params {
-    let result = eval_object(&obj, env)?;
-    params.push(result);
+  match macro_ {
+    Some(macro_ @ (Object::Macro(_) | Object::Syntax(_, _))) => {
+      let (expansion, renames) = expand_macro(&macro_, list)?;
+      alias_renames(&macro_, &renames, env);
+      Ok(Some(expansion))
+    }
+    _ => Ok(None),
   }
-
-  return f(&params, env);
 }
 
-pub fn eval_object(obj: &Object, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let mut current_obj: Box<Object> = Box::new(obj.clone());
-  let mut current_env = env.clone();
+/// Parses the `((pattern value) ...)` bindings of a `let` form, with where
+/// each value starts.
+pub(crate) fn eval_let_bindings(
+  bindings: &Object,
+  form: &str,
+) -> Result<Vec<(Pattern, Object, Span)>, EvalError> {
+  let bindings = match bindings {
+    Object::List(list, _) => list,
+    _ => return Err(EvalError::syntax(format!("Invalid bindings for {}", form))),
+  };
 
-  loop {
-    match *current_obj {
-      Object::List(list) => {
-        let head = &list[0];
-        match head {
-          Object::Operator(_op) => return eval_operator(&list, &mut current_env),
-          Object::Keyword(_k) => return eval_keyword(&list, &mut current_env),
-          Object::Cond => {
-            current_obj = eval_cond(&list, &mut current_env)?;
-            continue;
-          }
-          Object::Symbol(s) => {
-            let symbol = eval_symbol(s, &mut current_env)?;
-
-            match symbol {
-              Object::Lambda(_, _, _) => {
-                (current_obj, current_env) = eval_function_call(&s, &list, &mut current_env)?;
-                continue;
-              }
-              Object::Native(_) => {
-                return eval_native(&s, &list, &mut current_env);
-              }
-              _ => {
-                current_obj = Box::new(symbol);
-                continue;
-              }
-            }
-          }
-          Object::Lambda(_, _, _) => {
-            (current_obj, current_env) = eval_anonymus_function_call(&list, &mut current_env)?;
-            continue;
-          }
-          _ => {
-            let mut new_list = Vec::new();
-            for obj in list {
-              let result = eval_object(&obj, &mut current_env)?;
-              match result {
-                Object::Void => {}
-                _ => new_list.push(result),
-              }
-            }
-
-            let head = new_list.first().unwrap_or(&Object::Void);
-            return match head {
-              Object::Void => Ok(Object::Void),
-              Object::Bool(_) | Object::Integer(_) | Object::Float(_) | Object::String(_) => {
-                Err(format!("Invalid head of list to call: {}", head))
-              }
-              _ => eval_object(&Object::List(new_list), &mut current_env),
-            };
-          }
-        }
+  bindings
+    .iter()
+    .map(|binding| match binding {
+      Object::List(binding, span) if binding.len() == 2 => {
+        let value_span = binding.span_of(1).unwrap_or(span).clone();
+        Ok((eval_pattern(&binding[0])?, binding[1].clone(), value_span))
       }
-      Object::Bool(_) => return Ok(obj.clone()),
-      Object::Integer(n) => return Ok(Object::Integer(n)),
-      Object::Float(n) => return Ok(Object::Float(n)),
-      Object::String(s) => return Ok(Object::String(s.to_string())),
-      Object::Symbol(s) => return eval_symbol(&s, &mut current_env),
-      Object::Lambda(_params, _body, _func_env) => return Ok(Object::Void),
-      Object::Quote(o) => return Ok(Object::Quote(o)),
-      Object::Operator(o) => return Ok(Object::Operator(o)),
-      Object::Keyword(k) => return Ok(Object::Keyword(k)),
-      Object::Void => return Ok(Object::Void),
-      Object::Cond => return Ok(Object::Cond),
-      Object::Native(s) => return Ok(Object::Native(s)),
-    }
-  }
+      _ => Err(EvalError::syntax(format!(
+        "Invalid binding for {}: {}",
+        form, binding
+      ))),
+    })
+    .collect()
 }
 
-pub fn eval(program: &str, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let parsed_list = parse(program);
-  if parsed_list.is_err() {
-    return Err(format!("{}", parsed_list.err().unwrap()));
+fn eval_function_definition(
+  list: &List,
+  env: &mut Rc<RefCell<Environment>>,
+) -> Result<Object, EvalError> {
+  if list.len() < 3 {
+    return Err(EvalError::syntax("Invalid number of forms for lambda"));
   }
-  eval_object(&parsed_list.unwrap(), env)
+
+  let lambda = eval_lambda(None, &list[1], list.tail(2), env)?;
+
+  Ok(Object::Lambda(Rc::new(lambda)))
 }
 
-#[cfg(test)]
-mod tests {
-  use crate::runtime::Runtime;
+/// Which `let` form a `Let` frame evaluates.
+#[derive(Clone, PartialEq)]
+enum LetKind {
+  Let,
+  LetStar,
+  LetRec,
+  /// A named `let`, looping through the function bound to the name.
+  Named(String),
+}
 
-  use super::*;
+/// A `let` form whose binding values are being evaluated, `done` of them
+/// so far. `let*` binds each value as soon as it is known, the others
+/// collect them in `values` first.
+#[derive(Clone)]
+struct LetState {
+  kind: LetKind,
+  form: List,
+  span: Span,
+  bindings: Rc<Vec<(Pattern, Object, Span)>>,
+  done: usize,
+  values: Vec<Object>,
+  env: Rc<RefCell<Environment>>,
+  new_env: Rc<RefCell<Environment>>,
+}
 
-  #[test]
-  fn test_simple_add() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let result = eval("(+ 1 2)", &mut env).unwrap();
-    assert_eq!(result, Object::Integer(3));
-  }
+/// What is left to do with a value once it has been computed. A running
+/// evaluation keeps these on a stack of its own rather than on the Rust
+/// stack, so that `call/cc` can capture it.
+#[derive(Clone)]
+enum Frame {
+  /// Evaluating the call `form`: its head `f`, then its arguments.
+  Call {
+    form: List,
+    span: Span,
+    f: Option<Object>,
+    args: Vec<Object>,
+    env: Rc<RefCell<Environment>>,
+  },
+  /// Evaluating the operand at `next` of an `and`/`or` form.
+  Logic {
+    form: List,
+    span: Span,
+    next: usize,
+    env: Rc<RefCell<Environment>>,
+  },
+  /// Evaluating the test at `next` of a `cond` form.
+  Cond {
+    form: List,
+    span: Span,
+    next: usize,
+    env: Rc<RefCell<Environment>>,
+  },
+  /// The forms of a body from `next` on are left to evaluate.
+  Body {
+    forms: List,
+    next: usize,
+    env: Rc<RefCell<Environment>>,
+  },
+  /// Evaluating the value of a `define` or, with `set`, a `set!` form.
+  Assign {
+    symbol: String,
+    set: bool,
+    span: Span,
+    env: Rc<RefCell<Environment>>,
+  },
+  Let(LetState),
+  /// The body of `lambda`, called at `span`, is running.
+  Function {
+    lambda: Rc<Lambda>,
+    span: Span,
+  },
+  /// The body or handler of a `try` form is running. `catch` and `cleanup`
+  /// are its clauses; `id` tells it apart when a continuation leaves it.
+  Try {
+    id: usize,
+    catch: Option<List>,
+    cleanup: Option<List>,
+    span: Span,
+    env: Rc<RefCell<Environment>>,
+  },
+  /// The cleanup forms of a `try` are running; it ends with this outcome.
+  Cleanup(Result<Object, EvalError>),
+  /// The extent of a `let/ec`, which escapes here.
+  Escape(usize),
+}
 
-  #[test]
-  fn test_lambda_add() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let result = eval("((lambda (x y) (+ x y)) 2 5)", &mut env).unwrap();
-    assert_eq!(result, Object::Integer(7));
-  }
+/// The rest of an evaluation from some point on, as captured by `call/cc`
+/// or `let/ec`. Calling it continues from that point with a new value.
+pub struct Continuation {
+  /// The machine it was captured in.
+  run: usize,
+  target: Target,
+}
 
-  #[test]
-  fn test_area_of_a_circle() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define r 10)
-      (define pi 314)
-      (* pi (* r r)))";
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer((314 * 10 * 10) as i64));
+enum Target {
+  /// The stack of frames when `call/cc` was called, copied.
+  Stack(Vec<Frame>),
+  /// The `Escape` frame of a `let/ec` on the stack it was captured in.
+  Escape(usize),
+  /// Where the VM was when compiled code called `call/cc`.
+  Vm(vm::Resume),
+}
+
+impl Continuation {
+  /// The continuation of a `call/cc` call run by the VM, which resumes it
+  /// itself.
+  pub(crate) fn compiled(resume: vm::Resume) -> Continuation {
+    Continuation {
+      run: next_id(),
+      target: Target::Vm(resume),
+    }
   }
 
-  #[test]
-  fn test_sqr_function() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define sqr (lambda (r) (* r r)))
-      (sqr 10))";
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer((10 * 10) as i64));
+  /// Where the VM resumes the continuation, if the VM captured it.
+  pub(crate) fn resume(&self) -> Option<&vm::Resume> {
+    match &self.target {
+      Target::Vm(resume) => Some(resume),
+      _ => None,
+    }
   }
+}
 
-  #[test]
-  fn test_fibonacci() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define fib
-        (lambda (n)
-          (cond (< n 2) 1
-                #t (+ (fib (- n 1)) (fib (- n 2))))))
-      (fib 10))";
+/// A call of a continuation captured by a machine further out, on its way
+/// there through the Rust calls in between. It travels as an `EvalError`
+/// holding its id, which `try` lets through, running only the cleanups.
+pub(crate) struct Unwind {
+  k: Rc<Continuation>,
+  value: Object,
+}
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer((89) as i64));
+impl Unwind {
+  /// The error that carries the call of `k` with `value` out.
+  fn raise(k: Rc<Continuation>, value: Object) -> EvalError {
+    let id = NonZeroUsize::new(next_id()).expect("ids start at 1");
+    UNWINDS.with(|unwinds| unwinds.borrow_mut().insert(id, Unwind { k, value }));
+
+    EvalError {
+      unwind: Some(id),
+      ..EvalError::new(
+        ErrorKind::TypeError,
+        "Continuation called outside of its evaluation",
+      )
+    }
   }
 
-  #[test]
-  fn test_factorial() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define fact
-        (lambda (n)
-          (cond (< n 1) 1
-                #t (* n (fact (- n 1))))))
-      (fact 5))";
+  /// The continuation `err` carries a call of, if it does.
+  fn target(err: &EvalError) -> Option<Rc<Continuation>> {
+    let id = err.unwind?;
+    UNWINDS.with(|unwinds| Some(unwinds.borrow().get(&id)?.k.clone()))
+  }
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer((120) as i64));
+  /// Takes the call `err` carries, once it has reached its machine.
+  fn take(err: &EvalError) -> Option<Unwind> {
+    let id = err.unwind?;
+    UNWINDS.with(|unwinds| unwinds.borrow_mut().remove(&id))
   }
 
-  #[test]
-  fn test_circle_area_function() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define pi 3.14)
-      (define r 10)
-      (define sqr (lambda (r) (* r r)))
-      (define area (lambda (r) (* pi (sqr r))))
-      (area r))";
+  /// Takes the call `err` carries if it is one of a continuation captured
+  /// by the VM, for the VM to resume.
+  pub(crate) fn take_compiled(err: &EvalError) -> Option<(Rc<Continuation>, Object)> {
+    Unwind::target(err)?.resume()?;
+    let Unwind { k, value } = Unwind::take(err)?;
+    Some((k, value))
+  }
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Float(3.14 * 10.0 * 10.0));
+  /// `err` as a failure, the call it carries being dropped as nothing
+  /// handled it.
+  fn settle(err: EvalError) -> EvalError {
+    Unwind::take(&err);
+    EvalError {
+      unwind: None,
+      ..err
+    }
   }
+}
 
-  #[test]
-  fn test_closure() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define add-n
-          (lambda (n)
-            (lambda (a) (+ n a))))
-      (define add-5 (add-n 5))
-      (add-5 10))";
+/// Continuations are equal only to themselves.
+impl PartialEq for Continuation {
+  fn eq(&self, other: &Self) -> bool {
+    std::ptr::eq(self, other)
+  }
+}
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(15));
+impl fmt::Debug for Continuation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Continuation({})", self.run)
   }
+}
 
-  #[test]
-  fn test_return_function() {
-    let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+/// A body of forms evaluated lazily: each call of `next` runs it up to
+/// its next `yield`, from a function it calls or from the body itself.
+pub struct Generator {
+  body: List,
+  env: Rc<RefCell<Environment>>,
+  state: RefCell<GeneratorState>,
+}
 
-    let program = "(defun const (n) (lambda (a) n))";
-    eval(program, &mut env).unwrap();
+enum GeneratorState {
+  /// `next` has not been called yet.
+  Start,
+  /// Stopped at a `yield`, with the frames left to run after it.
+  Suspended(Vec<Frame>),
+  Running,
+  Done,
+}
 
-    let program = "(const 10)";
-    let result = eval(program, &mut env).unwrap();
+/// Generators are equal only to themselves.
+impl PartialEq for Generator {
+  fn eq(&self, other: &Self) -> bool {
+    std::ptr::eq(self, other)
+  }
+}
+
+impl fmt::Debug for Generator {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Generator({:?})", self.body)
+  }
+}
+
+/// What the machine does next.
+enum Control {
+  /// Evaluate the call or special form `list`.
+  Eval(List, Span, Rc<RefCell<Environment>>),
+  /// Hand a value to the frame on top of the stack.
+  Return(Object),
+  /// Unwind the stack until something handles the error.
+  Raise(EvalError),
+  /// Suspend the generator being run with a value for `next` to return.
+  Yield(Object),
+}
+
+/// The name a call of `f` made with `head` goes by in errors.
+fn call_name<'a>(head: &'a Object, f: &'a Object) -> &'a str {
+  match (head, f) {
+    (Object::Symbol(s), _) | (_, Object::Native(s)) => s,
+    _ => "lambda",
+  }
+}
+
+/// The name the function value `f` goes by in errors.
+pub(crate) fn value_name(f: &Object) -> &str {
+  match f {
+    Object::Native(name) | Object::Operator(name) => name,
+    Object::Lambda(lambda) => lambda.name(),
+    _ => "lambda",
+  }
+}
+
+/// The function and arguments of `(apply f arg ... list)`: the `arg`s
+/// followed by the elements of `list`.
+pub(crate) fn spread_args(args: Vec<Object>) -> Result<(Object, Vec<Object>), EvalError> {
+  let mut args = args.into_iter();
+  let f = match args.next() {
+    Some(f) => f,
+    None => return Err(EvalError::arity("Expected a function to apply")),
+  };
+
+  let mut spread: Vec<Object> = args.collect();
+  let list = match spread.pop() {
+    Some(list) => list,
+    None => return Err(EvalError::arity("Expected a list of arguments to apply")),
+  };
+
+  let items = match &list {
+    Object::Quote(o) => o.list_items(),
+    o => o.list_items(),
+  };
+  let items = items.ok_or_else(|| {
+    EvalError::type_error(format!(
+      "Expected a list of arguments to apply, got {}",
+      list
+    ))
+  })?;
+
+  spread.extend(items);
+  Ok((f, spread))
+}
+
+/// The call to `vector` a `[...]` literal of `items` evaluates as.
+pub(crate) fn vector_call(items: &[Object]) -> List {
+  let mut call = Vec::with_capacity(items.len() + 1);
+  call.push(Object::Native("vector".to_string()));
+  call.extend(items.iter().cloned());
+
+  List::from(call)
+}
+
+/// The call to `assoc` a non-empty `{...}` literal `map` evaluates as.
+pub(crate) fn map_call(map: &Map) -> List {
+  let mut call = Vec::with_capacity(map.len() * 2 + 2);
+  call.push(Object::Native("assoc".to_string()));
+  call.push(Object::Map(Rc::default()));
+  for (key, value) in map.iter() {
+    call.push(key.clone());
+    call.push(value.clone());
+  }
+
+  List::from(call)
+}
+
+/// Applies the operator `op` to evaluated arguments.
+pub(crate) fn apply_operator(op: &str, args: Vec<Object>) -> Result<Object, EvalError> {
+  if args.is_empty() {
+    return Err(EvalError::arity(format!(
+      "Invalid number of arguments for operator {}",
+      op
+    )));
+  }
+
+  let mut args = args.into_iter().map(Ok);
+
+  match op {
+    "+" => operators::sum(&mut args),
+    "-" => operators::sub(&mut args),
+    "*" => operators::mult(&mut args),
+    "/" => operators::div(&mut args),
+    "%" => operators::mod_(&mut args),
+    "<" => operators::lt(&mut args),
+    ">" => operators::gt(&mut args),
+    "=" => operators::eq(&mut args),
+    "==" => operators::strict_eq(&mut args),
+    "and" => operators::and(&mut args),
+    "or" => operators::or(&mut args),
+    _ => Err(EvalError::syntax(format!("Invalid infix operator: {}", op))),
+  }
+}
+
+/// Evaluates forms step by step, with a stack of frames in place of
+/// recursion, so that tail calls do not grow it and continuations can
+/// capture it. Rust code that evaluates forms, such as a macro expansion,
+/// runs a machine of its own nested in the current one.
+struct Machine {
+  id: usize,
+  stack: Vec<Frame>,
+  /// How many `Function` frames are on the stack.
+  calls: usize,
+  /// The depth of the thread when the machine started.
+  depth: usize,
+  /// Whether the machine runs the body of a generator, so `yield` can
+  /// suspend it.
+  generator: bool,
+  /// Whether the machine stopped at a `yield` rather than at the end.
+  yielded: bool,
+}
+
+impl Machine {
+  fn new() -> Machine {
+    let machine = Machine {
+      id: next_id(),
+      stack: Vec::new(),
+      calls: 0,
+      depth: DEPTH.with(Cell::get),
+      generator: false,
+      yielded: false,
+    };
+
+    DEPTH.with(|depth| depth.set(machine.depth + 1));
+    RUNNING.with(|running| running.borrow_mut().push(machine.id));
+    machine
+  }
+
+  fn set_calls(&mut self, calls: usize) {
+    self.calls = calls;
+    DEPTH.with(|depth| depth.set(self.depth + 1 + calls));
+  }
+
+  /// Steps through `control` and whatever follows until the stack is empty
+  /// or the machine yields.
+  fn run(&mut self, control: Control) -> Result<Object, EvalError> {
+    let mut control = control;
+
+    loop {
+      control = match control {
+        Control::Eval(list, span, env) => self
+          .eval_list(&list, &span, &env)
+          .unwrap_or_else(|err| Control::Raise(err.at(&span))),
+        Control::Return(value) => match self.stack.pop() {
+          Some(frame) => self.resume(frame, value),
+          None => return Ok(value),
+        },
+        Control::Raise(err) => self.unwind(err)?,
+        Control::Yield(value) => {
+          self.yielded = true;
+          return Ok(value);
+        }
+      };
+    }
+  }
+
+  /// Starts evaluating `obj`. Only calls and special forms take steps,
+  /// anything else is evaluated at once.
+  fn eval(&self, obj: &Object, env: &Rc<RefCell<Environment>>) -> Control {
+    self.eval_at(obj, None, env)
+  }
+
+  /// Starts evaluating the item at `index` of `list`, errors in it pointing
+  /// at where it starts.
+  fn eval_item(&self, list: &List, index: usize, env: &Rc<RefCell<Environment>>) -> Control {
+    self.eval_at(&list[index], list.span_of(index), env)
+  }
+
+  /// Starts evaluating `obj`, found at `span` if it is known, so that errors
+  /// in atoms, vectors and maps point at them rather than at the enclosing
+  /// list.
+  fn eval_at(&self, obj: &Object, span: Option<&Span>, env: &Rc<RefCell<Environment>>) -> Control {
+    let at = |own: &Span| match span {
+      Some(span) if !own.is_known() => span.clone(),
+      _ => own.clone(),
+    };
+
+    let value = match obj {
+      Object::List(list, own) if !list.is_empty() => {
+        return Control::Eval(list.clone(), at(own), env.clone())
+      }
+      Object::Symbol(s) => eval_symbol(s, env),
+      Object::Vector(items) => {
+        return Control::Eval(
+          vector_call(&items.borrow()),
+          at(&Span::default()),
+          env.clone(),
+        )
+      }
+      Object::Map(map) if !map.is_empty() => {
+        return Control::Eval(map_call(map), at(&Span::default()), env.clone())
+      }
+      Object::Quasiquote(o) => {
+        eval_quasiquote(o, 1, &mut env.clone()).map(|filled| Object::Quote(Rc::new(filled)))
+      }
+      Object::Unquote(_) | Object::UnquoteSplicing(_) => Err(EvalError::syntax(format!(
+        "{} used outside of a quasiquote",
+        obj
+      ))),
+      Object::Pair(_) => match pairs_to_lists(obj) {
+        list @ Object::List(..) => return self.eval_at(&list, span, env),
+        _ => Err(EvalError::syntax(format!(
+          "Cannot evaluate the dotted list {}",
+          obj
+        ))),
+      },
+      _ => Ok(obj.clone()),
+    };
+
+    match value {
+      Ok(value) => Control::Return(value),
+      Err(err) => Control::Raise(err.at(&at(&Span::default()))),
+    }
+  }
+
+  /// Evaluates the call or special form `list` up to its first subform.
+  fn eval_list(
+    &mut self,
+    list: &List,
+    span: &Span,
+    env: &Rc<RefCell<Environment>>,
+  ) -> Result<Control, EvalError> {
+    match &list[0] {
+      Object::Operator(op) if op == "and" || op == "or" => self.eval_logic(list, span, env, 1),
+      Object::Keyword(k) => match k.as_str() {
+        "begin" => Ok(self.sequence(list.clone(), 1, env.clone())),
+        "do" => Ok(self.sequence(list.clone(), 1, new_scope(env))),
+        "define" | "set!" => {
+          let symbol = assigned_symbol(list, k)?;
+          self.stack.push(Frame::Assign {
+            symbol: symbol.to_string(),
+            set: k == "set!",
+            span: span.clone(),
+            env: env.clone(),
+          });
+          Ok(self.eval_item(list, 2, env))
+        }
+        "let" | "let*" | "letrec" => self.eval_let(k, list, span, env),
+        "let/ec" => self.eval_let_ec(list, env),
+        "try" => self.eval_try(list, span, env),
+        _ => eval_keyword(list, &mut env.clone()).map(Control::Return),
+      },
+      Object::Cond => {
+        if list.len() % 2 != 1 {
+          return Err(EvalError::syntax("Cond requires an even number of forms"));
+        }
+        Ok(self.eval_cond(list, span, env, 1))
+      }
+      _ => {
+        self.stack.push(Frame::Call {
+          form: list.clone(),
+          span: span.clone(),
+          f: None,
+          args: Vec::with_capacity(list.len() - 1),
+          env: env.clone(),
+        });
+        Ok(self.eval_item(list, 0, env))
+      }
+    }
+  }
+
+  /// Evaluates `forms` from `start` on, the last one in tail position.
+  fn sequence(&mut self, forms: List, start: usize, env: Rc<RefCell<Environment>>) -> Control {
+    let control = match forms.get(start) {
+      Some(_) => self.eval_item(&forms, start, &env),
+      None => return Control::Return(Object::Void),
+    };
+
+    if start + 1 < forms.len() {
+      self.stack.push(Frame::Body {
+        forms,
+        next: start + 1,
+        env,
+      });
+    }
+
+    control
+  }
+
+  /// Evaluates the operand at `next` of the `and`/`or` form `list`. The
+  /// last operand is in tail position.
+  fn eval_logic(
+    &mut self,
+    list: &List,
+    span: &Span,
+    env: &Rc<RefCell<Environment>>,
+    next: usize,
+  ) -> Result<Control, EvalError> {
+    if list.len() < 2 {
+      return Err(EvalError::arity(format!(
+        "Invalid number of arguments for operator {}",
+        list[0]
+      )));
+    }
+
+    if next + 1 < list.len() {
+      self.stack.push(Frame::Logic {
+        form: list.clone(),
+        span: span.clone(),
+        next,
+        env: env.clone(),
+      });
+    }
+
+    Ok(self.eval_item(list, next, env))
+  }
+
+  /// Evaluates the test at `next` of the `cond` form `list`, if any is left.
+  fn eval_cond(
+    &mut self,
+    list: &List,
+    span: &Span,
+    env: &Rc<RefCell<Environment>>,
+    next: usize,
+  ) -> Control {
+    if next >= list.len() {
+      return Control::Return(Object::Void);
+    }
+
+    self.stack.push(Frame::Cond {
+      form: list.clone(),
+      span: span.clone(),
+      next,
+      env: env.clone(),
+    });
+    self.eval_item(list, next, env)
+  }
+
+  /// Starts evaluating a `let`, `let*`, `letrec` or named `let` form.
+  ///
+  /// `let` evaluates every value in the enclosing environment before binding
+  /// any of them, `let*` binds them one by one so each value sees the previous
+  /// bindings, and `letrec` evaluates them all where the new bindings will
+  /// live, so functions bound there can refer to each other.
+  fn eval_let(
+    &mut self,
+    form: &str,
+    list: &List,
+    span: &Span,
+    env: &Rc<RefCell<Environment>>,
+  ) -> Result<Control, EvalError> {
+    if list.len() < 3 {
+      return Err(EvalError::syntax(format!(
+        "Invalid number of forms for {}",
+        form
+      )));
+    }
+
+    let (kind, bindings) = match (form, &list[1]) {
+      ("let", Object::Symbol(name)) if list.len() < 4 => {
+        return Err(EvalError::syntax("Invalid number of forms for named let"))
+      }
+      ("let", Object::Symbol(name)) => (LetKind::Named(name.clone()), &list[2]),
+      ("let", bindings) => (LetKind::Let, bindings),
+      ("let*", bindings) => (LetKind::LetStar, bindings),
+      (_, bindings) => (LetKind::LetRec, bindings),
+    };
+
+    let bindings = eval_let_bindings(bindings, form)?;
+    self.next_binding(LetState {
+      kind,
+      form: list.clone(),
+      span: span.clone(),
+      values: Vec::with_capacity(bindings.len()),
+      bindings: Rc::new(bindings),
+      done: 0,
+      env: env.clone(),
+      new_env: new_scope(env),
+    })
+  }
+
+  /// Evaluates the next binding value of a `let` form, or its body once
+  /// they are all bound.
+  fn next_binding(&mut self, state: LetState) -> Result<Control, EvalError> {
+    if let Some((_, value, span)) = state.bindings.get(state.done) {
+      let env = match state.kind {
+        LetKind::Let | LetKind::Named(_) => &state.env,
+        LetKind::LetStar | LetKind::LetRec => &state.new_env,
+      };
+      let control = self.eval_at(value, Some(span), env);
+      self.stack.push(Frame::Let(state));
+      return Ok(control);
+    }
+
+    let LetState {
+      kind,
+      form,
+      span,
+      bindings,
+      values,
+      mut new_env,
+      ..
+    } = state;
+
+    match kind {
+      LetKind::Named(name) => {
+        let lambda = Rc::new(Lambda {
+          name: Some(name.clone()),
+          params: Params {
+            required: bindings
+              .iter()
+              .map(|(pattern, ..)| pattern.clone())
+              .collect(),
+            ..Default::default()
+          },
+          body: form.tail(3),
+          env: new_env.clone(),
+          doc: None,
+          code: None,
+        });
+        new_env
+          .borrow_mut()
+          .bind(&name, Object::Lambda(lambda.clone()));
+
+        self.call_lambda(&name, &lambda, values, &span)
+      }
+      LetKind::LetStar => Ok(self.sequence(form, 2, new_env)),
+      LetKind::Let | LetKind::LetRec => {
+        for ((pattern, ..), value) in bindings.iter().zip(values) {
+          bind_pattern(pattern, value, &|value| value, &mut new_env)?;
+        }
+
+        Ok(self.sequence(form, 2, new_env))
+      }
+    }
+  }
+
+  /// `(try body... (catch e handler...) (finally cleanup...))` evaluates the
+  /// body and, if it fails, the handler with the error bound to `e`. The
+  /// cleanup forms run last whether or not anything failed; an error they
+  /// raise replaces the outcome of the rest. Both clauses are optional.
+  fn eval_try(
+    &mut self,
+    list: &List,
+    span: &Span,
+    env: &Rc<RefCell<Environment>>,
+  ) -> Result<Control, EvalError> {
+    let body_end = list
+      .iter()
+      .skip(1)
+      .position(|o| clause(o, "catch").is_some() || clause(o, "finally").is_some())
+      .map_or(list.len(), |pos| pos + 1);
+    let mut clauses = &list[body_end..];
+
+    let catch = match clauses.first().and_then(|o| clause(o, "catch")) {
+      Some(catch) if matches!(catch.get(1), Some(Object::Symbol(_))) => {
+        clauses = &clauses[1..];
+        Some(catch.clone())
+      }
+      Some(_) => {
+        return Err(EvalError::syntax(
+          "Expected a symbol to bind the error to in catch",
+        ))
+      }
+      None => None,
+    };
+
+    let cleanup = clauses.first().and_then(|o| clause(o, "finally")).cloned();
+    if let Some(obj) = clauses.get(cleanup.is_some() as usize) {
+      return Err(EvalError::syntax(format!(
+        "Invalid clause for try: {}",
+        obj
+      )));
+    }
+
+    self.stack.push(Frame::Try {
+      id: next_id(),
+      catch,
+      cleanup,
+      span: span.clone(),
+      env: env.clone(),
+    });
+
+    Ok(self.sequence(list.slice(1..body_end), 0, new_scope(env)))
+  }
+
+  /// Runs the forms of the `(finally ...)` clause `cleanup`, to end with
+  /// `outcome` after them.
+  fn run_cleanup(
+    &mut self,
+    cleanup: List,
+    env: &Rc<RefCell<Environment>>,
+    outcome: Result<Object, EvalError>,
+  ) -> Control {
+    self.stack.push(Frame::Cleanup(outcome));
+    self.sequence(cleanup, 1, new_scope(env))
+  }
+
+  /// `(let/ec k body...)` evaluates `body` with `k` bound to a continuation
+  /// that returns from the `let/ec` form. Unlike one from `call/cc`, it only
+  /// works until the form returns, but capturing it copies nothing.
+  fn eval_let_ec(
+    &mut self,
+    list: &List,
+    env: &Rc<RefCell<Environment>>,
+  ) -> Result<Control, EvalError> {
+    let name = match list.get(1) {
+      Some(Object::Symbol(name)) => name,
+      _ => {
+        return Err(EvalError::syntax(
+          "Expected a symbol to bind the continuation to in let/ec",
+        ))
+      }
+    };
+
+    let id = next_id();
+    let k = Continuation {
+      run: self.id,
+      target: Target::Escape(id),
+    };
+    let new_env = new_scope(env);
+    new_env
+      .borrow_mut()
+      .bind(name, Object::Continuation(Rc::new(k)));
+
+    self.stack.push(Frame::Escape(id));
+    Ok(self.sequence(list.clone(), 2, new_env))
+  }
+
+  /// Hands `value` to `frame`, just popped off the stack.
+  fn resume(&mut self, frame: Frame, value: Object) -> Control {
+    match frame {
+      Frame::Call {
+        form,
+        span,
+        f,
+        args,
+        env,
+      } => self
+        .resume_call(form, &span, f, args, env, value)
+        .unwrap_or_else(|err| Control::Raise(err.at(&span))),
+      Frame::Logic {
+        form,
+        span,
+        next,
+        env,
+      } => {
+        let is_and = matches!(&form[0], Object::Operator(op) if op == "and");
+
+        match (value, is_and) {
+          (Object::Bool(false), true) => Control::Return(Object::Bool(false)),
+          (Object::Void, true) => Control::Return(Object::Void),
+          (Object::Bool(true), false) => Control::Return(Object::Bool(true)),
+          (Object::Bool(false) | Object::Void, false) | (_, true) => self
+            .eval_logic(&form, &span, &env, next + 1)
+            .unwrap_or_else(Control::Raise),
+          (value, false) => Control::Return(value),
+        }
+      }
+      Frame::Cond {
+        form,
+        span,
+        next,
+        env,
+      } => match value {
+        Object::Bool(false) | Object::Void => self.eval_cond(&form, &span, &env, next + 2),
+        _ => self.eval_item(&form, next + 1, &env),
+      },
+      Frame::Body { forms, next, env } => self.sequence(forms, next, env),
+      Frame::Assign {
+        symbol,
+        set: false,
+        env,
+        ..
+      } => {
+        define(&symbol, value, &env);
+        Control::Return(Object::Void)
+      }
+      Frame::Assign {
+        symbol, span, env, ..
+      } => match assign(&symbol, value, &env) {
+        Ok(()) => Control::Return(Object::Void),
+        Err(err) => Control::Raise(err.at(&span)),
+      },
+      Frame::Let(mut state) => {
+        let span = state.span.clone();
+
+        let bound = match state.kind {
+          LetKind::LetStar => {
+            let pattern = &state.bindings[state.done].0;
+            bind_pattern(pattern, value, &|value| value, &mut state.new_env)
+          }
+          _ => {
+            state.values.push(value);
+            Ok(())
+          }
+        };
+        state.done += 1;
+
+        bound
+          .and_then(|_| self.next_binding(state))
+          .unwrap_or_else(|err| Control::Raise(err.at(&span)))
+      }
+      Frame::Function { .. } => {
+        self.set_calls(self.calls - 1);
+        Control::Return(value)
+      }
+      Frame::Try {
+        cleanup: Some(cleanup),
+        env,
+        ..
+      } => self.run_cleanup(cleanup, &env, Ok(value)),
+      Frame::Try { .. } | Frame::Escape(_) => Control::Return(value),
+      Frame::Cleanup(Ok(value)) => Control::Return(value),
+      Frame::Cleanup(Err(err)) => Control::Raise(err),
+    }
+  }
+
+  /// Continues the call `form` with `value`, the value of its head or of
+  /// its latest argument.
+  fn resume_call(
+    &mut self,
+    form: List,
+    span: &Span,
+    f: Option<Object>,
+    mut args: Vec<Object>,
+    env: Rc<RefCell<Environment>>,
+    value: Object,
+  ) -> Result<Control, EvalError> {
+    let f = match f {
+      Some(f) => {
+        args.push(value);
+        f
+      }
+      None => match value {
+        Object::Macro(_) | Object::Syntax(_, _) => {
+          let (expanded, renames) = expand_macro(&value, &form)?;
+          alias_renames(&value, &renames, &env);
+          return Ok(self.eval(&expanded, &env));
+        }
+        Object::Lambda(_) | Object::Native(_) | Object::Operator(_) | Object::Continuation(_) => {
+          value
+        }
+        _ => {
+          return Err(EvalError::type_error(format!(
+            "Invalid head of list to call: {}",
+            value
+          )))
+        }
+      },
+    };
+
+    if args.len() + 1 < form.len() {
+      let control = self.eval_item(&form, args.len() + 1, &env);
+      self.stack.push(Frame::Call {
+        form,
+        span: span.clone(),
+        f: Some(f),
+        args,
+        env,
+      });
+      return Ok(control);
+    }
+
+    let name = call_name(&form[0], &f);
+    Ok(self.apply(name, &f, args, span, &env))
+  }
+
+  /// Calls the function value `f`, named `name` in errors, with `args` at
+  /// `span`.
+  fn apply(
+    &mut self,
+    name: &str,
+    f: &Object,
+    args: Vec<Object>,
+    span: &Span,
+    env: &Rc<RefCell<Environment>>,
+  ) -> Control {
+    let control = match f {
+      Object::Lambda(lambda) => self.call_lambda(name, lambda, args, span),
+      Object::Native(native) => self.apply_native(native, args, span, env),
+      Object::Operator(op) => apply_operator(op, args).map(Control::Return),
+      Object::Continuation(k) => self.resume_continuation(name, k, args),
+      _ => Err(EvalError::type_error(format!("{} is not a function", name))),
+    };
+
+    control.unwrap_or_else(|err| Control::Raise(err.at(span)))
+  }
+
+  /// Calls `lambda` as `name` at `span`: binds `args` to its parameters
+  /// and continues with its body. A call in tail position takes the place
+  /// of the function it is made from rather than adding to the stack.
+  fn call_lambda(
+    &mut self,
+    name: &str,
+    lambda: &Rc<Lambda>,
+    args: Vec<Object>,
+    span: &Span,
+  ) -> Result<Control, EvalError> {
+    let mut new_env = Rc::new(RefCell::new(Environment::extend(lambda.env.clone())));
+    let tail = matches!(self.stack.last(), Some(Frame::Function { .. }));
+
+    if !tail && DEPTH.with(Cell::get) > new_env.borrow().max_depth() {
+      return Err(EvalError::new(
+        ErrorKind::RecursionLimit,
+        format!("Maximum recursion depth exceeded in {}", name),
+      ));
+    }
+
+    bind_params(name, &lambda.params, args, false, &mut new_env)?;
+
+    let frame = Frame::Function {
+      lambda: lambda.clone(),
+      span: span.clone(),
+    };
+    match self.stack.last_mut() {
+      Some(top @ Frame::Function { .. }) => *top = frame,
+      _ => {
+        self.stack.push(frame);
+        self.set_calls(self.calls + 1);
+      }
+    }
+
+    Ok(self.sequence(lambda.body.clone(), 0, new_env))
+  }
+
+  /// Calls the native `name`. `eval`, `apply` and `call/cc` continue in
+  /// this machine rather than in a nested one, and `yield` suspends it.
+  fn apply_native(
+    &mut self,
+    name: &str,
+    args: Vec<Object>,
+    span: &Span,
+    env: &Rc<RefCell<Environment>>,
+  ) -> Result<Control, EvalError> {
+    let native = match env.borrow().get_runtime_fn(name) {
+      Some(native) => native,
+      None => return Err(EvalError::unbound(name)),
+    };
+
+    native.arity.check(name, args.len())?;
+
+    match name {
+      "eval" => Ok(self.eval(&eval_form(&args[0]), env)),
+      "apply" => self.apply_spread(args, span, env),
+      "call/cc" | "call-with-current-continuation" => Ok(self.call_cc(&args[0], span, env)),
+      "yield" if self.generator => Ok(Control::Yield(args.into_iter().next().unwrap_or_default())),
+      _ => Ok(Control::Return((native.call)(&args, &mut env.clone())?)),
+    }
+  }
+
+  /// `(apply f arg ... list)` calls `f` with the `arg`s followed by the
+  /// elements of `list`.
+  fn apply_spread(
+    &mut self,
+    args: Vec<Object>,
+    span: &Span,
+    env: &Rc<RefCell<Environment>>,
+  ) -> Result<Control, EvalError> {
+    let (f, spread) = spread_args(args)?;
+    Ok(self.apply(value_name(&f), &f, spread, span, env))
+  }
+
+  /// `(call/cc f)` calls `f` with the continuation of the `call/cc` call.
+  fn call_cc(&mut self, f: &Object, span: &Span, env: &Rc<RefCell<Environment>>) -> Control {
+    let k = Continuation {
+      run: self.id,
+      target: Target::Stack(self.stack.clone()),
+    };
+
+    let args = vec![Object::Continuation(Rc::new(k))];
+    self.apply(value_name(f), f, args, span, env)
+  }
+
+  /// Calls the continuation `k`. One captured by a machine that is still
+  /// running further out, or by the VM, is reached by unwinding to it.
+  fn resume_continuation(
+    &mut self,
+    name: &str,
+    k: &Rc<Continuation>,
+    args: Vec<Object>,
+  ) -> Result<Control, EvalError> {
+    Arity::between(0, 1).check(name, args.len())?;
+    let value = args.into_iter().next().unwrap_or_default();
+
+    if k.resume().is_some() || (k.run != self.id && is_running(k.run)) {
+      return Err(Unwind::raise(k.clone(), value));
+    }
+
+    self.jump(k, value)
+  }
+
+  /// Continues with the rest of the evaluation `k` stands for, giving it
+  /// `value`. The cleanup forms of the `try` forms left behind run first,
+  /// innermost first.
+  fn jump(&mut self, k: &Continuation, value: Object) -> Result<Control, EvalError> {
+    let left = match &k.target {
+      Target::Stack(stack) => std::mem::replace(&mut self.stack, stack.clone()),
+      Target::Vm(_) => unreachable!("the VM resumes the continuations it captured"),
+      Target::Escape(id) => {
+        let escape = self
+          .stack
+          .iter()
+          .rposition(|frame| matches!(frame, Frame::Escape(i) if i == id));
+
+        match escape {
+          Some(escape) => self.stack.split_off(escape),
+          None => {
+            return Err(EvalError::type_error(
+              "Cannot escape from a let/ec that has already returned",
+            ))
+          }
+        }
+      }
+    };
+
+    let kept = |id: usize| {
+      self
+        .stack
+        .iter()
+        .any(|frame| matches!(frame, Frame::Try { id: i, .. } if *i == id))
+    };
+    let cleanups = left
+      .into_iter()
+      .filter_map(|frame| match frame {
+        Frame::Try {
+          id,
+          cleanup: Some(cleanup),
+          env,
+          ..
+        } if !kept(id) => Some((cleanup, env)),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+
+    let calls = self
+      .stack
+      .iter()
+      .filter(|frame| matches!(frame, Frame::Function { .. }))
+      .count();
+    self.set_calls(calls);
+
+    if cleanups.is_empty() {
+      return Ok(Control::Return(value));
+    }
+
+    self.stack.push(Frame::Cleanup(Ok(value)));
+    for (cleanup, env) in cleanups {
+      self.stack.push(Frame::Body {
+        forms: cleanup,
+        next: 1,
+        env: new_scope(&env),
+      });
+    }
+
+    Ok(Control::Return(Object::Void))
+  }
+
+  /// Pops frames until one handles `err`, recording the calls it leaves on
+  /// the way. Fails with `err` if none does.
+  fn unwind(&mut self, err: EvalError) -> Result<Control, EvalError> {
+    let mut err = err;
+
+    if Unwind::target(&err).is_some_and(|k| k.run == self.id) {
+      if let Some(Unwind { k, value }) = Unwind::take(&err) {
+        return Ok(self.jump(&k, value).unwrap_or_else(Control::Raise));
+      }
+    }
+
+    while let Some(frame) = self.stack.pop() {
+      match frame {
+        Frame::Function { lambda, span } => {
+          self.set_calls(self.calls - 1);
+          err = err.called_from(lambda.name(), &span).at(&span);
+        }
+        Frame::Call { span, .. }
+        | Frame::Logic { span, .. }
+        | Frame::Cond { span, .. }
+        | Frame::Assign { span, .. } => err = err.at(&span),
+        Frame::Let(state) => err = err.at(&state.span),
+        Frame::Try {
+          id,
+          catch: Some(catch),
+          cleanup,
+          span,
+          env,
+        } if err.unwind.is_none() => {
+          let handler_env = new_scope(&env);
+          if let Object::Symbol(name) = &catch[1] {
+            let caught = Object::Error(Rc::new(err.at(&span)));
+            handler_env.borrow_mut().bind(name, caught);
+          }
+
+          if cleanup.is_some() {
+            self.stack.push(Frame::Try {
+              id,
+              catch: None,
+              cleanup,
+              span,
+              env,
+            });
+          }
+
+          return Ok(self.sequence(catch, 2, handler_env));
+        }
+        Frame::Try {
+          cleanup: Some(cleanup),
+          span,
+          env,
+          ..
+        } => return Ok(self.run_cleanup(cleanup, &env, Err(err.at(&span)))),
+        _ => {}
+      }
+    }
+
+    Err(err)
+  }
+}
+
+impl Drop for Machine {
+  fn drop(&mut self) {
+    RUNNING.with(|running| running.borrow_mut().pop());
+    DEPTH.with(|depth| depth.set(self.depth));
+  }
+}
+
+/// Evaluates `body` in `env`, returning the value of its last form.
+pub(crate) fn eval_body(body: List, env: &Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut machine = Machine::new();
+  let control = machine.sequence(body, 0, env.clone());
+  machine.run(control)
+}
+
+/// `apply` outside of tail position, for when it is called indirectly.
+pub fn apply(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut machine = Machine::new();
+  let control = machine
+    .apply_spread(args.to_vec(), &Span::default(), env)
+    .unwrap_or_else(Control::Raise);
+  machine.run(control)
+}
+
+/// `call/cc` outside of the machine: the continuation it captures ends
+/// with this call.
+pub fn call_cc(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut machine = Machine::new();
+  let control = machine.call_cc(&args[0], &Span::default(), env);
+  machine.run(control)
+}
+
+/// `(next g)` runs the generator `g` up to its next `yield` and returns the
+/// value yielded. Once the body has returned, `(next g default)` returns
+/// `default` and `(next g)` fails with an exhausted error.
+pub fn next(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let generator = match args.first() {
+    Some(Object::Generator(generator)) => generator,
+    _ => {
+      return Err(EvalError::type_error(format!(
+        "Expected a generator, got {}",
+        args.first().unwrap_or(&Object::Void)
+      )))
+    }
+  };
+
+  let exhausted = || match args.get(1) {
+    Some(default) => Ok(default.clone()),
+    None => Err(EvalError::new(
+      ErrorKind::GeneratorExhausted,
+      "Generator is exhausted",
+    )),
+  };
+
+  let mut machine = Machine::new();
+  machine.generator = true;
+
+  let control = match generator.state.replace(GeneratorState::Running) {
+    GeneratorState::Start => machine.sequence(generator.body.clone(), 0, new_scope(&generator.env)),
+    GeneratorState::Suspended(stack) => {
+      let calls = stack
+        .iter()
+        .filter(|frame| matches!(frame, Frame::Function { .. }))
+        .count();
+      machine.stack = stack;
+      machine.set_calls(calls);
+      Control::Return(Object::Void)
+    }
+    GeneratorState::Running => {
+      generator.state.replace(GeneratorState::Running);
+      return Err(EvalError::type_error("Generator is already running"));
+    }
+    GeneratorState::Done => {
+      generator.state.replace(GeneratorState::Done);
+      return exhausted();
+    }
+  };
+
+  let result = machine.run(control);
+  match (result, machine.yielded) {
+    (Ok(value), true) => {
+      let stack = std::mem::take(&mut machine.stack);
+      generator.state.replace(GeneratorState::Suspended(stack));
+      Ok(value)
+    }
+    (result, _) => {
+      generator.state.replace(GeneratorState::Done);
+      result.and_then(|_| exhausted())
+    }
+  }
+}
+
+/// Calls the function value `f` with evaluated `args`, as the call `name`
+/// made at `span`.
+pub(crate) fn call(
+  name: &str,
+  f: &Object,
+  args: Vec<Object>,
+  span: &Span,
+  env: &Rc<RefCell<Environment>>,
+) -> Result<Object, EvalError> {
+  let mut machine = Machine::new();
+  let control = machine.apply(name, f, args, span, env);
+  machine.run(control)
+}
+
+pub fn eval_object(obj: &Object, env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut machine = Machine::new();
+  let control = machine.eval(obj, env);
+  machine.run(control)
+}
+
+/// What `(eval form)` evaluates: the quoted form, with the quotes of its
+/// elements removed.
+pub fn eval_form(form: &Object) -> Object {
+  let unquoted = match form {
+    Object::Quote(o) => o,
+    o => o,
+  };
+
+  match pairs_to_lists(unquoted) {
+    Object::List(list, span) => {
+      let list = list
+        .iter()
+        .map(|obj| match obj {
+          Object::Quote(o) => (**o).clone(),
+          o => o.clone(),
+        })
+        .collect();
+      Object::List(list, span)
+    }
+    o => o,
+  }
+}
+
+fn contains_pairs(obj: &Object) -> bool {
+  match obj {
+    Object::Pair(_) => true,
+    Object::List(items, _) => items.iter().any(contains_pairs),
+    _ => false,
+  }
+}
+
+/// `form` with the proper lists made of pairs in it turned into `List`s, so
+/// that code built with `cons` evaluates like code that was read.
+fn pairs_to_lists(form: &Object) -> Object {
+  match form {
+    Object::Pair(_) => match form.list_items() {
+      Some(items) => Object::List(items.iter().map(pairs_to_lists).collect(), Span::default()),
+      None => form.clone(),
+    },
+    Object::List(items, span) if contains_pairs(form) => {
+      Object::List(items.iter().map(pairs_to_lists).collect(), span.clone())
+    }
+    _ => form.clone(),
+  }
+}
+
+/// The value of an unquoted expression, without the quote that marks
+/// quoted values, so that it can be placed inside a template.
+fn unquoted_value(obj: &Object, env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  match eval_object(obj, env)? {
+    Object::Quote(o) => Ok((*o).clone()),
+    o => Ok(o),
+  }
+}
+
+/// Fills a quasiquote template: `,x` is replaced with the value of `x` and
+/// `,@xs` splices the elements of the list `xs`. Unquotes belonging to a
+/// nested quasiquote (`depth` > 1) are kept as they are.
+fn eval_quasiquote(
+  template: &Object,
+  depth: usize,
+  env: &mut Rc<RefCell<Environment>>,
+) -> Result<Object, EvalError> {
+  match template {
+    Object::Unquote(o) if depth == 1 => unquoted_value(o, env),
+    Object::Unquote(o) => {
+      let inner = eval_quasiquote(o, depth - 1, env)?;
+      Ok(Object::Unquote(Rc::new(inner)))
+    }
+    Object::UnquoteSplicing(_) if depth == 1 => Err(EvalError::syntax(
+      "Unquote-splicing must be used inside a list",
+    )),
+    Object::UnquoteSplicing(o) => {
+      let inner = eval_quasiquote(o, depth - 1, env)?;
+      Ok(Object::UnquoteSplicing(Rc::new(inner)))
+    }
+    Object::Quasiquote(o) => {
+      let inner = eval_quasiquote(o, depth + 1, env)?;
+      Ok(Object::Quasiquote(Rc::new(inner)))
+    }
+    Object::Quote(o) => Ok(Object::Quote(Rc::new(eval_quasiquote(o, depth, env)?))),
+    Object::List(items, span) => Ok(Object::List(
+      List::from(fill_items(items, depth, env)?),
+      span.clone(),
+    )),
+    Object::Vector(items) => {
+      let items = fill_items(&items.borrow(), depth, env)?;
+      Ok(Object::Vector(Rc::new(RefCell::new(items))))
+    }
+    Object::Map(map) => {
+      let mut filled = Map::default();
+      for (key, value) in map.iter() {
+        filled.insert(
+          eval_quasiquote(key, depth, env)?,
+          eval_quasiquote(value, depth, env)?,
+        );
+      }
+      Ok(Object::Map(Rc::new(filled)))
+    }
+    Object::Pair(pair) => {
+      let car = eval_quasiquote(&pair.car.borrow(), depth, env)?;
+      let cdr = eval_quasiquote(&pair.cdr.borrow(), depth, env)?;
+      Ok(Object::Pair(Rc::new(Pair::new(car, cdr))))
+    }
+    o => Ok(o.clone()),
+  }
+}
+
+/// Fills the items of a list or vector in a quasiquote template.
+fn fill_items(
+  items: &[Object],
+  depth: usize,
+  env: &mut Rc<RefCell<Environment>>,
+) -> Result<Vec<Object>, EvalError> {
+  let mut filled = Vec::new();
+
+  for item in items.iter() {
+    match item {
+      Object::UnquoteSplicing(o) if depth == 1 => {
+        let spliced = unquoted_value(o, env)?;
+        match spliced.list_items() {
+          Some(items) => filled.extend(items),
+          None => {
+            return Err(EvalError::type_error(format!(
+              "Cannot splice {}, expected a list",
+              spliced
+            )))
+          }
+        }
+      }
+      _ => filled.push(eval_quasiquote(item, depth, env)?),
+    }
+  }
+
+  Ok(filled)
+}
+
+fn eval_keyword(list: &List, env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let head = &list[0];
+  match head {
+    Object::Keyword(s) => match s.as_str() {
+      "defun" => eval_defun(list, env),
+      "defmacro" => eval_defmacro(list, env),
+      "define-syntax" => eval_define_syntax(list, env),
+      "lambda" => eval_function_definition(list, env),
+      "generator" => Ok(Object::Generator(Rc::new(Generator {
+        body: list.tail(1),
+        env: env.clone(),
+        state: RefCell::new(GeneratorState::Start),
+      }))),
+      _ => Err(EvalError::syntax(format!("Unknown keyword: {}", s))),
+    },
+    _ => Err(EvalError::syntax(format!("Invalid keyword: {}", head))),
+  }
+}
+
+pub fn eval(program: &str, env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  eval_source(program, "<input>", env)
+}
+
+/// Evaluates `program`, reporting errors against locations in `file`.
+pub fn eval_source(
+  program: &str,
+  file: &str,
+  env: &mut Rc<RefCell<Environment>>,
+) -> Result<Object, EvalError> {
+  let forms = parse_program(program, file)?;
+  eval_program(&forms, env)
+}
+
+/// Evaluates top-level forms as the body of a `begin`: one after another
+/// in `env`, so their definitions stay visible, returning the last value.
+pub fn eval_program(forms: &List, env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let backend = env.borrow().backend();
+  let result = match backend {
+    Backend::Bytecode => vm::eval_program(forms, env),
+    Backend::TreeWalker => eval_body(forms.clone(), env),
+  };
+
+  result.map_err(Unwind::settle)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::runtime::Runtime;
+
+  use super::*;
+
+  #[test]
+  fn test_simple_add() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let result = eval("(+ 1 2)", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(3));
+  }
+
+  #[test]
+  fn test_lambda_add() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let result = eval("((lambda (x y) (+ x y)) 2 5)", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(7));
+  }
+
+  #[test]
+  fn test_area_of_a_circle() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define r 10)
+      (define pi 314)
+      (* pi (* r r)))";
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(314 * 10 * 10));
+  }
+
+  #[test]
+  fn test_sqr_function() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define sqr (lambda (r) (* r r)))
+      (sqr 10))";
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(10 * 10));
+  }
+
+  #[test]
+  fn test_fibonacci() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define fib
+        (lambda (n)
+          (cond (< n 2) 1
+                #t (+ (fib (- n 1)) (fib (- n 2))))))
+      (fib 10))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(89));
+  }
+
+  #[test]
+  fn test_factorial() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define fact
+        (lambda (n)
+          (cond (< n 1) 1
+                #t (* n (fact (- n 1))))))
+      (fact 5))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(120));
+  }
+
+  #[test]
+  #[allow(clippy::approx_constant)]
+  fn test_circle_area_function() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define pi 3.14)
+      (define r 10)
+      (define sqr (lambda (r) (* r r)))
+      (define area (lambda (r) (* pi (sqr r))))
+      (area r))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Float(3.14 * 10.0 * 10.0));
+  }
+
+  #[test]
+  fn test_closure() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define add-n
+          (lambda (n)
+            (lambda (a) (+ n a))))
+      (define add-5 (add-n 5))
+      (add-5 10))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(15));
+  }
+
+  #[test]
+  fn test_return_function() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+
+    let program = "(defun const (n) (lambda (a) n))";
+    eval(program, &mut env).unwrap();
+
+    let program = "(const 10)";
+    let result = eval(program, &mut env).unwrap();
+
+    let lambda = match result {
+      Object::Lambda(lambda) => lambda,
+      o => panic!("Expected a lambda, got {}", o),
+    };
+    assert_eq!(lambda.name, None);
+    assert_eq!(lambda.params.to_string(), "a");
+    assert_eq!(lambda.body[..], [Object::Symbol("n".to_string())]);
+    assert_eq!(lambda.env.borrow().get("n"), Some(Object::Integer(10)));
+  }
+
+  #[test]
+  fn test_cond() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define x 40)
+      (cond (= x 10) 1
+            (= x 20) 2
+            #t 3))";
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(3));
+  }
+
+  #[test]
+  fn test_tail_recursion() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define sum-n
+          (lambda (n a)
+            (cond (= n 0) a
+                  #t (sum-n (- n 1) (+ n a)))))
+      (sum-n 5000 2))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(12502502));
+  }
+
+  #[test]
+  fn test_let() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+        (let ((x 2) (y 3))
+            (let ((x 7)
+                  (z (+ x y)))
+                (* z x)))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(35));
+
+    let result = eval(&program.replace("(let ((x 7)", "(let* ((x 7)"), &mut env).unwrap();
+    assert_eq!(result, Object::Integer(70));
+  }
+
+  #[test]
+  fn test_let_tail() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define fact
+        (lambda (n)
+          (letrec ((fact-iter
+                (lambda (n a)
+                  (cond
+                      (= n 0) a
+                      #t (fact-iter (- n 1) (* n a))))))
+            (fact-iter n 1))))
+          (fact 5))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(120));
+  }
+
+  #[test]
+  fn test_circle_area_no_lambda() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (define pi 314)
+      (define r 10)
+      (defun sqr (r) (* r r))
+      (defun area (r) (* pi (sqr r)))
+      (area r))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(314 * 10 * 10));
+  }
+
+  #[test]
+  fn test_eval_to_eval_quoted() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(eval '(+ 1 2 3))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(6));
+  }
+
+  #[test]
+  fn test_eval_quoted_function() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+      (defun sqr (r) (* r r))
+      (eval '(sqr 10)))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(100));
+  }
+
+  #[test]
+  fn test_multi_arguments_operators() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(= 1 1 1 1 1 1 1 1 1 1 1 1 1 1)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Bool(true));
+
+    let program = "(= 1 1 1 1 1 1 1 1 1 1 1 1 1 2)";
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Bool(false));
+
+    let program = "(+ 10 11 12 13 14 15 16 17 18 19 20)";
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(165));
+  }
+
+  #[test]
+  fn test_evaluate_operator_expression() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "((cond #f = #t *) 3 4)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(12));
+  }
+
+  #[test]
+  fn test_or_operator() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(or 1 2 3)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(1));
+
+    let program = "(or #f 2 3)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(2));
+
+    let program = "(or #f #nil 3)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(3));
+  }
+
+  #[test]
+  fn test_and_operator() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(and 1 2 3)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(3));
+
+    let program = "(and 1 2 #f)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Bool(false));
+
+    let program = "(and 1 #nil #f)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Void);
+  }
+
+  #[test]
+  fn test_eval_native_methods() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(eval (cons '+ '(1 2 3)))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(6));
+  }
+
+  #[test]
+  fn test_error_location() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(do
+  (define x 1)
+  (+ x (* y 2)))";
+
+    let err = eval_source(program, "f.tl", &mut env).unwrap_err();
+    assert_eq!(err.to_string(), "Unbound symbol: y\n  --> f.tl:3:11");
+
+    let cases = [
+      ("1\n  y", "f.tl:2:3"),
+      ("(+ 1\n   [2 y])", "f.tl:2:4"),
+      ("(get {:a 1\n      :b y} :a)", "f.tl:1:6"),
+      ("(let ((a 1)\n      (b y))\n  b)", "f.tl:2:10"),
+      ("(cond #f 1\n      #t y)", "f.tl:2:10"),
+    ];
+
+    for backend in [Backend::TreeWalker, Backend::Bytecode] {
+      for (program, location) in cases {
+        let runtime = Runtime::new().with_backend(backend);
+        let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+        let err = eval_source(program, "f.tl", &mut env).unwrap_err();
+        assert_eq!(err.span.to_string(), location, "{}", program);
+      }
+    }
+  }
+
+  #[test]
+  fn test_program_forms() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (define x 2)
+      (defun double (n) (* n 2))
+      ()
+      (double x)";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(4));
+
+    let result = eval("(double 21) #nil", &mut env).unwrap();
+    assert_eq!(result, Object::Void);
+  }
+
+  #[test]
+  fn test_load_prelude() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+
+    eval_source(include_str!("../prelude.tl"), "prelude.tl", &mut env).unwrap();
+
+    let result = eval("(fact 5)", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(120));
+
+    let result = eval("(unless (zero? 1) (when #t 'yes))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "'yes");
+
+    let result = eval("(when #t (define x 1) (+ x 1))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(2));
+  }
+
+  #[test]
+  fn test_quasiquote() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (define b 2)
+      (define rest '(3 4))
+      `(a ,b ,@rest (c ,(+ b 1)))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result.to_string(), "'(a 2 3 4 (c 3))");
+
+    let result = eval("(eval `(+ ,b ,@rest))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(9));
+
+    let result = eval("`(1 `(2 ,(3 ,b)))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "'(1 `(2 ,(3 2)))");
+
+    assert!(eval("`(a ,@b)", &mut env).is_err());
+    assert!(eval(",b", &mut env).is_err());
+  }
+
+  #[test]
+  fn test_defmacro() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (defmacro unless (c body) `(cond ,c #nil #t ,body))
+      (defmacro ignore (form) 42)
+      (defmacro flip (call)
+        (cons (car call) (cons (car (cdr (cdr call))) (cons (car (cdr call)) '()))))";
+    eval(program, &mut env).unwrap();
+
+    let result = eval("(unless #f '(1 2))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "'(1 2)");
+
+    let result = eval("(ignore (undefined-function))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(42));
+
+    let result = eval("(flip (- 10 3))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(-7));
+
+    let err = eval("(unless #f)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Too few arguments for macro unless: expected 2, got 1"));
+  }
+
+  #[test]
+  fn test_macroexpand() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (defmacro unless (c body) `(cond ,c #nil #t ,body))
+      (defmacro not-unless (c body) `(unless (not ,c) ,body))";
+    eval(program, &mut env).unwrap();
+
+    let result = eval("(macroexpand-1 '(not-unless x 1))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "'(unless (not x) 1)");
+
+    let result = eval("(macroexpand '(not-unless x 1))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "'(cond (not x) #nil #t 1)");
+
+    let result = eval("(macroexpand '(+ 1 2))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "'(+ 1 2)");
+  }
+
+  #[test]
+  fn test_multi_expression_body() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (defun area (w h)
+        (define double-w (* w 2))
+        (define double-h (* h 2))
+        (* double-w double-h))
+      (define perimeter
+        (lambda (w h)
+          (define half (+ w h))
+          (* half 2)))
+      (+ (area 2 3) (perimeter 2 3))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(34));
+    assert!(eval("double-w", &mut env).is_err());
+  }
 
-    let mut expected_env: Environment = Environment::extend(env.clone());
+  #[test]
+  fn test_docstrings() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = r#"
+      (defun square (x)
+        "Multiplies x by itself."
+        (* x x))
+      (defmacro unless (c body)
+        "Evaluates body when c is false."
+        `(cond ,c #nil #t ,body))
+      (defun greeting () "hello")"#;
+    eval(program, &mut env).unwrap();
+
+    let result = eval("(doc square)", &mut env).unwrap();
+    assert_eq!(result, Object::String("Multiplies x by itself.".into()));
+
+    let result = eval("(doc unless)", &mut env).unwrap();
+    assert_eq!(
+      result,
+      Object::String("Evaluates body when c is false.".into())
+    );
+
+    assert_eq!(eval("(square 4)", &mut env).unwrap(), Object::Integer(16));
+    assert_eq!(eval("(doc greeting)", &mut env).unwrap(), Object::Void);
+    assert_eq!(
+      eval("(greeting)", &mut env).unwrap(),
+      Object::String("hello".into())
+    );
+    assert_eq!(eval("(doc car)", &mut env).unwrap(), Object::Void);
+  }
 
-    expected_env.set("n", Object::Integer(10));
+  #[test]
+  fn test_rest_optional_and_key_params() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (defun tail (a &rest more) more)
+      (defun add (a &optional (b 10) c) (cond c (+ a b c) #t (+ a b)))
+      (defun point (&key (x 0) (y x)) (list x y))
+      (defun list (&rest items) items)";
+    eval(program, &mut env).unwrap();
 
+    assert_eq!(eval("(tail 1 2 3)", &mut env).unwrap().to_string(), "(2 3)");
+    assert_eq!(eval("(tail 1)", &mut env).unwrap().to_string(), "()");
+    assert_eq!(eval("(add 1)", &mut env).unwrap(), Object::Integer(11));
+    assert_eq!(eval("(add 1 2)", &mut env).unwrap(), Object::Integer(3));
+    assert_eq!(eval("(add 1 2 3)", &mut env).unwrap(), Object::Integer(6));
+    assert_eq!(eval("(point)", &mut env).unwrap().to_string(), "(0 0)");
+    assert_eq!(eval("(point :y 2)", &mut env).unwrap().to_string(), "(0 2)");
+    assert_eq!(eval("(point :x 5)", &mut env).unwrap().to_string(), "(5 5)");
     assert_eq!(
-      result,
-      Object::Lambda(
-        vec!["a".to_string()],
-        Box::new(Object::Symbol("n".to_string())),
-        Rc::new(RefCell::new(expected_env))
-      )
+      eval("((lambda (&rest xs) xs) 1 2)", &mut env)
+        .unwrap()
+        .to_string(),
+      "(1 2)"
     );
+
+    let err = eval("(point :z 1)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Unknown keyword argument :z for point"));
+
+    let err = eval("(point :x)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Odd number of keyword arguments for point"));
+
+    let err = eval("(defun bad (&rest) 1)", &mut env).unwrap_err();
+    assert!(err.message.starts_with("Expected a parameter after &rest"));
+
+    let err = eval("(defun bad (&rest a &optional b) 1)", &mut env).unwrap_err();
+    assert!(err.message.starts_with("Misplaced &optional"));
   }
 
   #[test]
-  fn test_cond() {
+  fn test_arity_errors() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define x 40)
-      (cond (= x 10) 1
-            (= x 20) 2
-            #t 3))";
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(3));
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    eval(
+      "(defun pair (a b) a) (defun opt (a &optional b) a)",
+      &mut env,
+    )
+    .unwrap();
+
+    let err = eval("(pair 1 2 3)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Too many arguments for pair: expected 2, got 3"));
+
+    let err = eval("(opt)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Too few arguments for opt: expected 1 to 2, got 0"));
+
+    let err = eval("((lambda (x) x))", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Too few arguments for lambda: expected 1, got 0"));
+
+    let err = eval("(cons 1)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Too few arguments for cons: expected 2, got 1"));
+
+    let err = eval("(format)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Too few arguments for format: expected at least 1, got 0"));
   }
 
   #[test]
-  fn test_tail_recursion() {
+  fn test_destructuring() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define sum-n
-          (lambda (n a)
-            (cond (= n 0) a
-                  #t (sum-n (- n 1) (+ n a)))))
-      (sum-n 5000 2))";
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (define xs '(1 (2 3) 4 5))
+      (defun swap ((x y)) (cons y (cons x '())))
+      (defmacro with-pair ((a b) body) `(let ((,a 1) (,b 2)) ,body))";
+    eval(program, &mut env).unwrap();
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer((12502502) as i64));
+    let result = eval("(let (((a (b c) . rest) xs)) (+ a b c))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(6));
+
+    let result = eval("(let (((a b . rest) xs)) rest)", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(4 5)");
+
+    let result = eval("(swap '(1 2))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "(2 1)");
+
+    let result = eval("(with-pair (p q) (- p q))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(-1));
+
+    let err = eval("(let (((a b) '(1 2 3))) a)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Pattern (a b) does not match '(1 2 3)"));
+
+    let err = eval("(swap 5)", &mut env).unwrap_err();
+    assert!(err.message.starts_with("Pattern (x y) does not match 5"));
+
+    let err = eval("(let (((a . b c) xs)) a)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::ParseError);
+    assert!(err.message.starts_with("Parse error: Misplaced `.`"));
   }
 
   #[test]
-  fn test_let() {
+  fn test_set() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
     let program = "
-        (let ((x 2) (y 3))
-            (let ((x 7)
-                  (z (+ x y)))
-                (* z x)))";
+      (defun make-counter ()
+        (let ((count 0))
+          (lambda () (set! count (+ count 1)) count)))
+      (define counter (make-counter))
+      (define total 0)
+      (defun add-to-total (n) (set! total (+ total n)))";
+    eval(program, &mut env).unwrap();
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(70));
+    eval("(counter) (counter)", &mut env).unwrap();
+    assert_eq!(eval("(counter)", &mut env).unwrap(), Object::Integer(3));
+
+    eval("(add-to-total 5) (add-to-total 7)", &mut env).unwrap();
+    assert_eq!(eval("total", &mut env).unwrap(), Object::Integer(12));
+
+    let err = eval("(set! missing 1)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Cannot set! unbound symbol: missing"));
   }
 
   #[test]
-  fn test_let_tail() {
+  fn test_begin() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define fact
-        (lambda (n)
-          (let ((fact-iter
-                (lambda (n a)
-                  (cond
-                      (= n 0) a
-                      #t (fact-iter (- n 1) (* n a))))))
-            (fact-iter n 1))))
-          (fact 5))";
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(120));
+    let result = eval("(begin (defun f (x) (* x 2)) (define y 4) (f y))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(8));
+    assert_eq!(eval("(f 5)", &mut env).unwrap(), Object::Integer(10));
+
+    eval("(do (defun g () 1))", &mut env).unwrap();
+    let err = eval("(g)", &mut env).unwrap_err();
+    assert!(err.message.starts_with("Unbound symbol: g"));
+
+    assert_eq!(eval("(begin)", &mut env).unwrap(), Object::Void);
   }
 
   #[test]
-  fn test_circle_area_no_lambda() {
+  fn test_let_forms() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (define pi 314)
-      (define r 10)
-      (defun sqr (r) (* r r))
-      (defun area (r) (* pi (sqr r)))
-      (area r))";
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    eval("(define x 1)", &mut env).unwrap();
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer((314 * 10 * 10) as i64));
+    let result = eval("(let ((x 10) (y x)) y)", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(1));
+
+    let result = eval("(let* ((x 10) (y x)) y)", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(10));
+
+    let program = "
+      (letrec ((even? (lambda (n) (cond (= n 0) #t #t (odd? (- n 1)))))
+               (odd? (lambda (n) (cond (= n 0) #f #t (even? (- n 1))))))
+        (even? 100))";
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Bool(true));
+
+    let program = "
+      (let loop ((i 0) (acc 0))
+        (cond (= i 5) acc
+              #t (loop (+ i 1) (+ acc i))))";
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(10));
+
+    let err = eval("(let ((a)) a)", &mut env).unwrap_err();
+    assert!(err.message.starts_with("Invalid binding for let: (a)"));
   }
 
   #[test]
-  fn test_eval_to_eval_quoted() {
+  fn test_let_tail_calls() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(eval '(+ 1 2 3))";
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(6));
+    let program = "
+      (let loop ((i 0))
+        (cond (< i 20000) (let* ((next (+ i 1))) (loop next))
+              #t i))";
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(20000));
+
+    let program = "
+      (letrec ((count (lambda (n) (cond (= n 0) 'done #t (count (- n 1))))))
+        (count 20000))";
+    assert_eq!(eval(program, &mut env).unwrap().to_string(), "'done");
   }
 
   #[test]
-  fn test_eval_quoted_function() {
+  fn test_tail_calls() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(do
-      (defun sqr (r) (* r r))
-      (eval '(sqr 10)))";
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (defun count-down (n) (cond (= n 0) 'done #t (do (count-down (- n 1)))))
+      (defun ping (n) (and #t (cond (= n 0) 'ping #t (pong (- n 1)))))
+      (defun pong (n) (or #f (cond (= n 0) 'pong #t (ping (- n 1)))))
+      (defun via-eval (n) (cond (= n 0) 'eval #t (eval (list 'via-eval (- n 1)))))
+      (defun via-apply (n) (cond (= n 0) 'apply #t (apply via-apply (list (- n 1)))))
+      (defun via-let (n) (let ((m (- n 1))) (cond (< m 0) 'let #t ((car (list via-let)) m))))
+      (defun list (&rest xs) xs)";
+    eval(program, &mut env).unwrap();
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(100));
+    let n = 1_000_000;
+    for (call, result) in [
+      ("count-down", "'done"),
+      ("ping", "'ping"),
+      ("via-eval", "'eval"),
+      ("via-apply", "'apply"),
+      ("via-let", "'let"),
+    ] {
+      let value = eval(&format!("({} {})", call, n), &mut env).unwrap();
+      assert_eq!(value.to_string(), result);
+    }
   }
 
   #[test]
-  fn test_multi_arguments_operators() {
+  fn test_apply() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(= 1 1 1 1 1 1 1 1 1 1 1 1 1 1)";
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    eval("(defun inc (x) (+ x 1))", &mut env).unwrap();
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Bool(true));
+    assert_eq!(
+      eval("(apply + 1 '(2 3))", &mut env).unwrap(),
+      Object::Integer(6)
+    );
+    assert_eq!(
+      eval("(apply (lambda (a b) (- a b)) '(5 2))", &mut env).unwrap(),
+      Object::Integer(3)
+    );
+    assert_eq!(
+      eval("(apply cons 1 '((2)))", &mut env).unwrap().to_string(),
+      "(1 2)"
+    );
+    assert_eq!(
+      eval("((lambda (f) (f 1)) inc)", &mut env).unwrap(),
+      Object::Integer(2)
+    );
 
-    let program = "(= 1 1 1 1 1 1 1 1 1 1 1 1 1 2)";
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Bool(false));
+    let err = eval("(apply inc 1)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Expected a list of arguments to apply, got 1"));
+  }
 
-    let program = "(+ 10 11 12 13 14 15 16 17 18 19 20)";
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(165));
+  #[test]
+  fn test_max_depth() {
+    let runtime = Runtime::new().with_max_depth(100);
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "
+      (defun sum (n) (cond (= n 0) 0 #t (+ n (sum (- n 1)))))
+      (defun runaway (n) (+ 1 (runaway n)))
+      (defun count (n) (cond (= n 0) 'done #t (count (- n 1))))";
+    eval(program, &mut env).unwrap();
+
+    assert_eq!(eval("(sum 10)", &mut env).unwrap(), Object::Integer(55));
+
+    let err = eval("(runaway 1)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Maximum recursion depth exceeded in runaway"));
+
+    let err = eval("(sum 1000)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Maximum recursion depth exceeded in sum"));
+
+    assert_eq!(eval("(count 1000)", &mut env).unwrap().to_string(), "'done");
+    assert_eq!(eval("(sum 10)", &mut env).unwrap(), Object::Integer(55));
   }
 
   #[test]
-  fn test_evaluate_operator_expression() {
+  fn test_error_kinds() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "((cond #f = #t *) 3 4)";
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    eval("(defun pair (a b) a)", &mut env).unwrap();
+
+    for (program, kind) in [
+      ("missing", ErrorKind::UnboundSymbol("missing".to_string())),
+      ("(+ 1 \"a\")", ErrorKind::TypeError),
+      ("(1 2)", ErrorKind::TypeError),
+      ("(pair 1)", ErrorKind::ArityError),
+      ("(/ 1 0)", ErrorKind::DivisionByZero),
+      ("(define 1 2)", ErrorKind::SyntaxError),
+      ("(+ 1", ErrorKind::ParseError),
+    ] {
+      assert_eq!(
+        eval(program, &mut env).unwrap_err().kind,
+        kind,
+        "{}",
+        program
+      );
+    }
+  }
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(12));
+  #[test]
+  fn test_error_call_stack() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(defun inner (x)
+  (define y (/ x 0))
+  y)
+(define outer (lambda (x) (+ 1 (inner x))))
+(defun tail (x) (outer x))
+(tail 5)";
+
+    let err = eval_source(program, "s.tl", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::DivisionByZero);
+    assert_eq!(
+      err.to_string(),
+      "Cannot divide by zero
+  --> s.tl:2:13
+  in inner at s.tl:4:32
+  in outer at s.tl:5:17"
+    );
+
+    let functions = err
+      .stack
+      .iter()
+      .map(|f| f.function.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(functions, vec!["inner", "outer"]);
   }
 
   #[test]
-  fn test_or_operator() {
+  fn test_try_catch() {
     let runtime = Runtime::new();
-    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(or 1 2 3)";
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    eval(
+      "(defun checked-div (a b)
+         (cond (= b 0) (error \"Division by zero\" a) #t (/ a b)))",
+      &mut env,
+    )
+    .unwrap();
+
+    let result = eval("(try (checked-div 6 2) (catch e 0))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(3));
 
-    let result = eval(program, &mut env).unwrap();
+    let result = eval("(try (checked-div 6 0) (catch e (error-data e)))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(6));
+
+    let result = eval(
+      "(try (checked-div 6 0) (catch e (error-message e)))",
+      &mut env,
+    )
+    .unwrap();
+    assert_eq!(result, Object::String("Division by zero".into()));
+
+    let result = eval("(try (/ 1 0) (catch e (error-kind e)))", &mut env).unwrap();
+    assert_eq!(result.to_string(), ":division-by-zero");
+
+    let result = eval(
+      "(try (try (car) (catch e (error e))) (catch e (error-kind e)))",
+      &mut env,
+    );
+    assert_eq!(result.unwrap().to_string(), ":arity-error");
+
+    let err = eval("(checked-div 1 0)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::User);
+    assert_eq!(*err.data, Object::Integer(1));
+
+    let err = eval("(try 1 (finally) (catch e e))", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Invalid clause for try: (catch e e)"));
+  }
+
+  #[test]
+  fn test_finally() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    eval_source(include_str!("../prelude.tl"), "prelude.tl", &mut env).unwrap();
+    eval("(define cleanups 0)", &mut env).unwrap();
+
+    let result = eval("(try 1 (finally (set! cleanups (inc cleanups))))", &mut env).unwrap();
     assert_eq!(result, Object::Integer(1));
 
-    let program = "(or #f 2 3)";
+    let err = eval(
+      "(try (error \"oops\") (finally (set! cleanups (inc cleanups))))",
+      &mut env,
+    );
+    assert_eq!(err.unwrap_err().message, "oops");
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(2));
+    let program = "(try (error \"oops\") (catch e 2) (finally (set! cleanups (inc cleanups))))";
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(2));
 
-    let program = "(or #f #nil 3)";
+    let err = eval(
+      "(unwind-protect (car) (set! cleanups (inc cleanups)))",
+      &mut env,
+    );
+    assert_eq!(err.unwrap_err().kind, ErrorKind::ArityError);
 
-    let result = eval(program, &mut env).unwrap();
+    assert_eq!(eval("cleanups", &mut env).unwrap(), Object::Integer(4));
+  }
+
+  #[test]
+  fn test_call_cc() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    eval(
+      "(defun list (&rest xs) xs)
+       (defun find-first (pred limit)
+         (call/cc (lambda (return)
+           (let loop ((i 0))
+             (cond (= i limit) #f
+                   (pred i) (return i)
+                   #t (loop (+ i 1)))))))",
+      &mut env,
+    )
+    .unwrap();
+
+    let result = eval("(+ 1 (call/cc (lambda (k) (* 10 (k 2)))))", &mut env).unwrap();
     assert_eq!(result, Object::Integer(3));
+
+    let result = eval("(find-first (lambda (x) (> (* x x) 10)) 100)", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(4));
+
+    let result = eval("(call-with-current-continuation (lambda (k) 5))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(5));
+
+    let result = eval(
+      "(define n 0)
+       (define again #nil)
+       (define r (+ 1 (call/cc (lambda (k) (set! again k) 1))))
+       (set! n (+ n 1))
+       (cond (< n 3) (again 10))
+       (list r n)",
+      &mut env,
+    )
+    .unwrap();
+    assert_eq!(result.to_string(), "(11 3)");
+
+    let program = "(+ 1 (call/cc (lambda (k)
+      (try (vector-map (lambda (x) (k 10)) [1]) (catch e 99)))))";
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(11));
+
+    let err = eval("(call/cc (lambda (k) (k 1 2)))", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::ArityError);
   }
 
   #[test]
-  fn test_and_operator() {
+  fn test_let_ec() {
     let runtime = Runtime::new();
     let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(and 1 2 3)";
 
-    let result = eval(program, &mut env).unwrap();
+    let result = eval("(let/ec k (+ 1 (k 2)) 3)", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(2));
+
+    let result = eval("(let/ec k (+ 1 2))", &mut env).unwrap();
     assert_eq!(result, Object::Integer(3));
 
-    let program = "(and 1 2 #f)";
+    let result = eval("(let/ec k (try (k 1) (catch e 2)))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(1));
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Bool(false));
+    let result = eval("(let/ec k (try `(a ,(k 1)) (catch e 2)))", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(1));
 
-    let program = "(and 1 #nil #f)";
+    let err = eval("(define esc (let/ec k k)) (esc 1)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Cannot escape from a let/ec that has already returned"));
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Void);
+    let err = eval("(let/ec 1 2)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::SyntaxError);
   }
 
   #[test]
-  fn test_eval_native_methods() {
+  fn test_escape_cleanup() {
     let runtime = Runtime::new();
     let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
-    let program = "(eval (cons '+ '(1 2 3)))";
+    eval("(define cleaned 0)", &mut env).unwrap();
+
+    let result = eval(
+      "(let/ec k (try (k 5) (finally (set! cleaned (+ cleaned 1)))))",
+      &mut env,
+    )
+    .unwrap();
+    assert_eq!(result, Object::Integer(5));
+    assert_eq!(eval("cleaned", &mut env).unwrap(), Object::Integer(1));
+
+    let result = eval(
+      "(call/cc (lambda (k)
+         (try
+           (try (k 7) (finally (set! cleaned (* cleaned 10))))
+           (finally (set! cleaned (+ cleaned 1))))))",
+      &mut env,
+    )
+    .unwrap();
+    assert_eq!(result, Object::Integer(7));
+    assert_eq!(eval("cleaned", &mut env).unwrap(), Object::Integer(11));
 
-    let result = eval(program, &mut env).unwrap();
-    assert_eq!(result, Object::Integer(6));
+    let result = eval(
+      "(let/ec k (try (k 1) (finally (set! cleaned 0))) (set! cleaned 99))",
+      &mut env,
+    )
+    .unwrap();
+    assert_eq!(result, Object::Integer(1));
+    assert_eq!(eval("cleaned", &mut env).unwrap(), Object::Integer(0));
+  }
+
+  #[test]
+  fn test_generators() {
+    let runtime = Runtime::new();
+    let mut env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new(runtime)));
+    eval(
+      "(defun range (n)
+         (generator
+           (let loop ((i 0))
+             (cond (< i n) (begin (yield i) (loop (+ i 1)))))))
+       (defun emit-twice (x) (yield x) (yield x))
+       (defun map-gen (f g)
+         (generator
+           (let loop ((x (next g -1)))
+             (cond (< x 0) #nil
+                   #t (begin (yield (f x)) (loop (next g -1)))))))
+       (define started 0)",
+      &mut env,
+    )
+    .unwrap();
+
+    eval("(define g (range 2))", &mut env).unwrap();
+    assert_eq!(eval("(next g)", &mut env).unwrap(), Object::Integer(0));
+    assert_eq!(eval("(next g)", &mut env).unwrap(), Object::Integer(1));
+    assert_eq!(eval("(next g :end)", &mut env).unwrap().to_string(), ":end");
+
+    let err = eval("(next g)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::GeneratorExhausted);
+    let result = eval("(try (next g) (catch e (error-kind e)))", &mut env).unwrap();
+    assert_eq!(result.to_string(), ":generator-exhausted");
+
+    eval(
+      "(define lazy (generator (set! started 1) (emit-twice 7)))",
+      &mut env,
+    )
+    .unwrap();
+    assert_eq!(eval("started", &mut env).unwrap(), Object::Integer(0));
+    assert_eq!(eval("(next lazy)", &mut env).unwrap(), Object::Integer(7));
+    assert_eq!(eval("started", &mut env).unwrap(), Object::Integer(1));
+    assert_eq!(eval("(next lazy)", &mut env).unwrap(), Object::Integer(7));
+    assert_eq!(
+      eval("(next lazy #f)", &mut env).unwrap(),
+      Object::Bool(false)
+    );
+
+    eval(
+      "(define squares (map-gen (lambda (x) (* x x)) (range 4)))",
+      &mut env,
+    )
+    .unwrap();
+    let result = eval(
+      "(+ (next squares) (next squares) (next squares) (next squares))",
+      &mut env,
+    );
+    assert_eq!(result.unwrap(), Object::Integer(14));
+    assert_eq!(eval("(next squares #nil)", &mut env).unwrap(), Object::Void);
+
+    let err = eval("(yield 1)", &mut env).unwrap_err();
+    assert!(err.message.starts_with("yield used outside of a generator"));
+
+    let err = eval("(next (generator (/ 1 0)))", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::DivisionByZero);
+
+    let err = eval("(next 1)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TypeError);
+  }
+
+  #[test]
+  fn test_shared_lists() {
+    let items = List::from(vec![
+      Object::Integer(1),
+      Object::Integer(2),
+      Object::Integer(3),
+    ]);
+    assert_eq!(&items.tail(1)[..], &items[1..]);
+    assert_eq!(&items.slice(1..2).tail(1)[..], &[]);
+    assert!(items.tail(5).is_empty());
+    assert_eq!(items.tail(1), List::from(items[1..].to_vec()));
+
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let program = "(defun sum (l acc) (cond (car l) (sum (cdr l) (+ acc (car l))) #t acc))
+      (sum '(1 2 3 4) 0)";
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(10));
+    assert_eq!(
+      eval("(cdr (cdr '(1 2 3)))", &mut env).unwrap().to_string(),
+      "(3)"
+    );
+    assert_eq!(eval("(cdr '(1))", &mut env).unwrap().to_string(), "()");
+    assert_eq!(eval("(cdr '())", &mut env).unwrap(), Object::Void);
+    assert_eq!(
+      eval("(cons 0 (cdr '(1 2)))", &mut env).unwrap().to_string(),
+      "(0 2)"
+    );
+
+    let result = eval("(defun f () \"Doc.\" 1) (+ (f) (f))", &mut env);
+    assert_eq!(result.unwrap(), Object::Integer(2));
+    assert_eq!(
+      eval("(doc f)", &mut env).unwrap(),
+      Object::String("Doc.".into())
+    );
+  }
+
+  #[test]
+  fn test_pairs() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let show =
+      |program: &str, env: &mut Rc<RefCell<Environment>>| eval(program, env).unwrap().to_string();
+
+    assert_eq!(show("(cons 1 2)", &mut env), "(1 . 2)");
+    assert_eq!(show("(car (cons 1 2))", &mut env), "1");
+    assert_eq!(show("(cdr (cons 1 2))", &mut env), "2");
+    assert_eq!(show("'(1 2 . 3)", &mut env), "'(1 2 . 3)");
+    assert_eq!(show("(cdr '(1 2 . 3))", &mut env), "(2 . 3)");
+    assert_eq!(show("(cons 1 '(2 3))", &mut env), "(1 2 3)");
+    assert_eq!(show("(cons 1 #nil)", &mut env), "(1)");
+    assert_eq!(show("(cdr (cons 1 #nil))", &mut env), "()");
+
+    eval("(define p (cons 1 '(2))) (define q (cons 0 p))", &mut env).unwrap();
+    eval("(set-car! p 10)", &mut env).unwrap();
+    assert_eq!(show("q", &mut env), "(0 10 2)");
+    eval("(set-cdr! p 5)", &mut env).unwrap();
+    assert_eq!(show("q", &mut env), "(0 10 . 5)");
+
+    let err = eval("(set-car! '(1 2) 3)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TypeError);
+
+    let built = eval("(cons 1 (cons 2 '()))", &mut env).unwrap();
+    let literal = eval("(cdr '(0 1 2))", &mut env).unwrap();
+    assert_eq!(built, literal);
+    assert_ne!(built, eval("(cons 1 (cons 2 3))", &mut env).unwrap());
+    assert_eq!(show("(get {'(1 2) 5} (cons 1 '(2)))", &mut env), "5");
+    assert_eq!(show("(get {(cons 1 '(2)) 5} '(1 2))", &mut env), "5");
+
+    let result = eval("(let (((a . b) (cons 1 2))) (+ a b))", &mut env);
+    assert_eq!(result.unwrap(), Object::Integer(3));
+    let result = eval("(let (((a b) (cons 1 (cons 2 '())))) (+ a b))", &mut env);
+    assert_eq!(result.unwrap(), Object::Integer(3));
+    let err = eval("(let (((a b) '(1 2 . 3))) a)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TypeError);
+
+    let result = eval("(apply + (cons 1 (cons 2 '())))", &mut env);
+    assert_eq!(result.unwrap(), Object::Integer(3));
+    let result = eval("(eval (cons '+ (cons 1 (cons 2 '()))))", &mut env);
+    assert_eq!(result.unwrap(), Object::Integer(3));
+    let err = eval("(eval (cons '+ 1))", &mut env).unwrap_err();
+    assert!(err.message.starts_with("Cannot evaluate the dotted list"));
+
+    let program = "(define xs (let loop ((i 0) (acc '()))
+        (cond (< i 100000) (loop (+ i 1) (cons i acc)) #t acc)))
+      (defun sum (l acc) (cond (car l) (sum (cdr l) (+ acc (car l))) #t acc))
+      (sum xs 0)";
+    let result = eval(program, &mut env);
+    assert_eq!(result.unwrap(), Object::Integer(99999 * 100000 / 2));
+  }
+
+  #[test]
+  fn test_vectors() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let show =
+      |program: &str, env: &mut Rc<RefCell<Environment>>| eval(program, env).unwrap().to_string();
+
+    assert_eq!(show("(define x 2) [1 x (+ x 1)]", &mut env), "[1 2 3]");
+    assert_eq!(show("'[1 x]", &mut env), "'[1 x]");
+    assert_eq!(show("(vector 1 \"a\" [])", &mut env), "[1 a []]");
+    assert_eq!(
+      show("`[1 ,x ,@(vector->list [3 4])]", &mut env),
+      "'[1 2 3 4]"
+    );
+
+    eval("(define v [10 20 30])", &mut env).unwrap();
+    assert_eq!(show("(vector-ref v 1)", &mut env), "20");
+    assert_eq!(show("(vector-length v)", &mut env), "3");
+    eval("(define w v) (vector-set! w 1 25)", &mut env).unwrap();
+    assert_eq!(show("v", &mut env), "[10 25 30]");
+    assert_eq!(show("(vector->list v)", &mut env), "(10 25 30)");
+    assert_eq!(show("(list->vector '(1 2))", &mut env), "[1 2]");
+    assert_eq!(show("(list->vector (cons 1 '()))", &mut env), "[1]");
+    assert_eq!(
+      show("(vector-map (lambda (a b) (+ a b)) v [1 2])", &mut env),
+      "[11 27]"
+    );
+
+    assert_eq!(show("(== [1 [2]] [1 [2]])", &mut env), "#t");
+    assert_eq!(show("(= v [10 25 30])", &mut env), "#t");
+    assert_eq!(show("(= v [10 25])", &mut env), "#f");
+    assert_eq!(show("(== v 1)", &mut env), "#f");
+
+    let err = eval("(vector-ref v 3)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::IndexOutOfRange);
+    let err = eval("(vector-set! v -1 0)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::IndexOutOfRange);
+    let err = eval("(vector-length '(1))", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TypeError);
+    let err = eval("(vector-map (lambda (a) (/ a 0)) v)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::DivisionByZero);
+  }
+
+  #[test]
+  fn test_maps() {
+    let runtime = Runtime::new();
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+    let show =
+      |program: &str, env: &mut Rc<RefCell<Environment>>| eval(program, env).unwrap().to_string();
+
+    eval("(define x 2) (define m {:a 1 :b x})", &mut env).unwrap();
+    assert_eq!(show("m", &mut env), "{:a 1 :b 2}");
+    assert_eq!(show("'{:a x}", &mut env), "'{:a x}");
+    assert_eq!(show("`{:a ,x}", &mut env), "'{:a 2}");
+    assert_eq!(show("{}", &mut env), "{}");
+
+    assert_eq!(show("(get m :b)", &mut env), "2");
+    assert_eq!(show("(get m :c)", &mut env), "#nil");
+    assert_eq!(show("(get m :c 0)", &mut env), "0");
+    assert_eq!(show("(assoc m :c 3 :a 0)", &mut env), "{:a 0 :b 2 :c 3}");
+    assert_eq!(show("m", &mut env), "{:a 1 :b 2}");
+    assert_eq!(show("(dissoc m :a :z)", &mut env), "{:b 2}");
+    assert_eq!(show("(keys m)", &mut env), "(:a :b)");
+    assert_eq!(show("(vals m)", &mut env), "(1 2)");
+    assert_eq!(show("(contains? m :a)", &mut env), "#t");
+    assert_eq!(show("(contains? m :c)", &mut env), "#f");
+    assert_eq!(
+      show("(merge m {:b 3} {:d 4})", &mut env),
+      "{:a 1 :b 3 :d 4}"
+    );
+    assert_eq!(show("(map-entries m)", &mut env), "((:a 1) (:b 2))");
+
+    let program = "(define k {\"s\" 1 1.5 2 '(1 (2)) 3 [1 2] 4 'sym 5 {:x 1} 6})
+      (+ (get k \"s\") (get k 1.5) (get k '(1 (2))) (get k (cdr '(0 1 (2))))
+         (get k [1 2]) (get k 'sym) (get k {:x 1}))";
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(24));
+
+    assert_eq!(show("(== {:a 1 :b 2} {:b 2 :a 1})", &mut env), "#t");
+    assert_eq!(show("(= m {:a 1})", &mut env), "#f");
+
+    let equal = [
+      ("(= '(1 2) '(1 2))", "#t"),
+      ("(= '(1 2) (cons 1 '(2)))", "#t"),
+      ("(= '(1 2) '(1 3))", "#f"),
+      ("(= :a :a)", "#t"),
+      ("(= :a :b)", "#f"),
+      ("(= 'a 'a)", "#t"),
+      ("(== 'a 'a 'b)", "#f"),
+      ("(== '(1 (2)) (cdr '(0 1 (2))))", "#t"),
+      ("(= 1 1.0)", "#t"),
+      ("(= 1 1.5)", "#f"),
+      ("(== 1 1.0)", "#f"),
+      ("(get {1 :a} 1.0)", ":a"),
+      ("(get {1.0 :a} 1)", ":a"),
+      ("(get {'(1 2) :a} '(1.0 2))", ":a"),
+    ];
+    for (program, expected) in equal {
+      assert_eq!(show(program, &mut env), expected, "{}", program);
+    }
+
+    let program = "(defun f () 1) (defun g () 1) (define fm (assoc {} f 1))
+      (define v [1 2]) (define vm (assoc {} v 1)) (vector-set! v 0 5)
+      (define p (cons 1 2)) (define pm (assoc {} p 1)) (set-car! p 3)
+      (vector-set! (car (keys vm)) 0 7)";
+    eval(program, &mut env).unwrap();
+    let keys = [
+      ("(get fm f)", "1"),
+      ("(get fm g)", "#nil"),
+      ("(== [f] [f])", "#t"),
+      ("(== [f] [g])", "#f"),
+      ("(= {f 1} fm)", "#t"),
+      (
+        "[(get vm v) (get vm [1 2]) (get vm [5 2])]",
+        "[#nil 1 #nil]",
+      ),
+      ("vm", "{[1 2] 1}"),
+      ("[(get pm p) (get pm (cons 1 2))]", "[#nil 1]"),
+    ];
+    for (program, expected) in keys {
+      assert_eq!(show(program, &mut env), expected, "{}", program);
+    }
+
+    let err = eval("(get '(1) 1)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::TypeError);
+    let err = eval("(assoc m :a)", &mut env).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::ArityError);
+  }
+
+  #[test]
+  fn test_object_keys() {
+    let mut map = Map::default();
+    map.insert(Object::Float(0.0), Object::Integer(1));
+    map.insert(Object::list(vec![Object::Integer(1)]), Object::Integer(2));
+    map.insert(Object::String("a".into()), Object::Integer(3));
+    map.insert(Object::String("a".into()), Object::Integer(4));
+
+    let key = &parse_program("(1)", "<input>").unwrap()[0];
+    assert_eq!(map.get(&Object::Float(-0.0)), Some(&Object::Integer(1)));
+    assert_eq!(map.get(key), Some(&Object::Integer(2)));
+    assert_eq!(
+      map.get(&Object::String("a".into())),
+      Some(&Object::Integer(4))
+    );
+    assert_eq!(map.get(&Object::Symbol("a".to_string())), None);
+
+    assert_eq!(map.get(&Object::Integer(0)), Some(&Object::Integer(1)));
+    assert_eq!(map.remove(&Object::Float(0.0)), Some(Object::Integer(1)));
+    assert_eq!(map.get(key), Some(&Object::Integer(2)));
+    assert_eq!(map.len(), 2);
+
+    let nan = Object::Float(f64::NAN);
+    assert_eq!(nan, Object::Float(-f64::NAN));
+    map.insert(nan.clone(), Object::Integer(5));
+    assert_eq!(map.get(&nan), Some(&Object::Integer(5)));
+
+    let big = Object::Integer((1 << 53) + 1);
+    assert_ne!(big, Object::Float((1u64 << 53) as f64));
+    assert_eq!(map.get(&Object::Float(1e300)), None);
+
+    let hash = |obj: &Object| {
+      let mut hasher = std::hash::DefaultHasher::new();
+      std::hash::Hash::hash(obj, &mut hasher);
+      std::hash::Hasher::finish(&hasher)
+    };
+    let mut env = Rc::new(RefCell::new(Environment::new(Runtime::new())));
+    let maps = ["{:a 1}", "{:b 1}", "{:a 2}", "{:b 2 :a 1}", "{:a 1 :b 2}"]
+      .map(|program| eval(program, &mut env).unwrap());
+    assert_ne!(hash(&maps[0]), hash(&maps[1]));
+    assert_ne!(hash(&maps[0]), hash(&maps[2]));
+    assert_eq!(hash(&maps[3]), hash(&maps[4]));
   }
 }
@@ -1,114 +1,280 @@
 use std::error::Error;
 use std::fmt;
+use std::rc::Rc;
+
+use crate::span::{locate, Span};
 
 #[derive(Debug, PartialEq, Clone)]
-pub enum Token {
+pub enum TokenKind {
   Integer(i64),
   Float(f64),
   String(String),
   Symbol(String),
   Quote,
+  Quasiquote,
+  Unquote,
+  UnquoteSplicing,
   LParen,
   RParen,
+  LBracket,
+  RBracket,
+  LBrace,
+  RBrace,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+  pub kind: TokenKind,
+  pub span: Span,
+}
+
+impl fmt::Display for TokenKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use TokenKind::*;
+    match self {
+      Integer(n) => write!(f, "{}", n),
+      Float(n) => write!(f, "{}", n),
+      String(s) => write!(f, "{}", s),
+      Symbol(s) => write!(f, "{}", s),
+      Quote => write!(f, "'"),
+      Quasiquote => write!(f, "`"),
+      Unquote => write!(f, ","),
+      UnquoteSplicing => write!(f, ",@"),
+      LParen => write!(f, "("),
+      RParen => write!(f, ")"),
+      LBracket => write!(f, "["),
+      RBracket => write!(f, "]"),
+      LBrace => write!(f, "{{"),
+      RBrace => write!(f, "}}"),
+    }
+  }
 }
 
 impl fmt::Display for Token {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    use Token::*;
-    f.write_str(
-      (match self {
-        Integer(n) => format!("{}", n),
-        Float(n) => format!("{}", n),
-        String(s) => format!("{}", s),
-        Symbol(s) => format!("{}", s),
-        Quote => format!("'"),
-        LParen => format!("("),
-        RParen => format!(")"),
-      })
-      .as_str(),
-    )
+    write!(f, "{}", self.kind)
   }
 }
 
 #[derive(Debug)]
 pub struct TokenError {
-  err: String,
+  pub err: String,
+  pub span: Span,
 }
 
 impl Error for TokenError {}
 
 impl fmt::Display for TokenError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "Tokenization error: {}", self.err)
+    let err = format!("Tokenization error: {}", self.err);
+    write!(f, "{}", locate(err, &self.span))
   }
 }
 
-pub fn tokenize(input: &str) -> Result<Vec<Token>, TokenError> {
-  let mut tokens = Vec::new();
-  let mut chars = input.chars().collect::<Vec<char>>();
+struct Cursor {
+  file: Rc<str>,
+  chars: Vec<char>,
+  pos: usize,
+  line: usize,
+  column: usize,
+}
 
-  if chars.is_empty() {
-    return Ok(tokens);
+impl Cursor {
+  fn span(&self) -> Span {
+    Span::new(self.file.clone(), self.line, self.column)
   }
 
-  while chars.len() > 0 {
-    let mut ch = chars.remove(0);
-    match ch {
-      '(' => tokens.push(Token::LParen),
-      ')' => tokens.push(Token::RParen),
-      '"' => {
-        let mut word = String::new();
-        while chars.len() > 0 && chars[0] != '"' {
-          word.push(chars.remove(0));
-        }
+  fn peek(&self) -> Option<char> {
+    self.peek_nth(0)
+  }
 
-        if chars.len() > 0 && chars[0] == '"' {
-          chars.remove(0);
-        } else {
+  fn peek_nth(&self, n: usize) -> Option<char> {
+    self.chars.get(self.pos + n).copied()
+  }
+
+  fn next(&mut self) -> Option<char> {
+    let ch = self.peek()?;
+    self.pos += 1;
+
+    if ch == '\n' {
+      self.line += 1;
+      self.column = 1;
+    } else {
+      self.column += 1;
+    }
+
+    Some(ch)
+  }
+}
+
+fn is_delimiter(ch: char) -> bool {
+  ch.is_whitespace() || matches!(ch, '(' | ')' | '[' | ']' | '{' | '}' | ',')
+}
+
+/// Reads the `{...}` part of a `\u{...}` escape.
+fn read_unicode_escape(cursor: &mut Cursor, span: Span) -> Result<char, TokenError> {
+  let invalid = |span| TokenError {
+    err: "Invalid unicode escape, expected \\u{XXXX}".to_string(),
+    span,
+  };
+
+  if cursor.next() != Some('{') {
+    return Err(invalid(span));
+  }
+
+  let mut digits = String::new();
+  loop {
+    match cursor.next() {
+      Some('}') => break,
+      Some(ch) if ch.is_ascii_hexdigit() && digits.len() < 6 => digits.push(ch),
+      _ => return Err(invalid(span)),
+    }
+  }
+
+  u32::from_str_radix(&digits, 16)
+    .ok()
+    .and_then(char::from_u32)
+    .ok_or_else(|| invalid(span))
+}
+
+/// Reads a string literal after its opening quote, resolving escapes.
+fn read_string(cursor: &mut Cursor, span: Span) -> Result<String, TokenError> {
+  let mut word = String::new();
+
+  loop {
+    let escape_span = cursor.span();
+    match cursor.next() {
+      Some('"') => return Ok(word),
+      Some('\\') => match cursor.next() {
+        Some('n') => word.push('\n'),
+        Some('t') => word.push('\t'),
+        Some('r') => word.push('\r'),
+        Some('0') => word.push('\0'),
+        Some('\\') => word.push('\\'),
+        Some('"') => word.push('"'),
+        Some('u') => word.push(read_unicode_escape(cursor, escape_span)?),
+        Some(ch) => {
           return Err(TokenError {
-            err: format!("Unterminated string: {}", word),
-          });
+            err: format!("Unknown escape in string: \\{}", ch),
+            span: escape_span,
+          })
         }
+        None => break,
+      },
+      Some(ch) => word.push(ch),
+      None => break,
+    }
+  }
+
+  Err(TokenError {
+    err: format!("Unterminated string: {}", word),
+    span,
+  })
+}
+
+/// Reads a raw string `r"..."` or `r#"..."#` after its `r`. Raw strings
+/// have no escapes; the number of `#` decides which `"` closes them.
+fn read_raw_string(cursor: &mut Cursor, span: Span) -> Result<String, TokenError> {
+  let mut hashes = 0;
+  while cursor.peek() == Some('#') {
+    cursor.next();
+    hashes += 1;
+  }
+  cursor.next();
+
+  let mut word = String::new();
+  while let Some(ch) = cursor.next() {
+    if ch == '"' && (0..hashes).all(|n| cursor.peek_nth(n) == Some('#')) {
+      for _ in 0..hashes {
+        cursor.next();
+      }
+      return Ok(word);
+    }
+
+    word.push(ch);
+  }
+
+  Err(TokenError {
+    err: format!("Unterminated raw string: {}", word),
+    span,
+  })
+}
+
+fn is_raw_string_start(cursor: &Cursor) -> bool {
+  let mut n = 0;
+  while cursor.peek_nth(n) == Some('#') {
+    n += 1;
+  }
 
-        tokens.push(Token::String(word));
+  cursor.peek_nth(n) == Some('"')
+}
+
+/// Tokenizes `input`, attributing every token to `file` in its span.
+pub fn tokenize(input: &str, file: &str) -> Result<Vec<Token>, TokenError> {
+  let mut tokens = Vec::new();
+  let mut cursor = Cursor {
+    file: Rc::from(file),
+    chars: input.chars().collect(),
+    pos: 0,
+    line: 1,
+    column: 1,
+  };
+
+  loop {
+    let span = cursor.span();
+    let ch = match cursor.next() {
+      Some(ch) => ch,
+      None => break,
+    };
+
+    let kind = match ch {
+      '(' => TokenKind::LParen,
+      ')' => TokenKind::RParen,
+      '[' => TokenKind::LBracket,
+      ']' => TokenKind::RBracket,
+      '{' => TokenKind::LBrace,
+      '}' => TokenKind::RBrace,
+      '"' => TokenKind::String(read_string(&mut cursor, span.clone())?),
+      'r' if is_raw_string_start(&cursor) => {
+        TokenKind::String(read_raw_string(&mut cursor, span.clone())?)
       }
       ';' => {
-        while chars.len() > 0 && chars[0] != '\n' {
-          chars.remove(0);
+        while cursor.peek().is_some_and(|ch| ch != '\n') {
+          cursor.next();
         }
+        continue;
       }
-      '\'' => {
-        tokens.push(Token::Quote);
+      '\'' => TokenKind::Quote,
+      '`' => TokenKind::Quasiquote,
+      ',' if cursor.peek() == Some('@') => {
+        cursor.next();
+        TokenKind::UnquoteSplicing
       }
-      ' ' | '\n' | '\t' => continue,
+      ',' => TokenKind::Unquote,
+      ch if ch.is_whitespace() => continue,
       _ => {
         let mut word = String::from(ch);
 
-        while chars.len() > 0 && !ch.is_whitespace() && ch != '(' && ch != ')' {
-          let peek = chars[0];
-          if peek == '(' || peek == ')' {
+        while let Some(peek) = cursor.peek() {
+          if is_delimiter(peek) {
             break;
           }
 
-          ch = chars.remove(0);
-          word.push(ch);
+          word.push(peek);
+          cursor.next();
         }
 
-        let word = String::from(word.trim());
-
-        if !word.is_empty() {
-          let token = if let Ok(i) = word.parse::<i64>() {
-            Token::Integer(i)
-          } else if let Ok(f) = word.parse::<f64>() {
-            Token::Float(f)
-          } else {
-            Token::Symbol(word)
-          };
-
-          tokens.push(token);
+        if let Ok(i) = word.parse::<i64>() {
+          TokenKind::Integer(i)
+        } else if let Ok(f) = word.parse::<f64>() {
+          TokenKind::Float(f)
+        } else {
+          TokenKind::Symbol(word)
         }
       }
-    }
+    };
+
+    tokens.push(Token { kind, span });
   }
 
   Ok(tokens)
@@ -118,18 +284,22 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, TokenError> {
 mod lexer_tests {
   use super::*;
 
+  fn kinds(tokens: Vec<Token>) -> Vec<TokenKind> {
+    tokens.into_iter().map(|t| t.kind).collect()
+  }
+
   #[test]
   fn test_add() {
     let program = "(+ 2 2)";
-    let tokens = tokenize(program).unwrap();
+    let tokens = tokenize(program, "<input>").unwrap();
     assert_eq!(
-      tokens,
+      kinds(tokens),
       vec![
-        Token::LParen,
-        Token::Symbol("+".to_string()),
-        Token::Integer(2),
-        Token::Integer(2),
-        Token::RParen,
+        TokenKind::LParen,
+        TokenKind::Symbol("+".to_string()),
+        TokenKind::Integer(2),
+        TokenKind::Integer(2),
+        TokenKind::RParen,
       ]
     );
   }
@@ -137,23 +307,132 @@ mod lexer_tests {
   #[test]
   fn test_quotation() {
     let program = "'(1  2 3)";
-    let tokens = tokenize(program).unwrap();
+    let tokens = tokenize(program, "<input>").unwrap();
     assert_eq!(
-      tokens,
+      kinds(tokens),
       vec![
-        Token::Quote,
-        Token::LParen,
-        Token::Integer(1),
-        Token::Integer(2),
-        Token::Integer(3),
-        Token::RParen,
+        TokenKind::Quote,
+        TokenKind::LParen,
+        TokenKind::Integer(1),
+        TokenKind::Integer(2),
+        TokenKind::Integer(3),
+        TokenKind::RParen,
       ]
     )
   }
 
+  #[test]
+  fn test_brackets() {
+    let tokens = tokenize("[1 a]", "<input>").unwrap();
+    assert_eq!(
+      kinds(tokens),
+      vec![
+        TokenKind::LBracket,
+        TokenKind::Integer(1),
+        TokenKind::Symbol("a".to_string()),
+        TokenKind::RBracket,
+      ]
+    );
+  }
+
+  #[test]
+  fn test_braces() {
+    let tokens = tokenize("{:a 1}", "<input>").unwrap();
+    assert_eq!(
+      kinds(tokens),
+      vec![
+        TokenKind::LBrace,
+        TokenKind::Symbol(":a".to_string()),
+        TokenKind::Integer(1),
+        TokenKind::RBrace,
+      ]
+    );
+  }
+
   #[test]
   fn test_symbol() {
-    let list = tokenize("#t").unwrap();
-    assert_eq!(list, vec![Token::Symbol("#t".to_string())])
+    let list = tokenize("#t", "<input>").unwrap();
+    assert_eq!(kinds(list), vec![TokenKind::Symbol("#t".to_string())])
+  }
+
+  #[test]
+  fn test_spans() {
+    let tokens = tokenize("(define x\n  \"hi\") ; done\n'y", "a.tl").unwrap();
+    let positions = tokens
+      .iter()
+      .map(|t| (t.span.line, t.span.column))
+      .collect::<Vec<_>>();
+
+    assert_eq!(
+      positions,
+      vec![(1, 1), (1, 2), (1, 9), (2, 3), (2, 7), (3, 1), (3, 2)]
+    );
+    assert_eq!(tokens[3].span.to_string(), "a.tl:2:3");
+  }
+
+  #[test]
+  fn test_unterminated_string_location() {
+    let err = tokenize("(print! \"oops)", "a.tl").unwrap_err();
+    assert!(err.to_string().ends_with("--> a.tl:1:9"));
+  }
+
+  #[test]
+  fn test_string_escapes() {
+    let tokens = tokenize(r#""a\"b\\c\nd\te \u{1F600}""#, "<input>").unwrap();
+    assert_eq!(
+      kinds(tokens),
+      vec![TokenKind::String("a\"b\\c\nd\te \u{1F600}".to_string())]
+    );
+  }
+
+  #[test]
+  fn test_multiline_string() {
+    let tokens = tokenize("\"one\ntwo\" x", "<input>").unwrap();
+    assert_eq!(tokens[0].kind, TokenKind::String("one\ntwo".to_string()));
+    assert_eq!((tokens[1].span.line, tokens[1].span.column), (2, 6));
+  }
+
+  #[test]
+  fn test_raw_strings() {
+    let tokens = tokenize(r###"r"C:\path\n" r#"say "hi""# rest"###, "<input>").unwrap();
+    assert_eq!(
+      kinds(tokens),
+      vec![
+        TokenKind::String("C:\\path\\n".to_string()),
+        TokenKind::String("say \"hi\"".to_string()),
+        TokenKind::Symbol("rest".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_invalid_escapes() {
+    let err = tokenize("\"ab\\q\"", "a.tl").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Tokenization error: Unknown escape in string: \\q\n  --> a.tl:1:4"
+    );
+
+    assert!(tokenize("\"\\u{110000}\"", "a.tl").is_err());
+    assert!(tokenize("\"\\u41\"", "a.tl").is_err());
+    assert!(tokenize("r#\"open\"", "a.tl").is_err());
+  }
+
+  #[test]
+  fn test_quasiquotation() {
+    let tokens = tokenize("`(a ,b ,@rest)", "<input>").unwrap();
+    assert_eq!(
+      kinds(tokens),
+      vec![
+        TokenKind::Quasiquote,
+        TokenKind::LParen,
+        TokenKind::Symbol("a".to_string()),
+        TokenKind::Unquote,
+        TokenKind::Symbol("b".to_string()),
+        TokenKind::UnquoteSplicing,
+        TokenKind::Symbol("rest".to_string()),
+        TokenKind::RParen,
+      ]
+    )
   }
 }
@@ -1,20 +1,49 @@
+mod bytecode;
 mod environment;
+mod error;
 mod eval;
 mod lexer;
 mod object;
 mod operators;
 mod parser;
 mod runtime;
+mod span;
+mod syntax_rules;
+mod vm;
 
 use std::cell::RefCell;
 use std::rc::Rc;
 
-pub fn tlisp_eval(input: &str) -> Result<String, String> {
+pub use error::{ErrorKind, EvalError, Frame};
+pub use runtime::{Backend, Runtime, DEFAULT_MAX_DEPTH};
+pub use span::Span;
+
+pub fn tlisp_eval(input: &str) -> Result<String, EvalError> {
   let runtime = runtime::Runtime::new();
   let mut env = Rc::new(RefCell::new(environment::Environment::new(runtime)));
 
-  match eval::eval(input.as_ref(), &mut env) {
+  match eval::eval(input, &mut env) {
+    Ok(object) => Ok(format!("{}", object)),
+    Err(e) => Err(e),
+  }
+}
+
+/// Same as [`tlisp_eval`], but errors point at locations in `file`.
+pub fn tlisp_eval_source(input: &str, file: &str) -> Result<String, EvalError> {
+  tlisp_eval_with_runtime(input, file, Runtime::new())
+}
+
+/// Same as [`tlisp_eval_source`], with a configured `runtime`, e.g. one with
+/// a different recursion depth limit.
+pub fn tlisp_eval_with_runtime(
+  input: &str,
+  file: &str,
+  runtime: Runtime,
+) -> Result<String, EvalError> {
+  let mut env = Rc::new(RefCell::new(environment::Environment::new(runtime)));
+
+  match eval::eval_source(input, file, &mut env) {
     Ok(object) => Ok(format!("{}", object)),
-    Err(e) => Err(format!("{}", e)),
+    Err(e) => Err(e),
   }
 }
@@ -1,10 +1,15 @@
+mod bytecode;
 mod environment;
+mod error;
 mod eval;
 mod lexer;
 mod object;
 mod operators;
 mod parser;
 mod runtime;
+mod span;
+mod syntax_rules;
+mod vm;
 
 use std::fs::File;
 use std::io::Read;
@@ -14,6 +19,11 @@ use linefeed::{Interface, ReadResult, Signal};
 
 const PROMPT: &str = "tlisp> ";
 
+/// The REPL runs on its own thread with a large stack, so that programs can
+/// recurse deeper than the default limit allows.
+const STACK_SIZE: usize = 512 * 1024 * 1024;
+const MAX_DEPTH: usize = 50_000;
+
 fn load_file(path: &str, env: &mut Rc<RefCell<environment::Environment>>) {
   let file = File::open(path);
 
@@ -22,9 +32,9 @@ fn load_file(path: &str, env: &mut Rc<RefCell<environment::Environment>>) {
       let mut prelude = String::new();
       f.read_to_string(&mut prelude).expect("Failed to read file");
 
-      prelude = format!("({})", prelude);
-
-      eval::eval(&prelude, env).unwrap();
+      if let Err(e) = eval::eval_source(&prelude, path, env) {
+        println!("{}", e);
+      }
     }
     Err(e) => {
       println!("Error while loading prelude: {}", e);
@@ -32,7 +42,19 @@ fn load_file(path: &str, env: &mut Rc<RefCell<environment::Environment>>) {
   }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() {
+  let repl = std::thread::Builder::new()
+    .stack_size(STACK_SIZE)
+    .spawn(|| repl().map_err(|e| e.to_string()))
+    .expect("Failed to start the REPL");
+
+  if let Err(e) = repl.join().expect("The REPL panicked") {
+    eprintln!("{}", e);
+    std::process::exit(1);
+  }
+}
+
+fn repl() -> Result<(), Box<dyn std::error::Error>> {
   let reader = Interface::new(PROMPT).unwrap();
 
   reader.set_report_signal(Signal::Break, true);
@@ -41,10 +63,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
   reader.set_report_signal(Signal::Suspend, true);
   reader.set_report_signal(Signal::Quit, true);
 
-  let runtime = runtime::Runtime::new();
+  let backend = match std::env::args().any(|arg| arg == "--bytecode") {
+    true => runtime::Backend::Bytecode,
+    false => runtime::Backend::TreeWalker,
+  };
+  let runtime = runtime::Runtime::new()
+    .with_max_depth(MAX_DEPTH)
+    .with_backend(backend);
   let mut env = Rc::new(RefCell::new(environment::Environment::new(runtime)));
 
-  reader.set_prompt(format!("{}", PROMPT).as_ref())?;
+  reader.set_prompt(PROMPT)?;
 
   while let ReadResult::Input(input) = reader.read_line()? {
     if input.eq("exit") {
@@ -73,7 +101,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     match eval::eval(input.as_ref(), &mut env) {
-      Ok(result) => println!("{}", result),
+      Ok(result) => println!("{:#}", result),
       Err(e) => println!("{}", e),
     }
 
@@ -1,31 +1,590 @@
 use std::{
   cell::RefCell,
+  collections::HashMap,
   fmt::{self, Debug},
+  hash::{DefaultHasher, Hash, Hasher},
+  mem,
+  ops::{Deref, Range},
   rc::Rc,
 };
 
-use crate::environment::Environment;
+use crate::{
+  bytecode::Code,
+  environment::Environment,
+  error::EvalError,
+  eval::{Continuation, Generator},
+  span::Span,
+  syntax_rules::SyntaxRules,
+};
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Default)]
 pub enum Object {
+  #[default]
   Void,
   Cond,
   Quote(Rc<Object>),
+  Quasiquote(Rc<Object>),
+  Unquote(Rc<Object>),
+  UnquoteSplicing(Rc<Object>),
   Keyword(String),
   Native(String),
   Operator(String),
   Float(f64),
   Integer(i64),
   Bool(bool),
-  String(String),
+  String(Rc<str>),
+  Symbol(String),
+  Lambda(Rc<Lambda>),
+  Macro(Rc<Lambda>),
+  Syntax(Rc<SyntaxRules>, Rc<RefCell<Environment>>),
+  List(List, Span),
+  /// A cons cell, from `cons` or a dotted list such as `(a . b)`.
+  Pair(Rc<Pair>),
+  /// A vector, from `vector` or a `[...]` literal. Copies of it share its
+  /// items, which `vector-set!` changes in place.
+  Vector(Rc<RefCell<Vec<Object>>>),
+  /// A map, from a `{...}` literal or `assoc`. Natives that change a map
+  /// return a changed copy.
+  Map(Rc<Map>),
+  /// An error caught by `try`.
+  Error(Rc<EvalError>),
+  /// A continuation captured by `call/cc` or `let/ec`.
+  Continuation(Rc<Continuation>),
+  /// A generator created by `(generator ...)`.
+  Generator(Rc<Generator>),
+}
+
+/// How many arguments a function accepts; `max` is `None` when it takes
+/// any number of extra arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Arity {
+  pub min: usize,
+  pub max: Option<usize>,
+}
+
+impl Arity {
+  pub fn exactly(n: usize) -> Self {
+    Arity {
+      min: n,
+      max: Some(n),
+    }
+  }
+
+  pub fn at_least(n: usize) -> Self {
+    Arity { min: n, max: None }
+  }
+
+  pub fn between(min: usize, max: usize) -> Self {
+    Arity {
+      min,
+      max: Some(max),
+    }
+  }
+
+  /// Checks that `name` may be called with `count` arguments.
+  pub fn check(&self, name: &str, count: usize) -> Result<(), EvalError> {
+    if count < self.min {
+      return Err(EvalError::arity(format!(
+        "Too few arguments for {}: expected {}, got {}",
+        name, self, count
+      )));
+    }
+
+    match self.max {
+      Some(max) if count > max => Err(EvalError::arity(format!(
+        "Too many arguments for {}: expected {}, got {}",
+        name, self, count
+      ))),
+      _ => Ok(()),
+    }
+  }
+}
+
+impl fmt::Display for Arity {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.max {
+      Some(max) if max == self.min => write!(f, "{}", max),
+      Some(max) => write!(f, "{} to {}", self.min, max),
+      None => write!(f, "at least {}", self.min),
+    }
+  }
+}
+
+/// What a value is bound to: a name, or a list pattern such as
+/// `(a (b c) . rest)` taking the value apart element by element.
+#[derive(Clone, PartialEq)]
+pub enum Pattern {
   Symbol(String),
-  Lambda(Vec<String>, Box<Object>, Rc<RefCell<Environment>>),
-  List(Vec<Object>),
+  List(Vec<Pattern>, Option<Box<Pattern>>),
+}
+
+impl fmt::Display for Pattern {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Pattern::Symbol(s) => write!(f, "{}", s),
+      Pattern::List(patterns, rest) => {
+        let mut items = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        if let Some(rest) = rest {
+          items.push(".".to_string());
+          items.push(rest.to_string());
+        }
+
+        write!(f, "({})", items.join(" "))
+      }
+    }
+  }
+}
+
+/// A lambda list such as `(a &optional (b 1) &rest more &key (c 2))`.
+/// Defaults are kept unevaluated; a missing default is `#nil`.
+#[derive(Clone, Default, PartialEq)]
+pub struct Params {
+  pub required: Vec<Pattern>,
+  pub optional: Vec<(String, Object)>,
+  pub rest: Option<String>,
+  pub keys: Vec<(String, Object)>,
+}
+
+impl Params {
+  pub fn arity(&self) -> Arity {
+    let min = self.required.len();
+
+    match self.rest.is_some() || !self.keys.is_empty() {
+      true => Arity::at_least(min),
+      false => Arity::between(min, min + self.optional.len()),
+    }
+  }
+}
+
+impl fmt::Display for Params {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let with_default = |(name, default): &(String, Object)| match default {
+      Object::Void => name.clone(),
+      default => format!("({} {})", name, default),
+    };
+
+    let mut params = self
+      .required
+      .iter()
+      .map(|p| p.to_string())
+      .collect::<Vec<_>>();
+
+    if !self.optional.is_empty() {
+      params.push("&optional".to_string());
+      params.extend(self.optional.iter().map(with_default));
+    }
+
+    if let Some(rest) = &self.rest {
+      params.push("&rest".to_string());
+      params.push(rest.clone());
+    }
+
+    if !self.keys.is_empty() {
+      params.push("&key".to_string());
+      params.extend(self.keys.iter().map(with_default));
+    }
+
+    write!(f, "{}", params.join(" "))
+  }
+}
+
+/// A function, or a `defmacro` macro: its parameters, the forms of its
+/// body, the environment it closes over and an optional docstring. Named
+/// functions show their name in the call stack of errors.
+#[derive(Clone)]
+pub struct Lambda {
+  pub name: Option<String>,
+  pub params: Params,
+  pub body: List,
+  pub env: Rc<RefCell<Environment>>,
+  pub doc: Option<Rc<str>>,
+  /// The body compiled to bytecode, for lambdas created by the VM.
+  pub code: Option<Rc<Code>>,
+}
+
+impl Lambda {
+  /// The name calls of this function appear under in a call stack.
+  pub fn name(&self) -> &str {
+    self.name.as_deref().unwrap_or("lambda")
+  }
+}
+
+impl Object {
+  /// A copy sharing no vector or pair with the original, so that changing
+  /// those in place leaves the copy as it was. Map keys are kept this way.
+  pub fn detached(&self) -> Object {
+    let detach = |items: &[Object]| items.iter().map(Object::detached).collect();
+
+    match self {
+      Object::Vector(items) => Object::Vector(Rc::new(RefCell::new(detach(&items.borrow())))),
+      Object::Pair(_) => match spine(self) {
+        (items, Object::Void) => Object::list(detach(&items)),
+        (items, tail) => Object::dotted(detach(&items), tail.detached()),
+      },
+      Object::List(items, span) => Object::List(List::from(detach(items)), span.clone()),
+      Object::Quote(o) => Object::Quote(Rc::new(o.detached())),
+      Object::Map(map) => Object::Map(Rc::new(
+        map
+          .iter()
+          .map(|(key, value)| (key.clone(), value.detached()))
+          .collect(),
+      )),
+      o => o.clone(),
+    }
+  }
+
+  /// Builds a list that does not come from source code, so it has no span.
+  pub fn list(items: Vec<Object>) -> Object {
+    Object::List(List::from(items), Span::default())
+  }
+
+  /// Builds the list `items` followed by `tail` out of pairs, a dotted
+  /// list unless `tail` is a list.
+  pub fn dotted(items: Vec<Object>, tail: Object) -> Object {
+    items
+      .into_iter()
+      .rev()
+      .fold(tail, |cdr, car| Object::Pair(Rc::new(Pair::new(car, cdr))))
+  }
+
+  /// The first item and the rest of a list or a pair, as `car` and `cdr`
+  /// return them, or `None` for anything else or the empty list.
+  pub fn split_first(&self) -> Option<(Object, Object)> {
+    match self {
+      Object::List(items, _) => {
+        let first = items.first()?.clone();
+        Some((first, Object::List(items.tail(1), Span::default())))
+      }
+      Object::Pair(pair) => Some((pair.car.borrow().clone(), pair.cdr.borrow().clone())),
+      _ => None,
+    }
+  }
+
+  /// The items of a proper list: a `List`, or pairs ending with one or with
+  /// `#nil`. `None` for anything else, dotted lists included.
+  pub fn list_items(&self) -> Option<Vec<Object>> {
+    let mut items = Vec::new();
+    let mut rest = self.clone();
+
+    loop {
+      match rest {
+        Object::Pair(pair) => {
+          items.push(pair.car.borrow().clone());
+          rest = pair.cdr.borrow().clone();
+        }
+        Object::List(list, _) => {
+          items.extend(list.iter().cloned());
+          return Some(items);
+        }
+        Object::Void => return Some(items),
+        _ => return None,
+      }
+    }
+  }
+}
+
+/// A cons cell. Its `cdr` is the rest of the list it starts, another pair
+/// or a `List`, unless the list is dotted. Unlike a `List`, it can be
+/// changed in place with `set-car!` and `set-cdr!`.
+#[derive(PartialEq)]
+pub struct Pair {
+  pub car: RefCell<Object>,
+  pub cdr: RefCell<Object>,
+}
+
+impl Pair {
+  pub fn new(car: Object, cdr: Object) -> Self {
+    Pair {
+      car: RefCell::new(car),
+      cdr: RefCell::new(cdr),
+    }
+  }
+}
+
+/// The items of a list, shared by reference counting: cloning a list, or
+/// taking a part of it such as its tail or the body of a function, copies
+/// none of them. A list read by the parser also knows where each of its
+/// items starts, so that errors in atoms point at the atom.
+#[derive(Clone)]
+pub struct List {
+  items: Rc<Vec<Object>>,
+  spans: Option<Rc<Vec<Span>>>,
+  range: Range<usize>,
+}
+
+impl List {
+  /// A list of `items` starting at `spans` in the source.
+  pub fn with_spans(items: Vec<Object>, spans: Vec<Span>) -> List {
+    assert_eq!(items.len(), spans.len());
+
+    List {
+      range: 0..items.len(),
+      items: Rc::new(items),
+      spans: Some(Rc::new(spans)),
+    }
+  }
+
+  /// Where the item at `index` starts in the source, if it is known.
+  pub fn span_of(&self, index: usize) -> Option<&Span> {
+    let spans = self.spans.as_ref()?;
+    spans[self.range.clone()].get(index)
+  }
+
+  /// The items in `range` of this list.
+  pub fn slice(&self, range: Range<usize>) -> List {
+    assert!(range.start <= range.end && range.end <= self.len());
+
+    List {
+      items: self.items.clone(),
+      spans: self.spans.clone(),
+      range: self.range.start + range.start..self.range.start + range.end,
+    }
+  }
+
+  /// The items of this list from `start` on, or none if it is shorter.
+  pub fn tail(&self, start: usize) -> List {
+    let len = self.len();
+    self.slice(start.min(len)..len)
+  }
+}
+
+impl From<Vec<Object>> for List {
+  fn from(items: Vec<Object>) -> Self {
+    List {
+      range: 0..items.len(),
+      items: Rc::new(items),
+      spans: None,
+    }
+  }
+}
+
+impl FromIterator<Object> for List {
+  fn from_iter<I: IntoIterator<Item = Object>>(iter: I) -> Self {
+    List::from(iter.into_iter().collect::<Vec<Object>>())
+  }
+}
+
+impl Deref for List {
+  type Target = [Object];
+
+  fn deref(&self) -> &[Object] {
+    &self.items[self.range.clone()]
+  }
+}
+
+impl PartialEq for List {
+  fn eq(&self, other: &Self) -> bool {
+    **self == **other
+  }
+}
+
+impl Debug for List {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_list().entries(self.iter()).finish()
+  }
+}
+
+/// The items of a list made of pairs, a `List` or both, and what ends it:
+/// `#nil` for a proper list, the last `cdr` for a dotted one.
+fn spine(obj: &Object) -> (Vec<Object>, Object) {
+  let mut items = Vec::new();
+  let mut rest = obj.clone();
+
+  loop {
+    match rest {
+      Object::Pair(pair) => {
+        items.push(pair.car.borrow().clone());
+        rest = pair.cdr.borrow().clone();
+      }
+      Object::List(list, _) => {
+        items.extend(list.iter().cloned());
+        return (items, Object::Void);
+      }
+      tail => return (items, tail),
+    }
+  }
 }
 
-impl Default for Object {
-  fn default() -> Self {
-    Object::Void
+/// Lists are compared by their items, so one built with `cons` equals the
+/// literal list it prints as. An integer equals a float of the same value,
+/// as it does with `=`. Functions and macros are only equal to themselves.
+impl PartialEq for Object {
+  fn eq(&self, other: &Self) -> bool {
+    use Object::*;
+
+    match (self, other) {
+      (Void, Void) | (Cond, Cond) => true,
+      (Quote(a), Quote(b))
+      | (Quasiquote(a), Quasiquote(b))
+      | (Unquote(a), Unquote(b))
+      | (UnquoteSplicing(a), UnquoteSplicing(b)) => a == b,
+      (Keyword(a), Keyword(b))
+      | (Native(a), Native(b))
+      | (Operator(a), Operator(b))
+      | (Symbol(a), Symbol(b)) => a == b,
+      (Float(a), Float(b)) => a == b || (a.is_nan() && b.is_nan()),
+      (Integer(a), Integer(b)) => a == b,
+      (Integer(n), Float(f)) | (Float(f), Integer(n)) => exact_integer(*f) == Some(*n),
+      (Bool(a), Bool(b)) => a == b,
+      (String(a), String(b)) => a == b,
+      (Lambda(a), Lambda(b)) | (Macro(a), Macro(b)) => Rc::ptr_eq(a, b),
+      (Syntax(a, a_env), Syntax(b, b_env)) => Rc::ptr_eq(a, b) && Rc::ptr_eq(a_env, b_env),
+      (List(a, _), List(b, _)) => a == b,
+      (List(..) | Pair(_), List(..) | Pair(_)) => spine(self) == spine(other),
+      (Vector(a), Vector(b)) => a == b,
+      (Map(a), Map(b)) => a == b,
+      (Error(a), Error(b)) => a == b,
+      (Continuation(a), Continuation(b)) => a == b,
+      (Generator(a), Generator(b)) => a == b,
+      _ => false,
+    }
+  }
+}
+
+/// The integer `f` is exactly equal to, if there is one.
+fn exact_integer(f: f64) -> Option<i64> {
+  // `i64::MAX as f64` rounds up to 2^63, which is out of range.
+  match f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 {
+    true => Some(f as i64),
+    false => None,
+  }
+}
+
+/// Objects are compared structurally, and functions by identity, so they
+/// can be the keys of a `Map`. Every NaN equals every other, so that
+/// equality stays reflexive.
+impl Eq for Object {}
+
+impl Hash for Object {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    match self {
+      // Hashed like the `List` of the same items, which it equals.
+      Object::Pair(_) => {
+        let (items, tail) = spine(self);
+        mem::discriminant(&Object::list(Vec::new())).hash(state);
+        items.hash(state);
+        return tail.hash(state);
+      }
+      // Hashed like the integer it equals, `-0.0` like `0`.
+      Object::Float(f) => {
+        if let Some(n) = exact_integer(*f) {
+          return Object::Integer(n).hash(state);
+        }
+      }
+      _ => {}
+    }
+
+    mem::discriminant(self).hash(state);
+
+    match self {
+      Object::Integer(n) => n.hash(state),
+      Object::Float(n) if n.is_nan() => f64::NAN.to_bits().hash(state),
+      Object::Float(n) => n.to_bits().hash(state),
+      Object::Bool(b) => b.hash(state),
+      Object::String(s) => s.hash(state),
+      Object::Symbol(s) | Object::Keyword(s) | Object::Native(s) | Object::Operator(s) => {
+        s.hash(state)
+      }
+      Object::Quote(o)
+      | Object::Quasiquote(o)
+      | Object::Unquote(o)
+      | Object::UnquoteSplicing(o) => o.hash(state),
+      Object::List(items, _) => {
+        items[..].hash(state);
+        Object::Void.hash(state);
+      }
+      Object::Vector(items) => items.borrow().hash(state),
+      // Equal maps can list their entries in different orders, so the
+      // hashes of the entries are combined in a way that ignores it.
+      Object::Map(map) => {
+        let entries = map.iter().fold(0u64, |sum, entry| {
+          let mut hasher = DefaultHasher::new();
+          entry.hash(&mut hasher);
+          sum.wrapping_add(hasher.finish())
+        });
+        map.len().hash(state);
+        entries.hash(state);
+      }
+      _ => {}
+    }
+  }
+}
+
+/// Entries of a map, kept in the order their keys were first added.
+#[derive(Clone, Default)]
+pub struct Map {
+  entries: Vec<(Object, Object)>,
+  index: HashMap<Object, usize>,
+}
+
+impl Map {
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  pub fn get(&self, key: &Object) -> Option<&Object> {
+    let i = *self.index.get(key)?;
+    Some(&self.entries[i].1)
+  }
+
+  pub fn contains_key(&self, key: &Object) -> bool {
+    self.index.contains_key(key)
+  }
+
+  /// Sets the value of `key`, keeping its place if it is already there. A
+  /// new key is detached, so that changing it in place later cannot move
+  /// its entry.
+  pub fn insert(&mut self, key: Object, value: Object) {
+    match self.index.get(&key) {
+      Some(&i) => self.entries[i].1 = value,
+      None => {
+        let key = key.detached();
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+      }
+    }
+  }
+
+  pub fn remove(&mut self, key: &Object) -> Option<Object> {
+    let i = self.index.remove(key)?;
+    let (_, value) = self.entries.remove(i);
+
+    for (key, _) in &self.entries[i..] {
+      if let Some(j) = self.index.get_mut(key) {
+        *j -= 1;
+      }
+    }
+
+    Some(value)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&Object, &Object)> {
+    self.entries.iter().map(|(key, value)| (key, value))
+  }
+}
+
+/// Maps are equal when they have the same entries, in whatever order.
+impl PartialEq for Map {
+  fn eq(&self, other: &Self) -> bool {
+    self.len() == other.len()
+      && self
+        .iter()
+        .all(|(key, value)| other.get(key) == Some(value))
+  }
+}
+
+impl FromIterator<(Object, Object)> for Map {
+  fn from_iter<I: IntoIterator<Item = (Object, Object)>>(iter: I) -> Self {
+    let mut map = Map::default();
+    for (key, value) in iter {
+      map.insert(key, value);
+    }
+    map
   }
 }
 
@@ -45,13 +604,30 @@ impl Debug for Object {
       Object::String(s) => write!(f, "String({})", s),
       Object::Symbol(s) => write!(f, "Symbol({})", s),
       Object::Keyword(s) => write!(f, "Keyword({})", s),
-      Object::Lambda(params, body, _env) => {
-        let params_str = params.join(" ");
+      Object::Lambda(lambda) => {
+        let params_str = lambda.params.to_string();
 
-        write!(f, "Lambda(params: ({}), body: {:?})", params_str, body)
+        write!(
+          f,
+          "Lambda(params: ({}), body: {:?})",
+          params_str, lambda.body
+        )
       }
+      Object::Macro(lambda) => {
+        let params_str = lambda.params.to_string();
+
+        write!(
+          f,
+          "Macro(params: ({}), body: {:?})",
+          params_str, lambda.body
+        )
+      }
+      Object::Syntax(rules, _env) => write!(f, "Syntax({:?})", rules),
+      Object::Error(err) => write!(f, "Error({:?})", err),
+      Object::Continuation(k) => write!(f, "{:?}", k),
+      Object::Generator(generator) => write!(f, "{:?}", generator),
       Object::Native(s) => write!(f, "Native({})", s),
-      Object::List(list) => {
+      Object::List(list, _span) => {
         let list_str = list
           .iter()
           .map(|x| format!("{:?}", x))
@@ -60,15 +636,53 @@ impl Debug for Object {
 
         write!(f, "List({})", list_str)
       }
+      Object::Pair(pair) => write!(f, "Pair({:?} . {:?})", pair.car.borrow(), pair.cdr.borrow()),
+      Object::Vector(items) => write!(f, "Vector({:?})", items.borrow()),
+      Object::Map(map) => f.debug_map().entries(map.iter()).finish(),
       Object::Cond => write!(f, "Cond"),
       Object::Quote(o) => write!(f, "Quote({:?})", o),
+      Object::Quasiquote(o) => write!(f, "Quasiquote({:?})", o),
+      Object::Unquote(o) => write!(f, "Unquote({:?})", o),
+      Object::UnquoteSplicing(o) => write!(f, "UnquoteSplicing({:?})", o),
       Object::Operator(s) => write!(f, "Operator({})", s),
     }
   }
 }
 
+/// Quotes `s` and escapes it, so that the lexer reads it back as `s`.
+fn escape_string(s: &str) -> String {
+  let mut escaped = String::from('"');
+
+  for ch in s.chars() {
+    match ch {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\t' => escaped.push_str("\\t"),
+      '\r' => escaped.push_str("\\r"),
+      '\0' => escaped.push_str("\\0"),
+      ch if ch.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", ch as u32)),
+      ch => escaped.push(ch),
+    }
+  }
+
+  escaped.push('"');
+  escaped
+}
+
+/// `{}` displays objects the way `print!` shows them, while the alternate
+/// `{:#}` writes them in readable form: strings are quoted and escaped.
 impl fmt::Display for Object {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let readable = f.alternate();
+    let show = |o: &Object| {
+      if readable {
+        format!("{:#}", o)
+      } else {
+        format!("{}", o)
+      }
+    };
+
     match self {
       Object::Void => write!(f, "#nil"),
       Object::Integer(n) => write!(f, "{}", n),
@@ -81,25 +695,83 @@ impl fmt::Display for Object {
       }
       Object::Symbol(s) => write!(f, "{}", s),
       Object::Native(s) => write!(f, "{}", s),
-      Object::Lambda(params, body, _env) => {
-        let params_str = params.join(" ");
+      Object::Lambda(lambda) => {
+        let params_str = lambda.params.to_string();
+        let body_str = lambda
+          .body
+          .iter()
+          .map(show)
+          .collect::<Vec<String>>()
+          .join(" ");
 
-        write!(f, "(lambda ({}) {})", params_str, body)
+        write!(f, "(lambda ({}) {})", params_str, body_str)
       }
-      Object::List(list) => {
-        let list_str = list
+      Object::Macro(lambda) => {
+        let params_str = lambda.params.to_string();
+        let body_str = lambda
+          .body
           .iter()
-          .map(|x| format!("{}", x))
+          .map(show)
           .collect::<Vec<String>>()
           .join(" ");
 
+        write!(f, "(macro ({}) {})", params_str, body_str)
+      }
+      Object::Syntax(_rules, _env) => write!(f, "(syntax-rules ...)"),
+      Object::Error(err) => write!(f, "#<error {} {}>", err.kind.keyword(), err.message),
+      Object::Continuation(_) => write!(f, "#<continuation>"),
+      Object::Generator(_) => write!(f, "#<generator>"),
+      Object::List(list, _span) => {
+        let list_str = list.iter().map(show).collect::<Vec<String>>().join(" ");
+
         write!(f, "({})", list_str)
       }
+      Object::Pair(pair) => {
+        let mut items = vec![show(&pair.car.borrow())];
+        let mut rest = pair.cdr.borrow().clone();
+
+        loop {
+          match rest {
+            Object::Pair(pair) => {
+              items.push(show(&pair.car.borrow()));
+              rest = pair.cdr.borrow().clone();
+            }
+            Object::List(list, _) => {
+              items.extend(list.iter().map(show));
+              break;
+            }
+            Object::Void => break,
+            tail => {
+              items.push(format!(". {}", show(&tail)));
+              break;
+            }
+          }
+        }
+
+        write!(f, "({})", items.join(" "))
+      }
+      Object::Vector(items) => {
+        let items_str = items.borrow().iter().map(show).collect::<Vec<String>>();
+
+        write!(f, "[{}]", items_str.join(" "))
+      }
+      Object::Map(map) => {
+        let entries = map
+          .iter()
+          .map(|(key, value)| format!("{} {}", show(key), show(value)))
+          .collect::<Vec<String>>();
+
+        write!(f, "{{{}}}", entries.join(" "))
+      }
       Object::Keyword(s) => write!(f, "{}", s),
       Object::Operator(s) => write!(f, "{}", s),
       Object::Float(n) => write!(f, "{}", n),
+      Object::String(s) if readable => write!(f, "{}", escape_string(s)),
       Object::String(s) => write!(f, "{}", s),
-      Object::Quote(o) => write!(f, "'{}", o),
+      Object::Quote(o) => write!(f, "'{}", show(o)),
+      Object::Quasiquote(o) => write!(f, "`{}", show(o)),
+      Object::Unquote(o) => write!(f, ",{}", show(o)),
+      Object::UnquoteSplicing(o) => write!(f, ",@{}", show(o)),
       Object::Cond => write!(f, "cond"),
     }
   }
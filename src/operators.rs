@@ -1,6 +1,9 @@
+use crate::error::{ErrorKind, EvalError};
 use crate::object::Object;
 
-pub fn sum<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn sum<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut sum = params.next().unwrap()?;
 
   for param in params {
@@ -8,25 +11,42 @@ pub fn sum<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result
       Object::Integer(n) => match param? {
         Object::Integer(m) => Object::Integer(m + n),
         Object::Float(m) => Object::Float(m + n as f64),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
       Object::Float(n) => match param? {
         Object::Integer(m) => Object::Float(m as f64 + n),
         Object::Float(m) => Object::Float(m + n),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
       Object::String(s) => match param? {
-        Object::String(t) => Object::String(format!("{}{}", s, t)),
-        param => return Err(format!("Expected string, found {}", param)),
+        Object::String(t) => Object::String(format!("{}{}", s, t).into()),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected string, found {}",
+            param
+          )))
+        }
       },
-      _ => return Err(format!("{} could not be added", sum)),
+      _ => return Err(EvalError::type_error(format!("{} could not be added", sum))),
     }
   }
 
   Ok(sum)
 }
 
-pub fn sub<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn sub<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut diff: Object = params.next().unwrap()?;
 
   let next = params.next();
@@ -35,7 +55,12 @@ pub fn sub<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result
     diff = match diff {
       Object::Integer(n) => Object::Integer(-n),
       Object::Float(n) => Object::Float(-n),
-      _ => return Err(format!("Expected int or float, found {}", diff)),
+      _ => {
+        return Err(EvalError::type_error(format!(
+          "Expected int or float, found {}",
+          diff
+        )))
+      }
     };
 
     return Ok(diff);
@@ -48,21 +73,38 @@ pub fn sub<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result
       Object::Integer(n) => match param? {
         Object::Integer(m) => Object::Integer(n - m),
         Object::Float(m) => Object::Float(n as f64 - m),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
       Object::Float(n) => match param? {
         Object::Integer(m) => Object::Float(n - m as f64),
         Object::Float(m) => Object::Float(n - m),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
-      _ => return Err(format!("{} could not be subtracted", diff)),
+      _ => {
+        return Err(EvalError::type_error(format!(
+          "{} could not be subtracted",
+          diff
+        )))
+      }
     }
   }
 
   Ok(diff)
 }
 
-pub fn mult<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn mult<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut product: Object = params.next().unwrap()?;
 
   for param in params {
@@ -70,77 +112,134 @@ pub fn mult<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Resul
       Object::Integer(n) => match param? {
         Object::Integer(m) => Object::Integer(m * n),
         Object::Float(m) => Object::Float(m * n as f64),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
       Object::Float(n) => match param? {
         Object::Integer(m) => Object::Float(m as f64 * n),
         Object::Float(m) => Object::Float(m * n),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
-      _ => return Err(format!("{} could not be multiplied", product)),
+      _ => {
+        return Err(EvalError::type_error(format!(
+          "{} could not be multiplied",
+          product
+        )))
+      }
     }
   }
 
   Ok(product)
 }
 
-pub fn div<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn div<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut quotient: Object = params.next().unwrap()?;
 
   for param in params {
     let param = param?;
 
     if param == Object::Integer(0) || param == Object::Float(0.0) {
-      return Err(format!("Cannot divide by zero"));
+      return Err(EvalError::new(
+        ErrorKind::DivisionByZero,
+        "Cannot divide by zero",
+      ));
     }
 
     quotient = match quotient {
       Object::Integer(n) => match param {
         Object::Integer(m) => Object::Integer(n / m),
         Object::Float(m) => Object::Float(n as f64 / m),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
       Object::Float(n) => match param {
         Object::Integer(m) => Object::Float(n / m as f64),
         Object::Float(m) => Object::Float(n / m),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
-      _ => return Err(format!("{} could not be divided", quotient)),
+      _ => {
+        return Err(EvalError::type_error(format!(
+          "{} could not be divided",
+          quotient
+        )))
+      }
     }
   }
 
   Ok(quotient)
 }
 
-pub fn mod_<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn mod_<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut remainder: Object = params.next().unwrap()?;
 
   for param in params {
     let param = param?;
 
     if param == Object::Integer(0) || param == Object::Float(0.0) {
-      return Err(format!("Cannot get remainder of zero"));
+      return Err(EvalError::new(
+        ErrorKind::DivisionByZero,
+        "Cannot get remainder of zero",
+      ));
     }
 
     remainder = match remainder {
       Object::Integer(n) => match param {
         Object::Integer(m) => Object::Integer(n % m),
         Object::Float(m) => Object::Float(n as f64 % m),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
       Object::Float(n) => match param {
         Object::Integer(m) => Object::Float(n % m as f64),
         Object::Float(m) => Object::Float(n % m),
-        param => return Err(format!("Expected int or float, found {}", param)),
+        param => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            param
+          )))
+        }
       },
-      _ => return Err(format!("{} could not be divided", remainder)),
+      _ => {
+        return Err(EvalError::type_error(format!(
+          "{} could not be divided",
+          remainder
+        )))
+      }
     }
   }
 
   Ok(remainder)
 }
 
-pub fn lt<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn lt<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut result = true;
 
   let mut prev = params.next().unwrap()?;
@@ -158,7 +257,12 @@ pub fn lt<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
           prev = next;
           n < m as i64
         }
-        v => return Err(format!("Expected int or float, found {}", v)),
+        v => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            v
+          )))
+        }
       },
       Object::Float(n) => match next {
         Object::Integer(m) => {
@@ -169,9 +273,19 @@ pub fn lt<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
           prev = next;
           n < m
         }
-        v => return Err(format!("Expected int or float, found {}", v)),
+        v => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            v
+          )))
+        }
       },
-      _ => return Err(format!("{} could not be compared", prev)),
+      _ => {
+        return Err(EvalError::type_error(format!(
+          "{} could not be compared",
+          prev
+        )))
+      }
     };
 
     if !result {
@@ -182,7 +296,9 @@ pub fn lt<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
   Ok(Object::Bool(result))
 }
 
-pub fn gt<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn gt<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut result = true;
 
   let mut prev = params.next().unwrap()?;
@@ -202,7 +318,12 @@ pub fn gt<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
             m as i64
           }
         }
-        v => return Err(format!("Expected int or float, found {}", v)),
+        v => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            v
+          )))
+        }
       },
       Object::Float(n) => match next {
         Object::Integer(m) => {
@@ -213,9 +334,19 @@ pub fn gt<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
           prev = next;
           n > m
         }
-        v => return Err(format!("Expected int or float, found {}", v)),
+        v => {
+          return Err(EvalError::type_error(format!(
+            "Expected int or float, found {}",
+            v
+          )))
+        }
       },
-      _ => return Err(format!("{} could not be compared", prev)),
+      _ => {
+        return Err(EvalError::type_error(format!(
+          "{} could not be compared",
+          prev
+        )))
+      }
     };
 
     if !result {
@@ -226,7 +357,28 @@ pub fn gt<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
   Ok(Object::Bool(result))
 }
 
-pub fn eq<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+/// `obj` without the quote around it, so that `'a` and `'(1 2)` are
+/// compared as the symbol and the list they quote.
+fn unquoted(obj: &Object) -> &Object {
+  match obj {
+    Object::Quote(o) => o,
+    o => o,
+  }
+}
+
+/// Whether `obj` is compared by its structure, as map keys are.
+fn is_structural(obj: &Object) -> bool {
+  matches!(
+    unquoted(obj),
+    Object::Symbol(_) | Object::Keyword(_) | Object::List(..) | Object::Pair(_)
+  )
+}
+
+/// `=` compares numbers by value, `1` and `1.0` being equal as they are as
+/// map keys, and symbols, keywords and lists by their structure.
+pub fn eq<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut result = true;
 
   let mut prev = params.next().unwrap()?;
@@ -235,14 +387,11 @@ pub fn eq<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
     let next = param?;
 
     let updated_prev = match (&prev, &next) {
-      (Object::Integer(n), Object::Integer(m)) => n == m,
-      (Object::Integer(n), Object::Float(m)) => *n == *m as i64,
-      (Object::Integer(_), Object::Bool(_)) => true,
-      (Object::Integer(_), _) => false,
-      (Object::Float(n), Object::Integer(m)) => *n == *m as f64,
-      (Object::Float(n), Object::Float(m)) => n == m,
-      (Object::Float(_), Object::Bool(_)) => true,
-      (Object::Float(_), _) => false,
+      (Object::Integer(_) | Object::Float(_), Object::Integer(_) | Object::Float(_)) => {
+        prev == next
+      }
+      (Object::Integer(_) | Object::Float(_), Object::Bool(_)) => true,
+      (Object::Integer(_) | Object::Float(_), _) => false,
       (Object::Bool(n), Object::Bool(m)) => n == m,
       (Object::Bool(n), Object::Void) => !n,
       (Object::Bool(b), Object::String(_)) => *b,
@@ -253,7 +402,17 @@ pub fn eq<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
       (Object::Void, Object::Void) => true,
       (Object::Void, Object::Bool(b)) => !b,
       (Object::Void, _) => false,
-      (_, _) => return Err(format!("{} could not be compared", prev)),
+      (Object::Vector(n), Object::Vector(m)) => n == m,
+      (Object::Vector(_), _) => false,
+      (Object::Map(n), Object::Map(m)) => n == m,
+      (Object::Map(_), _) => false,
+      (prev, next) if is_structural(prev) => unquoted(prev) == unquoted(next),
+      (_, _) => {
+        return Err(EvalError::type_error(format!(
+          "{} could not be compared",
+          prev
+        )))
+      }
     };
 
     if !updated_prev {
@@ -267,9 +426,11 @@ pub fn eq<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<
   Ok(Object::Bool(result))
 }
 
-pub fn strict_eq<I: Iterator<Item = Result<Object, String>>>(
+/// `==` is `=` without conversions: numbers of different types and values
+/// of different types are never equal.
+pub fn strict_eq<I: Iterator<Item = Result<Object, EvalError>>>(
   params: &mut I,
-) -> Result<Object, String> {
+) -> Result<Object, EvalError> {
   let mut result = true;
 
   let mut prev = params.next().unwrap()?;
@@ -280,7 +441,7 @@ pub fn strict_eq<I: Iterator<Item = Result<Object, String>>>(
     let updated_prev = match (&prev, &next) {
       (Object::Integer(n), Object::Integer(m)) => n == m,
       (Object::Integer(_), _) => false,
-      (Object::Float(n), Object::Float(m)) => *n == *m,
+      (Object::Float(_), Object::Float(_)) => prev == next,
       (Object::Float(_), _) => false,
       (Object::Bool(n), Object::Bool(m)) => n == m,
       (Object::Bool(_), _) => false,
@@ -288,7 +449,17 @@ pub fn strict_eq<I: Iterator<Item = Result<Object, String>>>(
       (Object::String(_), _) => false,
       (Object::Void, Object::Void) => true,
       (Object::Void, _) => false,
-      _ => return Err(format!("{} could not be compared", prev)),
+      (Object::Vector(n), Object::Vector(m)) => n == m,
+      (Object::Vector(_), _) => false,
+      (Object::Map(n), Object::Map(m)) => n == m,
+      (Object::Map(_), _) => false,
+      (prev, next) if is_structural(prev) => unquoted(prev) == unquoted(next),
+      _ => {
+        return Err(EvalError::type_error(format!(
+          "{} could not be compared",
+          prev
+        )))
+      }
     };
 
     if !updated_prev {
@@ -302,7 +473,9 @@ pub fn strict_eq<I: Iterator<Item = Result<Object, String>>>(
   Ok(Object::Bool(result))
 }
 
-pub fn and<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn and<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut result = Object::Void;
 
   for param in params {
@@ -326,7 +499,9 @@ pub fn and<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result
   Ok(result)
 }
 
-pub fn or<I: Iterator<Item = Result<Object, String>>>(params: &mut I) -> Result<Object, String> {
+pub fn or<I: Iterator<Item = Result<Object, EvalError>>>(
+  params: &mut I,
+) -> Result<Object, EvalError> {
   let mut result = Object::Void;
 
   for param in params {
@@ -1,127 +1,273 @@
+use crate::error::{ErrorKind, EvalError};
 use crate::lexer::*;
-use crate::object::Object;
+use crate::object::{List, Map, Object};
+use crate::span::{locate, Span};
 
-use std::{fmt, vec, error::Error, rc::Rc};
+use std::{cell::RefCell, error::Error, fmt, iter::Peekable, rc::Rc, vec::IntoIter};
 
 #[derive(Debug)]
 pub struct ParseError {
   err: String,
+  span: Span,
 }
 
 impl fmt::Display for ParseError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "Parse error: {}", self.err)
+    let err = format!("Parse error: {}", self.err);
+    write!(f, "{}", locate(err, &self.span))
   }
 }
 
 impl Error for ParseError {}
 
+impl From<ParseError> for EvalError {
+  fn from(e: ParseError) -> Self {
+    EvalError::new(ErrorKind::ParseError, format!("Parse error: {}", e.err)).at(&e.span)
+  }
+}
+
+impl From<TokenError> for ParseError {
+  fn from(e: TokenError) -> Self {
+    ParseError {
+      err: e.err,
+      span: e.span,
+    }
+  }
+}
+
+/// The tokens of a program, and where its last one is, to report a program
+/// that ends too early.
+struct Tokens {
+  tokens: Peekable<IntoIter<Token>>,
+  end: Span,
+}
+
+impl Tokens {
+  fn next(&mut self) -> Option<Token> {
+    self.tokens.next()
+  }
+
+  fn peek(&mut self) -> Option<&Token> {
+    self.tokens.peek()
+  }
+}
+
 fn token_to_object(t: Token) -> Result<Object, ParseError> {
-  let object = match t {
-    Token::Integer(n) => Object::Integer(n),
-    Token::Float(f) => Object::Float(f),
-    Token::String(s) => Object::String(s),
-    Token::Symbol(word) => match word.as_str() {
-      "define" | "defun" | "lambda" | "let" | "do" => Object::Keyword(word),
+  let object = match t.kind {
+    TokenKind::Integer(n) => Object::Integer(n),
+    TokenKind::Float(f) => Object::Float(f),
+    TokenKind::String(s) => Object::String(s.into()),
+    TokenKind::Symbol(word) => match word.as_str() {
+      "define" | "defun" | "defmacro" | "define-syntax" | "lambda" | "let" | "let*" | "letrec"
+      | "do" | "begin" | "set!" | "try" | "let/ec" | "generator" => Object::Keyword(word),
       "+" | "-" | "*" | "/" | "<" | ">" | "=" | "==" | "%" | "or" | "and" => Object::Operator(word),
       "cond" => Object::Cond,
+      "#t" => Object::Bool(true),
+      "#f" => Object::Bool(false),
+      "#nil" => Object::Void,
       _ => Object::Symbol(word),
     },
-    _ => {
+    kind => {
+      return Err(ParseError {
+        err: format!("Unexpected token: {:?}", kind),
+        span: t.span,
+      })
+    }
+  };
+
+  Ok(object)
+}
+
+fn parse_form(tokens: &mut Tokens) -> Result<Object, ParseError> {
+  let token = match tokens.next() {
+    Some(token) => token,
+    None => {
       return Err(ParseError {
-        err: format!("Unexpected token: {:?}", t),
+        err: "Unexpected end of input".to_string(),
+        span: tokens.end.clone(),
       })
     }
   };
 
-  return Ok(object);
+  match token.kind {
+    TokenKind::LParen => parse_list(token.span, tokens),
+    TokenKind::LBracket => parse_vector(token.span, tokens),
+    TokenKind::LBrace => parse_map(token.span, tokens),
+    TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace => Err(ParseError {
+      err: format!("Unexpected `{}`", token),
+      span: token.span,
+    }),
+    TokenKind::Quote => parse_prefixed(token, tokens, Object::Quote),
+    TokenKind::Quasiquote => parse_prefixed(token, tokens, Object::Quasiquote),
+    TokenKind::Unquote => parse_prefixed(token, tokens, Object::Unquote),
+    TokenKind::UnquoteSplicing => parse_prefixed(token, tokens, Object::UnquoteSplicing),
+    _ => token_to_object(token),
+  }
+}
+
+/// Parses the form following a reader prefix such as `'` and wraps it.
+fn parse_prefixed(
+  prefix: Token,
+  tokens: &mut Tokens,
+  wrap: fn(Rc<Object>) -> Object,
+) -> Result<Object, ParseError> {
+  if tokens.peek().is_none() {
+    return Err(ParseError {
+      err: format!("Expected a form after `{}`", prefix),
+      span: prefix.span,
+    });
+  }
+
+  let form = parse_form(tokens)?;
+  Ok(wrap(Rc::new(form)))
 }
 
-fn parse_list(tokens: &mut Vec<Token>) -> Result<Object, ParseError> {
-  let mut stack: Vec<Object> = Vec::new();
+/// Reads a `[...]` vector literal, whose items are evaluated like the
+/// arguments of a call to `vector`.
+fn parse_vector(open: Span, tokens: &mut Tokens) -> Result<Object, ParseError> {
+  let mut items = Vec::new();
 
-  for token in tokens.iter() {
-    match token {
-      Token::RParen => {
-        stack.push(Object::List(vec![]));
+  loop {
+    match tokens.peek() {
+      Some(token) if token.kind == TokenKind::RBracket => {
+        tokens.next();
+        return Ok(Object::Vector(Rc::new(RefCell::new(items))));
       }
-      Token::LParen => {
-        let sublist = match stack.pop() {
-          Some(o) => o,
-          None => {
-            return Err(ParseError {
-              err: format!("Unexpected token: {:?}", token),
-            })
-          }
-        };
-
-        let to = match stack.last_mut() {
-          Some(Object::List(l)) => l,
-          _ => &mut stack,
-        };
-
-        match sublist {
-          Object::List(l) => {
-            let mut l = l.clone();
-            l.reverse();
-
-            to.push(Object::List(l.to_vec()));
-          }
-          o => {
-            to.push(o);
-          }
-        }
+      Some(_) => items.push(parse_form(tokens)?),
+      None => {
+        return Err(ParseError {
+          err: format!("Unclosed vector opened at line {}", open.line),
+          span: open,
+        })
       }
-      Token::Quote => {
+    }
+  }
+}
+
+/// Reads a `{key value ...}` map literal. Its keys and values are evaluated
+/// like the arguments of a call to `assoc` on an empty map.
+fn parse_map(open: Span, tokens: &mut Tokens) -> Result<Object, ParseError> {
+  let mut items = Vec::new();
+
+  loop {
+    match tokens.peek() {
+      Some(token) if token.kind == TokenKind::RBrace => {
+        tokens.next();
+        break;
       }
-      token => {
-        let object = token_to_object(token.clone())?;
-
-        let to = match stack.len() {
-          0 => &mut stack,
-          _ => {
-            let last = stack.last_mut().unwrap();
-            match last {
-              Object::List(l) => l,
-              _ => &mut stack,
-            }
-          }
-        };
-
-        to.push(object);
+      Some(_) => items.push(parse_form(tokens)?),
+      None => {
+        return Err(ParseError {
+          err: format!("Unclosed map opened at line {}", open.line),
+          span: open,
+        })
       }
     }
   }
 
-  match stack.len() {
-    0 => Ok(Object::Void),
-    1 => Ok(stack.pop().unwrap()),
-    _ => Ok(Object::List(stack)),
+  if items.len() % 2 != 0 {
+    return Err(ParseError {
+      err: "A map literal needs a value for every key".to_string(),
+      span: open,
+    });
+  }
+
+  let mut items = items.into_iter();
+  let mut map = Map::default();
+  while let (Some(key), Some(value)) = (items.next(), items.next()) {
+    if map.contains_key(&key) {
+      return Err(ParseError {
+        err: format!("Duplicate key {} in a map literal", key),
+        span: open,
+      });
+    }
+    map.insert(key, value);
+  }
+
+  Ok(Object::Map(Rc::new(map)))
+}
+
+/// Reads `(a b . c)` as pairs, and a list without a `.` as is. A `.` must
+/// follow at least one item and come before exactly one.
+fn dotted(mut items: Vec<Object>, spans: Vec<Span>, open: Span) -> Result<Object, ParseError> {
+  let is_dot = |o: &Object| matches!(o, Object::Symbol(s) if s == ".");
+
+  match items.iter().position(is_dot) {
+    None => Ok(Object::List(List::with_spans(items, spans), open)),
+    Some(dot) if dot > 0 && dot + 2 == items.len() => {
+      let tail = items.pop().unwrap_or_default();
+      items.pop();
+      Ok(Object::dotted(items, tail))
+    }
+    Some(dot) => Err(ParseError {
+      err: "Misplaced `.`: expected one form after it and at least one before".to_string(),
+      span: spans[dot].clone(),
+    }),
   }
 }
 
-pub fn parse(program: &str) -> Result<Object, ParseError> {
-  let token_result = tokenize(program);
+fn parse_list(open: Span, tokens: &mut Tokens) -> Result<Object, ParseError> {
+  let mut list = Vec::new();
+  let mut spans = Vec::new();
 
-  let mut tokens = token_result
-    .unwrap()
-    .into_iter()
-    .rev()
-    .collect::<Vec<Token>>();
-  let parsed_list = parse_list(&mut tokens)?;
-  Ok(parsed_list)
+  loop {
+    match tokens.peek() {
+      Some(token) if token.kind == TokenKind::RParen => {
+        tokens.next();
+        return dotted(list, spans, open);
+      }
+      Some(token) => {
+        spans.push(token.span.clone());
+        list.push(parse_form(tokens)?);
+      }
+      None => {
+        return Err(ParseError {
+          err: format!("Unclosed list opened at line {}", open.line),
+          span: open,
+        })
+      }
+    }
+  }
+}
+
+/// Parses every top-level form of `program`, attributing each one to `file`
+/// in its span.
+pub fn parse_program(program: &str, file: &str) -> Result<List, ParseError> {
+  let tokens = tokenize(program, file)?;
+  let end = match tokens.last() {
+    Some(token) => token.span.clone(),
+    None => Span::new(file.into(), 1, 1),
+  };
+
+  let mut tokens = Tokens {
+    tokens: tokens.into_iter().peekable(),
+    end,
+  };
+  let mut forms = Vec::new();
+  let mut spans = Vec::new();
+
+  while let Some(token) = tokens.peek() {
+    spans.push(token.span.clone());
+    forms.push(parse_form(&mut tokens)?);
+  }
+
+  Ok(List::with_spans(forms, spans))
 }
 
 #[cfg(test)]
 mod lexer_tests {
   use super::*;
 
+  fn parse(program: &str) -> Result<Object, ParseError> {
+    parse_program(program, "<input>").map(|forms| forms[0].clone())
+  }
+
   #[test]
   fn test_add() {
     let list = parse("(+ 1 2)").unwrap();
     assert_eq!(
       list,
-      Object::List(vec![
+      Object::list(vec![
         Object::Operator("+".to_string()),
         Object::Integer(1),
         Object::Integer(2),
@@ -134,10 +280,10 @@ mod lexer_tests {
     let list = parse("(+ 1 (+ 2 3))").unwrap();
     assert_eq!(
       list,
-      Object::List(vec![
+      Object::list(vec![
         Object::Operator("+".to_string()),
         Object::Integer(1),
-        Object::List(vec![
+        Object::list(vec![
           Object::Operator("+".to_string()),
           Object::Integer(2),
           Object::Integer(3),
@@ -152,19 +298,19 @@ mod lexer_tests {
 
     assert_eq!(
       list,
-      Object::List(vec![
+      Object::list(vec![
         Object::Symbol("add".to_string()),
         Object::Integer(1),
-        Object::List(vec![
+        Object::list(vec![
           Object::Operator("/".to_string()),
           Object::Integer(3),
-          Object::List(vec![
+          Object::list(vec![
             Object::Operator("*".to_string()),
             Object::Integer(10),
             Object::Integer(2),
           ]),
         ]),
-        Object::List(vec![
+        Object::list(vec![
           Object::Operator("+".to_string()),
           Object::Integer(2),
           Object::Integer(3),
@@ -175,8 +321,67 @@ mod lexer_tests {
 
   #[test]
   fn test_symbol() {
-    let list = parse("#t").unwrap();
-    assert_eq!(list, Object::Symbol("#t".to_string()))
+    let list = parse("x").unwrap();
+    assert_eq!(list, Object::Symbol("x".to_string()))
+  }
+
+  #[test]
+  fn test_constants() {
+    assert_eq!(parse("#t").unwrap(), Object::Bool(true));
+    assert_eq!(parse("#f").unwrap(), Object::Bool(false));
+    assert_eq!(parse("#nil").unwrap(), Object::Void);
+  }
+
+  #[test]
+  fn test_dotted_list() {
+    assert_eq!(
+      parse("(a . b)").unwrap(),
+      Object::dotted(
+        vec![Object::Symbol("a".to_string())],
+        Object::Symbol("b".to_string())
+      )
+    );
+    assert_eq!(parse("(1 2 . 3)").unwrap().to_string(), "(1 2 . 3)");
+    assert_eq!(parse("(1 . (2 3))").unwrap().to_string(), "(1 2 3)");
+
+    for (misplaced, column) in [
+      ("(. a)", 2),
+      ("(a .)", 4),
+      ("(a . b c)", 4),
+      ("'(1 . 2 3)", 5),
+    ] {
+      let err = parse_program(misplaced, "f.tl").unwrap_err();
+      assert_eq!(
+        err.to_string(),
+        format!(
+          "Parse error: Misplaced `.`: expected one form after it and at least one before\n  --> f.tl:1:{}",
+          column
+        )
+      );
+    }
+  }
+
+  #[test]
+  fn test_vector() {
+    let vector = parse("[1 (+ 1 1) []]").unwrap();
+    assert_eq!(vector.to_string(), "[1 (+ 1 1) []]");
+    assert!(matches!(vector, Object::Vector(_)));
+
+    assert!(parse("[1 2").is_err());
+    assert!(parse("(1 2]").is_err());
+  }
+
+  #[test]
+  fn test_map() {
+    let map = parse("{:a 1 \"b\" (+ 1 1) [1] {}}").unwrap();
+    assert_eq!(map.to_string(), "{:a 1 b (+ 1 1) [1] {}}");
+    assert!(matches!(map, Object::Map(_)));
+
+    let err = parse("{:a}").unwrap_err();
+    assert!(err.to_string().contains("needs a value for every key"));
+    let err = parse("{:a 1 :a 2}").unwrap_err();
+    assert!(err.to_string().contains("Duplicate key :a"));
+    assert!(parse("{:a 1").is_err());
   }
 
   #[test]
@@ -184,7 +389,7 @@ mod lexer_tests {
     let list = parse("'(1 2 3)").unwrap();
     assert_eq!(
       list,
-      Object::Quote(Rc::new(Object::List(vec![
+      Object::Quote(Rc::new(Object::list(vec![
         Object::Integer(1),
         Object::Integer(2),
         Object::Integer(3),
@@ -197,7 +402,7 @@ mod lexer_tests {
     let list = parse("('a b)").unwrap();
     assert_eq!(
       list,
-      Object::List(vec![
+      Object::list(vec![
         Object::Quote(Rc::new(Object::Symbol("a".to_string()))),
         Object::Symbol("b".to_string())
       ])
@@ -210,8 +415,8 @@ mod lexer_tests {
 
     assert_eq!(
       list,
-      Object::Quote(Rc::new(Object::List(vec![Object::Quote(Rc::new(
-        Object::List(vec![
+      Object::Quote(Rc::new(Object::list(vec![Object::Quote(Rc::new(
+        Object::list(vec![
           Object::Quote(Rc::new(Object::Symbol("a".to_string()))),
           Object::Symbol("b".to_string())
         ])
@@ -230,4 +435,120 @@ mod lexer_tests {
       )))))
     )
   }
+
+  #[test]
+  fn test_list_spans() {
+    let list = parse_program("(a\n  (b c))", "f.tl").unwrap()[0].clone();
+
+    match list {
+      Object::List(items, span) => {
+        assert_eq!(span.to_string(), "f.tl:1:1");
+
+        match &items[1] {
+          Object::List(_, span) => assert_eq!(span.to_string(), "f.tl:2:3"),
+          o => panic!("Expected a list, found {:?}", o),
+        }
+      }
+      o => panic!("Expected a list, found {:?}", o),
+    }
+  }
+
+  #[test]
+  fn test_item_spans() {
+    let forms = parse_program("x\n(f 12 [a]\n   {:k v})", "f.tl").unwrap();
+    assert_eq!(forms.span_of(0).unwrap().to_string(), "f.tl:1:1");
+    assert_eq!(forms.span_of(1).unwrap().to_string(), "f.tl:2:1");
+
+    match &forms[1] {
+      Object::List(items, _) => {
+        let spans = (0..items.len())
+          .map(|i| items.span_of(i).unwrap().to_string())
+          .collect::<Vec<String>>();
+        assert_eq!(spans, ["f.tl:2:2", "f.tl:2:4", "f.tl:2:7", "f.tl:3:4"]);
+        assert_eq!(items.tail(2).span_of(0).unwrap().to_string(), "f.tl:2:7");
+      }
+      o => panic!("Expected a list, found {:?}", o),
+    }
+  }
+
+  #[test]
+  fn test_unclosed_list() {
+    let err = parse_program("(do\n  (+ 1 2)", "f.tl").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Parse error: Unclosed list opened at line 1\n  --> f.tl:1:1"
+    );
+  }
+
+  #[test]
+  fn test_unexpected_rparen() {
+    let err = parse_program("(+ 1 2))", "f.tl").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Parse error: Unexpected `)`\n  --> f.tl:1:8"
+    );
+
+    let err = parse(")(").unwrap_err();
+    assert!(err.to_string().starts_with("Parse error: Unexpected `)`"));
+  }
+
+  #[test]
+  fn test_malformed_input_does_not_panic() {
+    assert!(parse("'").is_err());
+    assert!(parse("(a '").is_err());
+    assert!(parse("(print! \"oops)").is_err());
+  }
+
+  #[test]
+  fn test_program() {
+    let forms = parse_program("(define x 1)\n'x\n#nil", "<input>").unwrap();
+    assert_eq!(
+      forms.to_vec(),
+      vec![
+        Object::list(vec![
+          Object::Keyword("define".to_string()),
+          Object::Symbol("x".to_string()),
+          Object::Integer(1),
+        ]),
+        Object::Quote(Rc::new(Object::Symbol("x".to_string()))),
+        Object::Void,
+      ]
+    );
+
+    assert_eq!(
+      parse_program(" ; nothing here\n", "<input>")
+        .unwrap()
+        .to_vec(),
+      vec![]
+    );
+  }
+
+  #[test]
+  fn test_readable_strings_round_trip() {
+    let list = parse(r#"("tab\there" "quote \" and \\" "bell \u{7}")"#).unwrap();
+    let written = format!("{:#}", list);
+
+    assert_eq!(written, r#"("tab\there" "quote \" and \\" "bell \u{7}")"#);
+    assert_eq!(parse(&written).unwrap(), list);
+    assert_eq!(format!("{}", parse(r#""a\nb""#).unwrap()), "a\nb");
+  }
+
+  #[test]
+  fn test_quasiquotation() {
+    let list = parse("`(a ,b ,@(f c))").unwrap();
+
+    assert_eq!(
+      list,
+      Object::Quasiquote(Rc::new(Object::list(vec![
+        Object::Symbol("a".to_string()),
+        Object::Unquote(Rc::new(Object::Symbol("b".to_string()))),
+        Object::UnquoteSplicing(Rc::new(Object::list(vec![
+          Object::Symbol("f".to_string()),
+          Object::Symbol("c".to_string()),
+        ]))),
+      ])))
+    );
+    assert_eq!(list.to_string(), "`(a ,b ,@(f c))");
+    assert!(parse("(a ,@)").is_err());
+  }
 }
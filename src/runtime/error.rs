@@ -0,0 +1,68 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+  environment::Environment,
+  error::EvalError,
+  object::{Arity, Object},
+};
+
+use super::NativeFn;
+
+/// `(error message data)` raises a user error, and `(error e)` raises the
+/// caught error `e` again.
+fn error(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let data = args.get(1).cloned().unwrap_or_default();
+
+  match &args[0] {
+    Object::String(message) => Err(EvalError::user(message.to_string(), data)),
+    Object::Error(err) if args.len() == 1 => Err((**err).clone()),
+    o => Err(EvalError::type_error(format!(
+      "Expected a message for error, got {}",
+      o
+    ))),
+  }
+}
+
+fn caught(args: &[Object]) -> Result<&EvalError, EvalError> {
+  match &args[0] {
+    Object::Error(err) => Ok(err),
+    o => Err(EvalError::type_error(format!(
+      "Expected an error, got {}",
+      o
+    ))),
+  }
+}
+
+fn error_kind(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  Ok(Object::Symbol(caught(args)?.kind.keyword().to_string()))
+}
+
+fn error_message(
+  args: &[Object],
+  _env: &mut Rc<RefCell<Environment>>,
+) -> Result<Object, EvalError> {
+  Ok(Object::String(caught(args)?.message.as_str().into()))
+}
+
+fn error_data(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  Ok((*caught(args)?.data).clone())
+}
+
+pub fn load_error_fns(methods: &mut HashMap<String, NativeFn>) {
+  methods.insert(
+    "error".to_string(),
+    NativeFn::new(Arity::between(1, 2), error),
+  );
+  methods.insert(
+    "error-kind".to_string(),
+    NativeFn::new(Arity::exactly(1), error_kind),
+  );
+  methods.insert(
+    "error-message".to_string(),
+    NativeFn::new(Arity::exactly(1), error_message),
+  );
+  methods.insert(
+    "error-data".to_string(),
+    NativeFn::new(Arity::exactly(1), error_data),
+  );
+}
@@ -1,83 +1,88 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc, vec};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use crate::{environment::Environment, object::Object};
+use crate::{
+  environment::Environment,
+  error::EvalError,
+  object::{Arity, Object, Pair},
+};
 
-use super::RuntimeFn;
+use super::NativeFn;
 
-pub fn unquote(args: &Vec<Object>) -> Object {
-  match args.get(0) {
-    Some(Object::Quote(o)) => {
-      let o = o.clone();
-
-      (*o).clone()
-    }
+pub fn unquote(args: &[Object]) -> Object {
+  match args.first() {
+    Some(Object::Quote(o)) => (**o).clone(),
     Some(o) => o.clone(),
     None => Object::Void,
   }
 }
 
-fn cdr(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let list = unquote(args);
-
-  match list {
-    Object::List(list) => {
-      let cdr = list.get(1..);
-
-      match cdr {
-        Some(cdr) => Ok(Object::List(cdr.to_vec())),
-        None => Ok(Object::Void),
-      }
-    }
-    _ => Ok(Object::Void),
+fn cdr(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  match unquote(args).split_first() {
+    Some((_, cdr)) => Ok(cdr),
+    None => Ok(Object::Void),
   }
 }
 
-fn car(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let list = unquote(args);
-
-  match list {
-    Object::List(list) => {
-      let car = list.get(0);
-
-      match car {
-        Some(car) => Ok(car.clone()),
-        None => Ok(Object::Void),
-      }
-    }
-    _ => Ok(Object::Void),
+fn car(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  match unquote(args).split_first() {
+    Some((car, _)) => Ok(car),
+    None => Ok(Object::Void),
   }
 }
 
-fn cons(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let car = args.get(0);
-  let cdr = args.get(1);
-
-  let list = match cdr.clone().unwrap_or(&Object::Void) {
-    Object::Quote(o) => {
-      let o = o.clone();
-
-      (*o).clone()
-    }
+/// `(cons a b)` makes a pair in constant time, sharing `b`. It starts a
+/// list when `b` is a list or `#nil`, and is a dotted pair otherwise.
+fn cons(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let cdr = match &args[1] {
+    Object::Quote(o) => (**o).clone(),
+    Object::Void => Object::list(vec![]),
     o => o.clone(),
   };
 
-  match car {
-    Some(car) => match list {
-      Object::List(list) => {
-        let mut list = list.clone();
-
-        list.insert(0, car.clone());
+  Ok(Object::Pair(Rc::new(Pair::new(args[0].clone(), cdr))))
+}
 
-        Ok(Object::List(list))
-      }
-      _ => Ok(Object::List(vec![car.clone()])),
+/// The pair `set-car!` or `set-cdr!` changes. Lists that were read or built
+/// otherwise than with `cons` share their items and cannot be changed.
+fn pair<'a>(args: &'a [Object], name: &str) -> Result<&'a Pair, EvalError> {
+  match &args[0] {
+    Object::Pair(pair) => Ok(pair),
+    Object::Quote(o) => match &**o {
+      Object::Pair(pair) => Ok(pair),
+      o => Err(EvalError::type_error(format!(
+        "Expected a pair for {}, got {}",
+        name, o
+      ))),
     },
-    None => Ok(Object::List(vec![])),
+    o => Err(EvalError::type_error(format!(
+      "Expected a pair for {}, got {}",
+      name, o
+    ))),
   }
 }
 
-pub fn load_list_fns(methods: &mut HashMap<String, Rc<RuntimeFn>>) {
-  methods.insert("cdr".to_string(), Rc::new(cdr));
-  methods.insert("car".to_string(), Rc::new(car));
-  methods.insert("cons".to_string(), Rc::new(cons));
+fn set_car(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  *pair(args, "set-car!")?.car.borrow_mut() = args[1].clone();
+
+  Ok(Object::Void)
+}
+
+fn set_cdr(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  *pair(args, "set-cdr!")?.cdr.borrow_mut() = args[1].clone();
+
+  Ok(Object::Void)
+}
+
+pub fn load_list_fns(methods: &mut HashMap<String, NativeFn>) {
+  methods.insert("cdr".to_string(), NativeFn::new(Arity::exactly(1), cdr));
+  methods.insert("car".to_string(), NativeFn::new(Arity::exactly(1), car));
+  methods.insert("cons".to_string(), NativeFn::new(Arity::exactly(2), cons));
+  methods.insert(
+    "set-car!".to_string(),
+    NativeFn::new(Arity::exactly(2), set_car),
+  );
+  methods.insert(
+    "set-cdr!".to_string(),
+    NativeFn::new(Arity::exactly(2), set_cdr),
+  );
 }
@@ -0,0 +1,140 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+  environment::Environment,
+  error::EvalError,
+  object::{Arity, Map, Object},
+};
+
+use super::NativeFn;
+
+/// `obj` as a map key: a quoted value is the same key as the value.
+fn key(obj: &Object) -> Object {
+  match obj {
+    Object::Quote(o) => (**o).clone(),
+    o => o.clone(),
+  }
+}
+
+/// The map argument at `n` of `name`.
+fn map<'a>(args: &'a [Object], n: usize, name: &str) -> Result<&'a Rc<Map>, EvalError> {
+  match &args[n] {
+    Object::Map(map) => Ok(map),
+    o => Err(EvalError::type_error(format!(
+      "Expected a map for {}, got {}",
+      name, o
+    ))),
+  }
+}
+
+/// `(get m key default)` is the value of `key` in `m`, or `default`, `#nil`
+/// if it is left out, when `m` has no such key.
+fn get(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let map = map(args, 0, "get")?;
+
+  match map.get(&key(&args[1])) {
+    Some(value) => Ok(value.clone()),
+    None => Ok(args.get(2).cloned().unwrap_or_default()),
+  }
+}
+
+/// `(assoc m key value ...)` is a copy of `m` with the values of the keys
+/// set.
+fn assoc(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut map = (**map(args, 0, "assoc")?).clone();
+
+  if args.len() % 2 != 1 {
+    return Err(EvalError::arity("Expected a value for every key in assoc"));
+  }
+
+  for entry in args[1..].chunks(2) {
+    map.insert(key(&entry[0]), entry[1].clone());
+  }
+
+  Ok(Object::Map(Rc::new(map)))
+}
+
+/// `(dissoc m key ...)` is a copy of `m` without the keys.
+fn dissoc(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut map = (**map(args, 0, "dissoc")?).clone();
+
+  for k in &args[1..] {
+    map.remove(&key(k));
+  }
+
+  Ok(Object::Map(Rc::new(map)))
+}
+
+/// `(keys m)` is the list of the keys of `m`, copied so that changing one
+/// in place does not change `m`.
+fn keys(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let map = map(args, 0, "keys")?;
+
+  Ok(Object::list(
+    map.iter().map(|(k, _)| k.detached()).collect(),
+  ))
+}
+
+fn vals(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let map = map(args, 0, "vals")?;
+
+  Ok(Object::list(map.iter().map(|(_, v)| v.clone()).collect()))
+}
+
+fn contains(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let map = map(args, 0, "contains?")?;
+
+  Ok(Object::Bool(map.contains_key(&key(&args[1]))))
+}
+
+/// `(merge m ...)` is a map of the entries of all the maps; a key in more
+/// than one of them gets the value from the last.
+fn merge(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut merged = (**map(args, 0, "merge")?).clone();
+
+  for n in 1..args.len() {
+    for (k, v) in map(args, n, "merge")?.iter() {
+      merged.insert(k.clone(), v.clone());
+    }
+  }
+
+  Ok(Object::Map(Rc::new(merged)))
+}
+
+/// `(map-entries m)` is the list of the `(key value)` entries of `m`.
+fn map_entries(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let map = map(args, 0, "map-entries")?;
+
+  Ok(Object::list(
+    map
+      .iter()
+      .map(|(k, v)| Object::list(vec![k.detached(), v.clone()]))
+      .collect(),
+  ))
+}
+
+pub fn load_map_fns(methods: &mut HashMap<String, NativeFn>) {
+  methods.insert("get".to_string(), NativeFn::new(Arity::between(2, 3), get));
+  methods.insert(
+    "assoc".to_string(),
+    NativeFn::new(Arity::at_least(1), assoc),
+  );
+  methods.insert(
+    "dissoc".to_string(),
+    NativeFn::new(Arity::at_least(1), dissoc),
+  );
+  methods.insert("keys".to_string(), NativeFn::new(Arity::exactly(1), keys));
+  methods.insert("vals".to_string(), NativeFn::new(Arity::exactly(1), vals));
+  methods.insert(
+    "contains?".to_string(),
+    NativeFn::new(Arity::exactly(2), contains),
+  );
+  methods.insert(
+    "merge".to_string(),
+    NativeFn::new(Arity::at_least(1), merge),
+  );
+  methods.insert(
+    "map-entries".to_string(),
+    NativeFn::new(Arity::exactly(1), map_entries),
+  );
+}
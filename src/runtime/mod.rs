@@ -1,23 +1,79 @@
+mod error;
 mod list;
+mod map;
 mod string;
+mod vector;
 
 use std::{cell::RefCell, collections::HashMap, fmt::Debug, rc::Rc};
 
-use crate::{environment::Environment, eval::eval_object, object::Object};
+use crate::{
+  environment::Environment,
+  error::EvalError,
+  eval::{self, eval_object},
+  object::{Arity, Object},
+};
 
-pub type RuntimeFn = dyn Fn(&Vec<Object>, &mut Rc<RefCell<Environment>>) -> Result<Object, String>;
+pub type RuntimeFn = dyn Fn(&[Object], &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError>;
+
+/// A native function and the number of arguments it accepts, checked
+/// before it is called.
+#[derive(Clone)]
+pub struct NativeFn {
+  pub arity: Arity,
+  pub call: Rc<RuntimeFn>,
+}
+
+impl NativeFn {
+  pub fn new(
+    arity: Arity,
+    call: impl Fn(&[Object], &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> + 'static,
+  ) -> Self {
+    NativeFn {
+      arity,
+      call: Rc::new(call),
+    }
+  }
+}
+
+/// How deeply evaluations may nest before a call fails, unless set with
+/// `Runtime::with_max_depth`. Low enough to fit the stack of a main thread
+/// in an unoptimized build.
+pub const DEFAULT_MAX_DEPTH: usize = 1_000;
+
+/// How programs are evaluated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backend {
+  /// Walk the parsed forms directly.
+  #[default]
+  TreeWalker,
+  /// Compile each top-level form to bytecode and run it on a stack machine.
+  ///
+  /// Programs give the same results as with the tree-walker, with two
+  /// differences in how they get there:
+  /// - A macro call is expanded once, when it is compiled or first runs,
+  ///   rather than every time it runs; the expansion is compiled and kept
+  ///   while the call's head stays the same macro.
+  /// - `yield`, forms such as `try`, `let/ec` and `generator`, the lambdas
+  ///   they make and what those call run in a nested tree-walker. Once a
+  ///   program refers to `call/cc`, the first top-level form with any of
+  ///   these has it and the rest of the program run on the tree-walker, so
+  ///   that its continuations go on to the end of the program.
+  Bytecode,
+}
 
 #[derive(Clone)]
 pub struct Runtime {
-  methods: Rc<HashMap<String, Rc<RuntimeFn>>>,
+  methods: Rc<HashMap<String, NativeFn>>,
+  max_depth: usize,
+  backend: Backend,
 }
 
 impl Debug for Runtime {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     let methods_str = self
       .methods
-      .iter()
-      .map(|(k, _)| k.clone())
+      .keys()
+      .cloned()
       .collect::<Vec<String>>()
       .join(", ");
 
@@ -25,13 +81,13 @@ impl Debug for Runtime {
   }
 }
 
-fn debug(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
+fn debug(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
   println!("{:?}", args);
 
   Ok(Object::Void)
 }
 
-fn print(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
+fn print(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
   let mut result = String::new();
 
   for arg in args {
@@ -43,56 +99,137 @@ fn print(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Obje
   Ok(Object::Void)
 }
 
-fn eval_eval(args: &Vec<Object>, env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let param = args.get(0);
+fn eval_eval(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  eval_object(&eval::eval_form(&args[0]), env)
+}
+
+fn apply(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  eval::apply(args, env)
+}
+
+fn call_cc(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  eval::call_cc(args, env)
+}
+
+fn next(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  eval::next(args, env)
+}
+
+/// `yield` only reaches here when no generator is running; inside one the
+/// evaluator handles it.
+fn yield_(_args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  Err(EvalError::syntax("yield used outside of a generator"))
+}
 
-  if param.is_none() {
-    return Ok(Object::Void);
+fn doc(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  match args.first() {
+    Some(Object::Lambda(lambda) | Object::Macro(lambda)) => match &lambda.doc {
+      Some(doc) => Ok(Object::String(doc.clone())),
+      None => Ok(Object::Void),
+    },
+    _ => Ok(Object::Void),
   }
+}
 
-  let param = param.unwrap();
-
-  let unquoted = match param {
-    Object::Quote(o) => &o,
-    o => o,
-  };
-
-  let unquoted = match unquoted {
-    Object::List(list) => {
-      let mut unquoted_params = Vec::new();
-      for obj in list {
-        let result = match obj {
-          Object::Quote(o) => &o,
-          o => o,
-        };
-
-        unquoted_params.push(result.clone());
-      }
-      Object::List(unquoted_params)
-    }
-    o => o.clone(),
-  };
+fn macroexpand_1(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let form = list::unquote(args);
 
-  eval_object(&unquoted, env)
+  match eval::macroexpand_1(&form, env)? {
+    Some(expanded) => Ok(Object::Quote(Rc::new(expanded))),
+    None => Ok(args.first().cloned().unwrap_or_default()),
+  }
 }
 
-impl Runtime {
-  pub fn new() -> Runtime {
-    let mut methods: HashMap<String, Rc<RuntimeFn>> = HashMap::new();
+fn macroexpand(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut form = list::unquote(args);
+  let mut expanded = false;
+
+  while let Some(next) = eval::macroexpand_1(&form, env)? {
+    form = next;
+    expanded = true;
+  }
 
-    methods.insert("debug!".to_string(), Rc::new(debug));
-    methods.insert("print!".to_string(), Rc::new(print));
-    methods.insert("eval".to_string(), Rc::new(eval_eval));
+  match expanded {
+    true => Ok(Object::Quote(Rc::new(form))),
+    false => Ok(args.first().cloned().unwrap_or_default()),
+  }
+}
+
+impl Default for Runtime {
+  fn default() -> Self {
+    Runtime::new()
+  }
+}
 
+impl Runtime {
+  pub fn new() -> Runtime {
+    let mut methods: HashMap<String, NativeFn> = HashMap::new();
+
+    let any = Arity::at_least(0);
+    let one = Arity::exactly(1);
+
+    methods.insert("debug!".to_string(), NativeFn::new(any, debug));
+    methods.insert("print!".to_string(), NativeFn::new(any, print));
+    methods.insert("eval".to_string(), NativeFn::new(one, eval_eval));
+    methods.insert(
+      "apply".to_string(),
+      NativeFn::new(Arity::at_least(2), apply),
+    );
+    methods.insert("call/cc".to_string(), NativeFn::new(one, call_cc));
+    methods.insert(
+      "call-with-current-continuation".to_string(),
+      NativeFn::new(one, call_cc),
+    );
+    methods.insert(
+      "next".to_string(),
+      NativeFn::new(Arity::between(1, 2), next),
+    );
+    methods.insert(
+      "yield".to_string(),
+      NativeFn::new(Arity::between(0, 1), yield_),
+    );
+    methods.insert("doc".to_string(), NativeFn::new(one, doc));
+    methods.insert(
+      "macroexpand-1".to_string(),
+      NativeFn::new(one, macroexpand_1),
+    );
+    methods.insert("macroexpand".to_string(), NativeFn::new(one, macroexpand));
+
+    error::load_error_fns(&mut methods);
     list::load_list_fns(&mut methods);
+    map::load_map_fns(&mut methods);
     string::load_string_fns(&mut methods);
+    vector::load_vector_fns(&mut methods);
 
     Runtime {
       methods: Rc::new(methods),
+      max_depth: DEFAULT_MAX_DEPTH,
+      backend: Backend::default(),
     }
   }
 
-  pub fn get_method(&self, name: &str) -> Option<&Rc<RuntimeFn>> {
+  /// Sets how deeply evaluations may nest, e.g. through recursive calls
+  /// that are not in tail position, before a call fails with an error.
+  pub fn with_max_depth(mut self, max_depth: usize) -> Runtime {
+    self.max_depth = max_depth;
+    self
+  }
+
+  pub fn max_depth(&self) -> usize {
+    self.max_depth
+  }
+
+  /// Sets how programs are evaluated.
+  pub fn with_backend(mut self, backend: Backend) -> Runtime {
+    self.backend = backend;
+    self
+  }
+
+  pub fn backend(&self) -> Backend {
+    self.backend
+  }
+
+  pub fn get_method(&self, name: &str) -> Option<&NativeFn> {
     self.methods.get(name)
   }
 }
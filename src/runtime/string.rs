@@ -1,24 +1,27 @@
-use crate::{environment::Environment, object::Object};
-use dyn_fmt;
+use crate::{
+  environment::Environment,
+  error::EvalError,
+  object::{Arity, Object},
+};
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use super::{list::unquote, RuntimeFn};
+use super::{list::unquote, NativeFn};
 
-fn format_(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let arg = args.get(0).unwrap();
+fn format_(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let arg = args.first().unwrap();
 
   let rest = args.get(1..).unwrap();
 
   match arg {
     Object::String(str) => Ok(Object::String(
-      dyn_fmt::AsStrFormatExt::format(str, rest).to_string(),
+      dyn_fmt::AsStrFormatExt::format(&&**str, rest).into(),
     )),
     _ => Ok(Object::Void),
   }
 }
 
-fn split(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let str = args.get(0);
+fn split(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let str = args.first();
 
   let str = match str {
     Some(Object::String(s)) => s,
@@ -28,53 +31,65 @@ fn split(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Obje
   let separator = args.get(1);
 
   let separator = match separator {
-    Some(Object::String(s)) => s.clone(),
-    _ => "".to_string(),
+    Some(Object::String(s)) => s,
+    _ => "",
   };
 
-  let result = match separator.as_str() {
+  let result = match separator {
     "" => str
       .split("")
       .filter(|&x| !x.is_empty())
-      .map(|s| Object::String(s.to_string()))
+      .map(|s| Object::String(s.into()))
       .collect::<Vec<Object>>(),
     " " => str
       .split_whitespace()
-      .map(|s| Object::String(s.to_string()))
+      .map(|s| Object::String(s.into()))
       .collect::<Vec<Object>>(),
     _ => str
       .split(&separator)
-      .map(|s| Object::String(s.to_string()))
+      .map(|s| Object::String(s.into()))
       .collect::<Vec<Object>>(),
   };
 
-  Ok(Object::List(result))
+  Ok(Object::list(result))
 }
 
-fn join(args: &Vec<Object>, _env: &mut Rc<RefCell<Environment>>) -> Result<Object, String> {
-  let list = unquote(args);
+fn join(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let list = match unquote(args) {
+    list @ (Object::List(..) | Object::Pair(_)) => list.list_items(),
+    _ => None,
+  };
   let list = match list {
-    Object::List(list) => list,
-    _ => return Ok(Object::Void),
+    Some(list) => list,
+    None => return Ok(Object::Void),
   };
 
   let separator = args.get(1);
   let separator = match separator {
-    Some(Object::String(s)) => s.clone(),
-    _ => "".to_string(),
+    Some(Object::String(s)) => s,
+    _ => "",
   };
 
   let result = list
     .iter()
     .map(|o| o.to_string())
     .collect::<Vec<String>>()
-    .join(&separator);
+    .join(separator);
 
-  Ok(Object::String(result))
+  Ok(Object::String(result.into()))
 }
 
-pub fn load_string_fns(methods: &mut HashMap<String, Rc<RuntimeFn>>) {
-  methods.insert("format".to_string(), Rc::new(format_));
-  methods.insert("split".to_string(), Rc::new(split));
-  methods.insert("join".to_string(), Rc::new(join));
+pub fn load_string_fns(methods: &mut HashMap<String, NativeFn>) {
+  methods.insert(
+    "format".to_string(),
+    NativeFn::new(Arity::at_least(1), format_),
+  );
+  methods.insert(
+    "split".to_string(),
+    NativeFn::new(Arity::between(1, 2), split),
+  );
+  methods.insert(
+    "join".to_string(),
+    NativeFn::new(Arity::between(1, 2), join),
+  );
 }
@@ -0,0 +1,163 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+  environment::Environment,
+  error::{ErrorKind, EvalError},
+  eval,
+  object::{Arity, Object},
+  span::Span,
+};
+
+use super::{list::unquote, NativeFn};
+
+fn new_vector(items: Vec<Object>) -> Object {
+  Object::Vector(Rc::new(RefCell::new(items)))
+}
+
+/// The vector argument at `n` of `name`.
+fn vector<'a>(
+  args: &'a [Object],
+  n: usize,
+  name: &str,
+) -> Result<&'a RefCell<Vec<Object>>, EvalError> {
+  match &args[n] {
+    Object::Vector(items) => Ok(items),
+    o => Err(EvalError::type_error(format!(
+      "Expected a vector for {}, got {}",
+      name, o
+    ))),
+  }
+}
+
+/// The index argument of `name` into `items`, checked to be in range.
+fn index(args: &[Object], items: &[Object], name: &str) -> Result<usize, EvalError> {
+  let i = match &args[1] {
+    Object::Integer(i) => *i,
+    o => {
+      return Err(EvalError::type_error(format!(
+        "Expected an integer index for {}, got {}",
+        name, o
+      )))
+    }
+  };
+
+  match usize::try_from(i) {
+    Ok(i) if i < items.len() => Ok(i),
+    _ => Err(EvalError::new(
+      ErrorKind::IndexOutOfRange,
+      format!(
+        "Index {} is out of range for a vector of length {}",
+        i,
+        items.len()
+      ),
+    )),
+  }
+}
+
+fn make_vector(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  Ok(new_vector(args.to_vec()))
+}
+
+fn vector_ref(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let items = vector(args, 0, "vector-ref")?.borrow();
+  let i = index(args, &items, "vector-ref")?;
+
+  Ok(items[i].clone())
+}
+
+fn vector_set(args: &[Object], _env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut items = vector(args, 0, "vector-set!")?.borrow_mut();
+  let i = index(args, &items, "vector-set!")?;
+  items[i] = args[2].clone();
+
+  Ok(Object::Void)
+}
+
+fn vector_length(
+  args: &[Object],
+  _env: &mut Rc<RefCell<Environment>>,
+) -> Result<Object, EvalError> {
+  let len = vector(args, 0, "vector-length")?.borrow().len();
+
+  Ok(Object::Integer(len as i64))
+}
+
+fn vector_to_list(
+  args: &[Object],
+  _env: &mut Rc<RefCell<Environment>>,
+) -> Result<Object, EvalError> {
+  let items = vector(args, 0, "vector->list")?.borrow().clone();
+
+  Ok(Object::list(items))
+}
+
+fn list_to_vector(
+  args: &[Object],
+  _env: &mut Rc<RefCell<Environment>>,
+) -> Result<Object, EvalError> {
+  let list = unquote(args);
+
+  match list.list_items() {
+    Some(items) => Ok(new_vector(items)),
+    None => Err(EvalError::type_error(format!(
+      "Expected a list for list->vector, got {}",
+      list
+    ))),
+  }
+}
+
+/// `(vector-map f v ...)` calls `f` with the items at each index of the
+/// vectors, up to the length of the shortest, and returns a vector of the
+/// results.
+fn vector_map(args: &[Object], env: &mut Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let f = &args[0];
+  let vectors = (1..args.len())
+    .map(|n| Ok(vector(args, n, "vector-map")?.borrow().clone()))
+    .collect::<Result<Vec<Vec<Object>>, EvalError>>()?;
+  let len = vectors.iter().map(Vec::len).min().unwrap_or(0);
+
+  let mut results = Vec::with_capacity(len);
+  for i in 0..len {
+    let args = vectors.iter().map(|items| items[i].clone()).collect();
+    results.push(eval::call(
+      eval::value_name(f),
+      f,
+      args,
+      &Span::default(),
+      env,
+    )?);
+  }
+
+  Ok(new_vector(results))
+}
+
+pub fn load_vector_fns(methods: &mut HashMap<String, NativeFn>) {
+  methods.insert(
+    "vector".to_string(),
+    NativeFn::new(Arity::at_least(0), make_vector),
+  );
+  methods.insert(
+    "vector-ref".to_string(),
+    NativeFn::new(Arity::exactly(2), vector_ref),
+  );
+  methods.insert(
+    "vector-set!".to_string(),
+    NativeFn::new(Arity::exactly(3), vector_set),
+  );
+  methods.insert(
+    "vector-length".to_string(),
+    NativeFn::new(Arity::exactly(1), vector_length),
+  );
+  methods.insert(
+    "vector->list".to_string(),
+    NativeFn::new(Arity::exactly(1), vector_to_list),
+  );
+  methods.insert(
+    "list->vector".to_string(),
+    NativeFn::new(Arity::exactly(1), list_to_vector),
+  );
+  methods.insert(
+    "vector-map".to_string(),
+    NativeFn::new(Arity::at_least(2), vector_map),
+  );
+}
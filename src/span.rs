@@ -0,0 +1,53 @@
+use std::{fmt, rc::Rc};
+
+/// Location of a token or a parsed form in its source. Lines and columns
+/// start at 1; a span without a file is unknown (e.g. lists built at runtime).
+#[derive(Clone, Debug, Default)]
+pub struct Span {
+  pub file: Option<Rc<str>>,
+  pub line: usize,
+  pub column: usize,
+}
+
+impl Span {
+  pub fn new(file: Rc<str>, line: usize, column: usize) -> Self {
+    Span {
+      file: Some(file),
+      line,
+      column,
+    }
+  }
+
+  pub fn is_known(&self) -> bool {
+    self.file.is_some()
+  }
+}
+
+/// Spans never take part in comparisons: the same form parsed from two
+/// different places is still the same form.
+impl PartialEq for Span {
+  fn eq(&self, _other: &Self) -> bool {
+    true
+  }
+}
+
+impl fmt::Display for Span {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.file {
+      Some(ref file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+      None => write!(f, "<unknown>"),
+    }
+  }
+}
+
+const LOCATION_MARKER: &str = "\n  --> ";
+
+/// Appends the location of `span` to an error message, unless the message
+/// already points at a (more precise) location.
+pub fn locate(err: String, span: &Span) -> String {
+  if !span.is_known() || err.contains(LOCATION_MARKER) {
+    return err;
+  }
+
+  format!("{}{}{}", err, LOCATION_MARKER, span)
+}
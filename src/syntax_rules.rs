@@ -0,0 +1,514 @@
+use std::{cell::Cell, collections::HashMap, rc::Rc};
+
+use crate::{
+  error::EvalError,
+  object::{List, Object},
+};
+
+const ELLIPSIS: &str = "...";
+const WILDCARD: &str = "_";
+
+thread_local! {
+  static RENAME_COUNTER: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A `syntax-rules` transformer: a list of `(pattern template)` rules tried
+/// in order against the macro call.
+#[derive(Debug, PartialEq)]
+pub struct SyntaxRules {
+  literals: Vec<String>,
+  rules: Vec<(Object, Object)>,
+}
+
+/// What a pattern variable matched. Variables under an ellipsis match a
+/// sequence, one element per repetition.
+#[derive(Debug, Clone)]
+enum Binding {
+  One(Object),
+  Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+/// The symbols an expansion renamed, by their original names.
+pub type Renames = HashMap<String, String>;
+
+/// Symbols evaluation reads as syntax rather than look up, which a template
+/// introduces as they are.
+const SYNTAX: [&str; 4] = ["catch", "finally", "syntax-rules", "."];
+
+fn is_ellipsis(obj: &Object) -> bool {
+  matches!(obj, Object::Symbol(s) if s == ELLIPSIS)
+}
+
+impl SyntaxRules {
+  /// Builds the transformer from a `(syntax-rules (literal ...) (pattern template) ...)` form.
+  pub fn new(spec: &Object) -> Result<SyntaxRules, EvalError> {
+    let list = match spec {
+      Object::List(list, _) => list,
+      _ => return Err(EvalError::syntax("Expected a syntax-rules form")),
+    };
+
+    match list.first() {
+      Some(Object::Symbol(s)) if s == "syntax-rules" => {}
+      _ => return Err(EvalError::syntax("Expected a syntax-rules form")),
+    }
+
+    let literals = match list.get(1) {
+      Some(Object::List(literals, _)) => literals
+        .iter()
+        .map(|literal| match literal {
+          Object::Symbol(s) => Ok(s.clone()),
+          o => Err(EvalError::syntax(format!(
+            "Invalid syntax-rules literal: {}",
+            o
+          ))),
+        })
+        .collect::<Result<Vec<String>, EvalError>>()?,
+      _ => {
+        return Err(EvalError::syntax(
+          "Expected a list of literals for syntax-rules",
+        ))
+      }
+    };
+
+    let mut rules = Vec::new();
+    for rule in list[2..].iter() {
+      match rule {
+        Object::List(rule, _) if rule.len() == 2 => match &rule[0] {
+          Object::List(_, _) => rules.push((rule[0].clone(), rule[1].clone())),
+          o => {
+            return Err(EvalError::syntax(format!(
+              "Invalid syntax-rules pattern: {}",
+              o
+            )))
+          }
+        },
+        o => return Err(EvalError::syntax(format!("Invalid syntax rule: {}", o))),
+      }
+    }
+
+    Ok(SyntaxRules { literals, rules })
+  }
+
+  /// Rewrites the macro call `form` with the first rule whose pattern
+  /// matches it. Every symbol the template introduces is renamed, so the
+  /// expansion cannot capture the caller's variables nor be captured by
+  /// them, and the renames are returned along with it as pairs of the
+  /// original name and the new one. A renamed symbol nothing in the
+  /// expansion binds is a free reference, to be looked up under its
+  /// original name where the macro was defined.
+  pub fn expand(&self, form: &[Object]) -> Result<(Object, Renames), EvalError> {
+    for (pattern, template) in self.rules.iter() {
+      let pattern = match pattern {
+        Object::List(pattern, _) => pattern,
+        _ => continue,
+      };
+
+      let mut bindings = Bindings::new();
+
+      // The macro keyword itself is never matched.
+      if pattern.is_empty() || !self.match_list(&pattern[1..], &form[1..], &mut bindings) {
+        continue;
+      }
+
+      let mut renames = Renames::new();
+      let expansion = self.transcribe(template, &bindings, &mut renames, false)?;
+      return Ok((expansion, renames));
+    }
+
+    Err(EvalError::syntax(format!(
+      "Invalid syntax for {}: no rule matches {}",
+      form[0],
+      Object::list(form.to_vec())
+    )))
+  }
+
+  fn match_pattern(&self, pattern: &Object, form: &Object, bindings: &mut Bindings) -> bool {
+    match pattern {
+      Object::Symbol(s) if s == WILDCARD => true,
+      Object::Symbol(s) if self.literals.contains(s) => form == pattern,
+      Object::Symbol(s) => {
+        bindings.insert(s.clone(), Binding::One(form.clone()));
+        true
+      }
+      Object::List(patterns, _) => match form {
+        Object::List(forms, _) => self.match_list(patterns, forms, bindings),
+        _ => false,
+      },
+      _ => form == pattern,
+    }
+  }
+
+  fn match_list(&self, patterns: &[Object], forms: &[Object], bindings: &mut Bindings) -> bool {
+    let ellipsis = match patterns.iter().position(is_ellipsis) {
+      Some(0) | None => {
+        return patterns.len() == forms.len()
+          && patterns
+            .iter()
+            .zip(forms.iter())
+            .all(|(pattern, form)| self.match_pattern(pattern, form, bindings))
+      }
+      Some(position) => position,
+    };
+
+    let before = &patterns[..ellipsis - 1];
+    let repeated = &patterns[ellipsis - 1];
+    let after = &patterns[ellipsis + 1..];
+
+    if forms.len() < before.len() + after.len() {
+      return false;
+    }
+
+    let repeated_forms = &forms[before.len()..forms.len() - after.len()];
+
+    if !self.match_list(before, &forms[..before.len()], bindings)
+      || !self.match_list(after, &forms[forms.len() - after.len()..], bindings)
+    {
+      return false;
+    }
+
+    let mut sequences: HashMap<String, Vec<Binding>> = HashMap::new();
+    for var in self.pattern_vars(repeated) {
+      sequences.insert(var, Vec::new());
+    }
+
+    for form in repeated_forms {
+      let mut repetition = Bindings::new();
+      if !self.match_pattern(repeated, form, &mut repetition) {
+        return false;
+      }
+
+      for (var, binding) in repetition {
+        sequences.entry(var).or_default().push(binding);
+      }
+    }
+
+    for (var, sequence) in sequences {
+      bindings.insert(var, Binding::Many(sequence));
+    }
+
+    true
+  }
+
+  fn pattern_vars(&self, pattern: &Object) -> Vec<String> {
+    match pattern {
+      Object::Symbol(s) if s == WILDCARD || s == ELLIPSIS || self.literals.contains(s) => vec![],
+      Object::Symbol(s) => vec![s.clone()],
+      Object::List(patterns, _) => patterns.iter().flat_map(|p| self.pattern_vars(p)).collect(),
+      _ => vec![],
+    }
+  }
+
+  /// Whether the introduced symbol `s` is renamed: it is not if it is
+  /// syntax, a literal of the macro, or evaluates to itself.
+  fn is_renamed(&self, s: &str) -> bool {
+    !(SYNTAX.contains(&s)
+      || s == ELLIPSIS
+      || s.starts_with(['#', '&', ':'])
+      || self.literals.iter().any(|literal| literal == s))
+  }
+
+  /// Builds the expansion from `template`, renaming the symbols it
+  /// introduces unless they are `quoted` data rather than code.
+  fn transcribe(
+    &self,
+    template: &Object,
+    bindings: &Bindings,
+    renames: &mut Renames,
+    quoted: bool,
+  ) -> Result<Object, EvalError> {
+    match template {
+      Object::Symbol(s) => match bindings.get(s) {
+        Some(Binding::One(form)) => Ok(form.clone()),
+        Some(Binding::Many(_)) => Err(EvalError::syntax(format!(
+          "Pattern variable {} must be followed by ...",
+          s
+        ))),
+        None if !quoted && self.is_renamed(s) => Ok(Object::Symbol(rename(s, renames))),
+        None => Ok(template.clone()),
+      },
+      Object::List(templates, span) => {
+        let mut list = Vec::new();
+        let mut i = 0;
+
+        while i < templates.len() {
+          let element = &templates[i];
+
+          if !templates.get(i + 1).is_some_and(is_ellipsis) {
+            list.push(self.transcribe(element, bindings, renames, quoted)?);
+            i += 1;
+            continue;
+          }
+
+          let vars = sequence_vars(element, bindings);
+          if vars.is_empty() {
+            return Err(EvalError::syntax(format!(
+              "No pattern variables before ... in {}",
+              element
+            )));
+          }
+
+          let lengths = vars
+            .iter()
+            .map(|var| match &bindings[var] {
+              Binding::Many(sequence) => sequence.len(),
+              Binding::One(_) => 0,
+            })
+            .collect::<Vec<usize>>();
+
+          if lengths.iter().any(|len| *len != lengths[0]) {
+            return Err(EvalError::syntax(format!(
+              "Mismatched repetition lengths in {}",
+              element
+            )));
+          }
+
+          for n in 0..lengths[0] {
+            let mut repetition = bindings.clone();
+            for var in vars.iter() {
+              if let Binding::Many(sequence) = &bindings[var] {
+                repetition.insert(var.clone(), sequence[n].clone());
+              }
+            }
+
+            list.push(self.transcribe(element, &repetition, renames, quoted)?);
+          }
+
+          i += 2;
+        }
+
+        Ok(Object::List(List::from(list), span.clone()))
+      }
+      Object::Quote(o)
+      | Object::Quasiquote(o)
+      | Object::Unquote(o)
+      | Object::UnquoteSplicing(o) => {
+        let (wrap, quoted): (fn(Rc<Object>) -> Object, bool) = match template {
+          Object::Quote(_) => (Object::Quote, true),
+          Object::Quasiquote(_) => (Object::Quasiquote, true),
+          Object::Unquote(_) => (Object::Unquote, false),
+          _ => (Object::UnquoteSplicing, false),
+        };
+
+        let inner = self.transcribe(o, bindings, renames, quoted)?;
+        Ok(wrap(Rc::new(inner)))
+      }
+      _ => Ok(template.clone()),
+    }
+  }
+}
+
+fn rename(name: &str, renames: &mut Renames) -> String {
+  renames
+    .entry(name.to_string())
+    .or_insert_with(|| {
+      let id = RENAME_COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        counter.get()
+      });
+
+      format!("{}.{}", name, id)
+    })
+    .clone()
+}
+
+/// Variables of `template` that are bound to a sequence in `bindings`.
+fn sequence_vars(template: &Object, bindings: &Bindings) -> Vec<String> {
+  match template {
+    Object::Symbol(s) => match bindings.get(s) {
+      Some(Binding::Many(_)) => vec![s.clone()],
+      _ => vec![],
+    },
+    Object::List(templates, _) => templates
+      .iter()
+      .flat_map(|t| sequence_vars(t, bindings))
+      .collect(),
+    Object::Quote(o) | Object::Quasiquote(o) | Object::Unquote(o) | Object::UnquoteSplicing(o) => {
+      sequence_vars(o, bindings)
+    }
+    _ => vec![],
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+
+  use crate::environment::Environment;
+  use crate::eval::eval;
+  use crate::runtime::Runtime;
+
+  use super::*;
+
+  fn new_env() -> Rc<RefCell<Environment>> {
+    Rc::new(RefCell::new(Environment::new(Runtime::new())))
+  }
+
+  #[test]
+  fn test_introduced_bindings_are_renamed() {
+    let mut env = new_env();
+    let program = "
+      (define-syntax my-or
+        (syntax-rules ()
+          ((_) #f)
+          ((_ e) e)
+          ((_ e r ...) (let ((t e)) (cond t t #t (my-or r ...))))))
+      (let ((t 5)) (my-or #f t))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result, Object::Integer(5));
+  }
+
+  #[test]
+  fn test_introduced_bindings_ignore_globals() {
+    let mut env = new_env();
+    let program = "
+      (define-syntax swap!
+        (syntax-rules ()
+          ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+      (define-syntax my-or2
+        (syntax-rules ()
+          ((_ a b) (let ((t a)) (cond t t #t b)))))
+      (define-syntax with-params
+        (syntax-rules ()
+          ((_ x) ((lambda (v &optional (w v)) (cons v (cons w x))) 1))))
+      (define tmp 1)
+      (define y 2)
+      (define t 7)
+      (define v 3)
+      (swap! tmp y)";
+    eval(program, &mut env).unwrap();
+
+    assert_eq!(eval("[tmp y]", &mut env).unwrap().to_string(), "[2 1]");
+    assert_eq!(
+      eval("(let ((t 5)) (my-or2 #f t))", &mut env).unwrap(),
+      Object::Integer(5)
+    );
+    assert_eq!(eval("(my-or2 #f t)", &mut env).unwrap(), Object::Integer(7));
+    assert_eq!(
+      eval("(with-params v)", &mut env).unwrap().to_string(),
+      "(1 1 . 3)"
+    );
+  }
+
+  #[test]
+  fn test_free_references_outside_binders() {
+    let mut env = new_env();
+    let program = "
+      (define t 10)
+      (define-syntax m
+        (syntax-rules ()
+          ((_ e) (+ t (let ((t e)) t)))))
+      (m 1)";
+
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(11));
+  }
+
+  #[test]
+  fn test_binders_of_nested_macros_are_renamed() {
+    let mut env = new_env();
+    let program = "
+      (define-syntax my-let1
+        (syntax-rules ()
+          ((_ v e body) (let ((v e)) body))))
+      (define-syntax m2
+        (syntax-rules ()
+          ((_ x) (my-let1 tmp 5 (+ tmp x)))))
+      (let ((tmp 1)) (m2 tmp))";
+
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(6));
+  }
+
+  #[test]
+  fn test_free_references_resolve_where_defined() {
+    let mut env = new_env();
+    let program = "
+      (define-syntax ch
+        (syntax-rules ()
+          ((_ x) (car x))))
+      (define-syntax bump!
+        (syntax-rules ()
+          ((_) (set! counter (+ counter 1)))))
+      (define counter 0)
+      (let ((car (lambda (x) 0)) (counter 5))
+        (bump!)
+        (cons (ch '(1 2)) counter))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result.to_string(), "(1 . 5)");
+    assert_eq!(eval("counter", &mut env).unwrap(), Object::Integer(1));
+  }
+
+  #[test]
+  fn test_free_references_see_later_definitions() {
+    let mut env = new_env();
+    let program = "
+      (define-syntax call-helper
+        (syntax-rules ()
+          ((_ x) (helper x))))
+      (defun helper (x) (* x 10))
+      (call-helper 4)";
+
+    assert_eq!(eval(program, &mut env).unwrap(), Object::Integer(40));
+  }
+
+  #[test]
+  fn test_ellipsis() {
+    let mut env = new_env();
+    let program = "
+      (define-syntax swap-pairs
+        (syntax-rules ()
+          ((_ (a b) ...) '((b a) ...))))
+      (define-syntax last-of
+        (syntax-rules ()
+          ((_ first ... last) last)))";
+    eval(program, &mut env).unwrap();
+
+    let result = eval("(swap-pairs (1 2) (3 4))", &mut env).unwrap();
+    assert_eq!(result.to_string(), "'((2 1) (4 3))");
+
+    let result = eval("(swap-pairs)", &mut env).unwrap();
+    assert_eq!(result.to_string(), "'()");
+
+    let result = eval("(last-of 1 2 3)", &mut env).unwrap();
+    assert_eq!(result, Object::Integer(3));
+  }
+
+  #[test]
+  fn test_literals_and_quoted_symbols() {
+    let mut env = new_env();
+    let program = "
+      (define-syntax arrow
+        (syntax-rules (=>)
+          ((_ a => b) (* a b))
+          ((_ a b) (- a b))
+          ((_) 'done)))";
+    eval(program, &mut env).unwrap();
+
+    assert_eq!(
+      eval("(arrow 3 => 4)", &mut env).unwrap(),
+      Object::Integer(12)
+    );
+    assert_eq!(eval("(arrow 3 4)", &mut env).unwrap(), Object::Integer(-1));
+    assert_eq!(eval("(arrow)", &mut env).unwrap().to_string(), "'done");
+
+    let err = eval("(arrow 1 2 3 4)", &mut env).unwrap_err();
+    assert!(err
+      .message
+      .starts_with("Invalid syntax for arrow: no rule matches (arrow 1 2 3 4)"));
+  }
+
+  #[test]
+  fn test_macroexpand_syntax_rules() {
+    let mut env = new_env();
+    let program = "
+      (define-syntax my-unless
+        (syntax-rules ()
+          ((_ c body ...) (cond c #nil #t (do body ...)))))
+      (macroexpand-1 '(my-unless x (print! 1) 2))";
+
+    let result = eval(program, &mut env).unwrap();
+    assert_eq!(result.to_string(), "'(cond x #nil #t (do (print! 1) 2))");
+  }
+}
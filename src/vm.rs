@@ -0,0 +1,785 @@
+use std::{
+  cell::{Cell, RefCell},
+  rc::Rc,
+};
+
+use crate::bytecode::{compile, Code, Expansion, Op};
+use crate::environment::Environment;
+use crate::error::{ErrorKind, EvalError};
+use crate::eval::{
+  self, alias_renames, apply_operator, assign, bind_params, bind_pattern, define, eval_symbol,
+  new_scope, value_name, Continuation, Unwind, DEPTH,
+};
+use crate::object::{Arity, Lambda, List, Object};
+use crate::span::Span;
+
+/// A function the running one was called from, to return to.
+#[derive(Clone)]
+struct Frame {
+  code: Rc<Code>,
+  pc: usize,
+  env: Rc<RefCell<Environment>>,
+  scopes: Vec<Rc<RefCell<Environment>>>,
+  call: Option<(Rc<Lambda>, Span)>,
+  /// Whether the running code was entered from this frame by a macro
+  /// expansion or `eval` rather than a call, still in the same function.
+  nested: bool,
+}
+
+/// Where the VM was when compiled code called `call/cc`, for the
+/// continuation it made to resume from.
+pub(crate) struct Resume {
+  vm: Vm,
+  /// Whether `call/cc` was called in tail position, the value then being
+  /// returned from the running function.
+  tail: bool,
+}
+
+/// Runs bytecode, as an alternative to the tree-walker in `eval`.
+///
+/// Both share environments and values, so they call each other freely: the
+/// VM hands what it does not compile to the tree-walker, and the tree-walker
+/// runs lambdas made by the VM from their forms. The VM runs a whole
+/// program, so that a continuation captured by compiled code goes on with
+/// the top-level forms after the one it was captured in.
+#[derive(Clone)]
+struct Vm {
+  stack: Vec<Object>,
+  frames: Vec<Frame>,
+  code: Rc<Code>,
+  pc: usize,
+  env: Rc<RefCell<Environment>>,
+  /// The environments the `PushScope`s of the running function replaced.
+  scopes: Vec<Rc<RefCell<Environment>>>,
+  /// The running function and where it was called from, `None` for
+  /// top-level code.
+  call: Option<(Rc<Lambda>, Span)>,
+  /// The top-level forms of the program, the index of the one after the
+  /// running form, and the environment they run in.
+  forms: List,
+  next: usize,
+  top: Rc<RefCell<Environment>>,
+  /// How deep the program started.
+  depth: usize,
+}
+
+fn is_false(value: &Object) -> bool {
+  matches!(value, Object::Bool(false) | Object::Void)
+}
+
+impl Vm {
+  fn new(forms: &List, env: &Rc<RefCell<Environment>>) -> Vm {
+    Vm {
+      stack: Vec::new(),
+      frames: Vec::new(),
+      code: Rc::new(Code {
+        ops: Vec::new(),
+        walks: false,
+        captures: false,
+      }),
+      pc: 0,
+      env: env.clone(),
+      scopes: Vec::new(),
+      call: None,
+      forms: forms.clone(),
+      next: 0,
+      top: env.clone(),
+      depth: DEPTH.with(Cell::get),
+    }
+  }
+
+  /// Runs the forms of the program from `next` on, each compiled just
+  /// before it runs. A form that leaves `call/cc` to the tree-walker, or
+  /// that leaves anything to it once the program has referred to
+  /// `call/cc`, has the rest of the program run by the tree-walker, so that
+  /// the continuations it captures go on to the end.
+  fn program(&mut self) -> Result<Object, EvalError> {
+    let mut captures = false;
+    let mut outcome = Ok(Object::Void);
+
+    loop {
+      let value = match outcome {
+        Ok(value) => value,
+        Err(err) => match Unwind::take_compiled(&err) {
+          Some((k, value)) => {
+            let resume = k.resume().expect("compiled continuations resume the VM");
+            outcome = match self.jump(resume, value) {
+              Some(value) => Ok(value),
+              None => self.run(),
+            };
+            continue;
+          }
+          None => return Err(self.unwind(err)),
+        },
+      };
+
+      let i = self.next;
+      let form = match self.forms.get(i) {
+        Some(form) => form,
+        None => return Ok(value),
+      };
+      let span = self.forms.span_of(i).cloned().unwrap_or_default();
+      let code = compile(form, &span, &self.top);
+      self.next += 1;
+      self.start(code.clone());
+
+      captures |= code.captures;
+      outcome = match code.walks && captures {
+        true => {
+          self.next = self.forms.len();
+          DEPTH.with(|depth| depth.set(self.depth));
+          eval::eval_body(self.forms.tail(i), &self.top)
+        }
+        false => self.run(),
+      };
+    }
+  }
+
+  /// Sets the VM up to run the top-level code `code`.
+  fn start(&mut self, code: Rc<Code>) {
+    self.stack.clear();
+    self.frames.clear();
+    self.code = code;
+    self.pc = 0;
+    self.env = self.top.clone();
+    self.scopes.clear();
+    self.call = None;
+    DEPTH.with(|depth| depth.set(self.depth + 1));
+  }
+
+  /// Goes back to where `call/cc` was called, giving it `value`. Returns a
+  /// value if that ends the form it was called in.
+  fn jump(&mut self, resume: &Resume, value: Object) -> Option<Object> {
+    let depth = self.depth;
+    *self = resume.vm.clone();
+    self.depth = depth;
+
+    let calls = self.frames.iter().filter(|frame| !frame.nested).count();
+    DEPTH.with(|d| d.set(depth + 1 + calls));
+
+    match resume.tail {
+      true => self.ret(value),
+      false => {
+        self.stack.push(value);
+        None
+      }
+    }
+  }
+
+  /// Runs `code` in the running function and environment, as a macro
+  /// expansion or an `eval`. In tail position it replaces the running code.
+  fn enter(&mut self, code: Rc<Code>, tail: bool) {
+    let code = std::mem::replace(&mut self.code, code);
+    let pc = std::mem::replace(&mut self.pc, 0);
+    let scopes = std::mem::take(&mut self.scopes);
+
+    if !tail {
+      self.frames.push(Frame {
+        code,
+        pc,
+        env: self.env.clone(),
+        scopes,
+        call: self.call.clone(),
+        nested: true,
+      });
+    }
+  }
+
+  fn pop(&mut self) -> Object {
+    self.stack.pop().unwrap_or_default()
+  }
+
+  fn run(&mut self) -> Result<Object, EvalError> {
+    let mut code = self.code.clone();
+    let mut reload = false;
+
+    loop {
+      if reload {
+        code = self.code.clone();
+        reload = false;
+      }
+
+      let op = &code.ops[self.pc];
+      self.pc += 1;
+
+      match op {
+        Op::Const(value) => self.stack.push(value.clone()),
+        Op::Load(symbol, span) => {
+          let value = eval_symbol(symbol, &self.env).map_err(|err| err.at(span))?;
+          self.stack.push(value);
+        }
+        Op::Local(depth, index, symbol, span) => {
+          let value = self.env.borrow().get_at(*depth, *index, symbol);
+          let value = match value {
+            Some(value) => value,
+            None => eval_symbol(symbol, &self.env).map_err(|err| err.at(span))?,
+          };
+          self.stack.push(value);
+        }
+        Op::Define(symbol) => {
+          let value = self.pop();
+          define(symbol, value, &self.env);
+          self.stack.push(Object::Void);
+        }
+        Op::Set(symbol, span) => {
+          let value = self.pop();
+          assign(symbol, value, &self.env).map_err(|err| err.at(span))?;
+          self.stack.push(Object::Void);
+        }
+        Op::SetLocal(depth, index, symbol, span) => {
+          let value = self.pop();
+          let unbound = self
+            .env
+            .borrow_mut()
+            .assign_at(*depth, *index, symbol, value);
+          if let Err(value) = unbound {
+            assign(symbol, value, &self.env).map_err(|err| err.at(span))?;
+          }
+          self.stack.push(Object::Void);
+        }
+        Op::Pop => {
+          self.pop();
+        }
+        Op::Jump(target) => self.pc = *target,
+        Op::JumpIfFalse(target) => {
+          if is_false(&self.pop()) {
+            self.pc = *target;
+          }
+        }
+        Op::And(target) => match self.stack.last() {
+          Some(value) if is_false(value) => self.pc = *target,
+          _ => {
+            self.pop();
+          }
+        },
+        Op::Or(target) => match self.stack.last() {
+          Some(value) if !is_false(value) => self.pc = *target,
+          _ => {
+            self.pop();
+          }
+        },
+        Op::Operator(op, argc, span) => {
+          let args = self.stack.split_off(self.stack.len() - argc);
+          let value = apply_operator(op, args).map_err(|err| err.at(span))?;
+          self.stack.push(value);
+        }
+        Op::Expanded {
+          macro_,
+          renames,
+          target,
+        } => match self.stack.last() {
+          Some(value) if value == macro_ => {
+            self.pop();
+            alias_renames(macro_, renames, &self.env);
+          }
+          _ => self.pc = *target,
+        },
+        Op::Expand {
+          form,
+          target,
+          tail,
+          span,
+          cache,
+        } => {
+          if let Some(Object::Macro(_) | Object::Syntax(_, _)) = self.stack.last() {
+            let macro_ = self.pop();
+            let cached = cache.borrow().clone();
+            let expansion = match cached {
+              Some(expansion) if expansion.macro_ == macro_ => expansion,
+              _ => {
+                let expansion = self.expand(&macro_, form, span)?;
+                *cache.borrow_mut() = Some(expansion.clone());
+                expansion
+              }
+            };
+
+            alias_renames(&macro_, &expansion.renames, &self.env);
+            self.pc = *target;
+            self.enter(expansion.code.clone(), *tail);
+            reload = true;
+          }
+        }
+        Op::Call {
+          argc,
+          tail,
+          name,
+          span,
+        } => {
+          if let Some(value) = self.call(*argc, *tail, name, span)? {
+            return Ok(value);
+          }
+          reload = true;
+        }
+        Op::Insert(n) => {
+          let value = self.pop();
+          self.stack.insert(self.stack.len() - n, value);
+        }
+        Op::Closure(lambda) => {
+          let closure = Lambda {
+            env: self.env.clone(),
+            ..(**lambda).clone()
+          };
+          self.stack.push(Object::Lambda(Rc::new(closure)));
+        }
+        Op::PushScope => {
+          let scope = new_scope(&self.env);
+          self.scopes.push(std::mem::replace(&mut self.env, scope));
+        }
+        Op::PopScope => {
+          if let Some(env) = self.scopes.pop() {
+            self.env = env;
+          }
+        }
+        Op::Bind(pattern, span) => {
+          let value = self.pop();
+          bind_pattern(pattern, value, &|value| value, &mut self.env)
+            .map_err(|err| err.at(span))?;
+        }
+        Op::Return => {
+          let value = self.pop();
+          if let Some(value) = self.ret(value) {
+            return Ok(value);
+          }
+          reload = true;
+        }
+        Op::Walk(form, span) => {
+          let value = eval::eval_object(form, &mut self.env.clone()).map_err(|err| err.at(span))?;
+          self.stack.push(value);
+        }
+      }
+    }
+  }
+
+  /// Expands the call `form` of `macro_` and compiles the expansion.
+  fn expand(
+    &self,
+    macro_: &Object,
+    form: &Object,
+    span: &Span,
+  ) -> Result<Rc<Expansion>, EvalError> {
+    let list = match form {
+      Object::List(list, _) => list,
+      _ => return Err(EvalError::type_error("Not a macro call").at(span)),
+    };
+
+    let (expansion, renames) = eval::expand_macro(macro_, list).map_err(|err| err.at(span))?;
+    alias_renames(macro_, &renames, &self.env);
+
+    Ok(Rc::new(Expansion {
+      macro_: macro_.clone(),
+      renames,
+      code: compile(&expansion, span, &self.env),
+    }))
+  }
+
+  /// Returns `value` from the running function to its caller, or from the
+  /// VM when there is none.
+  fn ret(&mut self, value: Object) -> Option<Object> {
+    let frame = match self.frames.pop() {
+      Some(frame) => frame,
+      None => return Some(value),
+    };
+
+    self.code = frame.code;
+    self.pc = frame.pc;
+    self.env = frame.env;
+    self.scopes = frame.scopes;
+    self.call = frame.call;
+    if !frame.nested {
+      DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+
+    self.stack.push(value);
+    None
+  }
+
+  /// Calls the function below `argc` arguments on the stack. Lambdas with
+  /// compiled code run in the VM, anything else is applied by the
+  /// tree-walker. Returns a value if a tail call ended the VM.
+  fn call(
+    &mut self,
+    argc: usize,
+    tail: bool,
+    name: &str,
+    span: &Span,
+  ) -> Result<Option<Object>, EvalError> {
+    let args = self.stack.split_off(self.stack.len() - argc);
+    let f = self.pop();
+
+    let (lambda, code) = match &f {
+      Object::Lambda(lambda) => match &lambda.code {
+        Some(code) => (lambda.clone(), code.clone()),
+        None => return self.apply(name, &f, args, tail, span),
+      },
+      Object::Native(_) | Object::Operator(_) | Object::Continuation(_) => {
+        return self.apply(name, &f, args, tail, span)
+      }
+      _ => {
+        return Err(EvalError::type_error(format!("Invalid head of list to call: {}", f)).at(span))
+      }
+    };
+
+    let mut env = Rc::new(RefCell::new(Environment::extend(lambda.env.clone())));
+    if !tail && DEPTH.with(Cell::get) > env.borrow().max_depth() {
+      return Err(
+        EvalError::new(
+          ErrorKind::RecursionLimit,
+          format!("Maximum recursion depth exceeded in {}", name),
+        )
+        .at(span),
+      );
+    }
+
+    bind_params(name, &lambda.params, args, false, &mut env).map_err(|err| err.at(span))?;
+
+    let caller = Frame {
+      code: std::mem::replace(&mut self.code, code),
+      pc: std::mem::replace(&mut self.pc, 0),
+      env: std::mem::replace(&mut self.env, env),
+      scopes: std::mem::take(&mut self.scopes),
+      call: self.call.replace((lambda, span.clone())),
+      nested: false,
+    };
+
+    if !tail {
+      self.frames.push(caller);
+      DEPTH.with(|depth| depth.set(depth.get() + 1));
+    }
+
+    Ok(None)
+  }
+
+  /// Applies a function that does not run in the VM, returning its value
+  /// from the running function if the call is a tail call.
+  fn apply(
+    &mut self,
+    name: &str,
+    f: &Object,
+    args: Vec<Object>,
+    tail: bool,
+    span: &Span,
+  ) -> Result<Option<Object>, EvalError> {
+    let value = match f {
+      Object::Operator(op) => apply_operator(op, args),
+      Object::Native(native) => match native.as_str() {
+        "eval" | "apply" | "call/cc" | "call-with-current-continuation" => {
+          return self.apply_control(native, args, tail, span)
+        }
+        _ => self.apply_native(native, args),
+      },
+      Object::Continuation(k) if k.resume().is_some() => {
+        Arity::between(0, 1)
+          .check(name, args.len())
+          .map_err(|err| err.at(span))?;
+        let value = args.into_iter().next().unwrap_or_default();
+        let resume = k.resume().expect("checked above");
+        return Ok(self.jump(resume, value));
+      }
+      _ => eval::call(name, f, args, span, &self.env),
+    }
+    .map_err(|err| err.at(span))?;
+
+    match tail {
+      true => Ok(self.ret(value)),
+      false => {
+        self.stack.push(value);
+        Ok(None)
+      }
+    }
+  }
+
+  /// Runs the natives that go on with the evaluation themselves: `eval`
+  /// runs the compiled form in place of the call, `apply` calls its
+  /// function and `call/cc` captures where the VM is.
+  fn apply_control(
+    &mut self,
+    name: &str,
+    args: Vec<Object>,
+    tail: bool,
+    span: &Span,
+  ) -> Result<Option<Object>, EvalError> {
+    let native = match self.env.borrow().get_runtime_fn(name) {
+      Some(native) => native,
+      None => return Err(EvalError::unbound(name).at(span)),
+    };
+    native
+      .arity
+      .check(name, args.len())
+      .map_err(|err| err.at(span))?;
+
+    let (f, args) = match name {
+      "eval" => {
+        let code = compile(&eval::eval_form(&args[0]), span, &self.env);
+        self.enter(code, tail);
+        return Ok(None);
+      }
+      "apply" => eval::spread_args(args).map_err(|err| err.at(span))?,
+      _ => {
+        let resume = Resume {
+          vm: self.clone(),
+          tail,
+        };
+        let k = Continuation::compiled(resume);
+        (args[0].clone(), vec![Object::Continuation(Rc::new(k))])
+      }
+    };
+
+    let argc = args.len();
+    let name = value_name(&f).to_string();
+    self.stack.push(f);
+    self.stack.extend(args);
+    self.call(argc, tail, &name, span)
+  }
+
+  /// Calls a native directly, unless it is one the tree-walker implements
+  /// itself.
+  fn apply_native(&mut self, name: &str, args: Vec<Object>) -> Result<Object, EvalError> {
+    let native = match self.env.borrow().get_runtime_fn(name) {
+      Some(native) => native,
+      None => return Err(EvalError::unbound(name)),
+    };
+
+    match name {
+      "yield" => eval::call(
+        name,
+        &Object::Native(name.to_string()),
+        args,
+        &Span::default(),
+        &self.env,
+      ),
+      _ => {
+        native.arity.check(name, args.len())?;
+        (native.call)(&args, &mut self.env.clone())
+      }
+    }
+  }
+
+  /// Records the calls running when `err` occurred, innermost first.
+  fn unwind(&mut self, err: EvalError) -> EvalError {
+    let calls = std::iter::once(self.call.take()).chain(
+      self
+        .frames
+        .drain(..)
+        .rev()
+        .filter(|frame| !frame.nested)
+        .map(|frame| frame.call),
+    );
+
+    calls.flatten().fold(err, |err, (lambda, span)| {
+      err.called_from(lambda.name(), &span).at(&span)
+    })
+  }
+}
+
+/// Evaluates top-level forms one after another, each compiled just before
+/// it runs.
+pub fn eval_program(forms: &List, env: &Rc<RefCell<Environment>>) -> Result<Object, EvalError> {
+  let mut vm = Vm::new(forms, env);
+  let result = vm.program();
+  DEPTH.with(|depth| depth.set(vm.depth));
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::eval::eval_source;
+  use crate::runtime::{Backend, Runtime};
+
+  fn eval_with(backend: Backend, program: &str) -> Result<String, String> {
+    let runtime = Runtime::new().with_backend(backend).with_max_depth(200);
+    let mut env = Rc::new(RefCell::new(Environment::new(runtime)));
+
+    eval_source(program, "t.tl", &mut env)
+      .map(|value| value.to_string())
+      .map_err(|err| err.to_string())
+  }
+
+  #[test]
+  fn test_same_as_tree_walker() {
+    let programs = [
+      "(+ 1 (* 2 3) (- 10 4))",
+      "(define add-n (lambda (n) (lambda (a) (+ n a)))) ((add-n 2) 3)",
+      "(defun count (n) (cond (= n 0) 'done #t (count (- n 1)))) (count 100000)",
+      "(let loop ((i 0) (acc 0)) (cond (< i 10) (loop (+ i 1) (+ acc i)) #t acc))",
+      "(let* ((a 1) (b (+ a 1))) (list a b)) (defun list (&rest xs) xs) (let* ((a 1) (b (+ a 1))) (list a b))",
+      "(letrec ((ev? (lambda (n) (cond (= n 0) #t #t (od? (- n 1)))))
+                (od? (lambda (n) (cond (= n 0) #f #t (ev? (- n 1))))))
+         (ev? 1001))",
+      "(let (((a b) '(1 2)) (c 3)) (+ a b c))",
+      "(defun f (a &optional (b 2) &rest more &key (k 5)) (+ a b k)) (f 1)",
+      "(define x 1) (set! x (+ x 1)) (do (define x 10) x)",
+      "(list (and 1 #nil 2) (or #f 3) (and) (or #f #f))",
+      "(defmacro twice (e) `(+ ,e ,e)) (twice 21)",
+      "(defun g (x) (twice x)) (defmacro twice (e) `(* ,e 2)) (g 4)",
+      "(try (/ 1 0) (catch e (error-kind e)))",
+      "(+ 1 (call/cc (lambda (k) (* 10 (k 2)))))",
+      "(let/ec k (k 1) 2)",
+      "(define g (generator (yield 1) (yield 2))) (+ (next g) (next g))",
+      "(eval '(+ 1 2))",
+      "(apply + 1 '(2 3))",
+      "(defun inner (x) (/ x 0))\n(defun outer (x) (+ 1 (inner x)))\n(outer 1)",
+      "(defun deep (n) (+ 1 (deep (- n 1)))) (deep 1000)",
+      "(undefined 1)",
+      "(1 2)",
+      "(let ((a 1)) (set! b 2))",
+      "(let (((a . b) (cons 1 2))) (+ a b))",
+      "(define p (cons 1 '())) (set-cdr! p 2) (+ (car p) (cdr p) (car (cdr '(1 2 . 3))))",
+      "(eval (cons '+ (cons 1 (cons 2 '()))))",
+      "(defun f (x) [x (* x 2)]) (vector-ref (f 3) 1)",
+      "(define v [1 2]) (vector-set! v 0 5) (vector-map (lambda (x) (+ x 1)) v)",
+      "(vector-ref [1] 1)",
+      "(defun f (x) {:x x :y (* x 2)}) (get (f 3) :y)",
+      "(define m {}) (get (assoc m '(1 2) 3) (cdr '(0 1 2)))",
+      "(define h (try (lambda (n) (cond (= n 0) 'done #t (g (- n 1)))) (catch e e)))
+       (defun g (n) (h n))
+       (g 100000)",
+      "(define k2 #nil) (define n 0)
+       (+ 1 (call/cc (lambda (k) (set! k2 k) 1)))
+       (set! n (+ n 1))
+       (cond (< n 3) (k2 5) #t n)",
+      "(define k2 #nil)
+       (defun f () (+ 1 (call/cc (lambda (k) (set! k2 k) 1))))
+       (define r (f))
+       (cond (< r 3) (k2 r) #t r)",
+      "(defun list (&rest xs) xs) (define k2 #nil) (define n 0)
+       (defun f () (call/cc (lambda (k) (set! k2 k) 1)))
+       (define r (try (f) (catch e 0)))
+       (set! n (+ n 1))
+       (cond (< n 3) (k2 (+ r 1)) #t (list r n))",
+      "(define k2 #nil) (define n 0)
+       (define r (try (call/cc (lambda (k) (set! k2 k) 1)) (catch e 0)))
+       (set! n (+ n 1))
+       (cond (< n 3) (k2 (+ r 1)) #t r)",
+      "(define k2 #nil) (define n 0)
+       (define r (eval '(call/cc (lambda (k) (set! k2 k) 1))))
+       (set! n (+ n r))
+       (cond (< n 10) (k2 (+ r 1)) #t n)",
+      "(define k2 #nil)
+       (define r (+ 1 (apply call/cc (cons (lambda (k) (set! k2 k) 1) '()))))
+       (cond (< r 4) (k2 r) #t r)",
+      "(define k2 #nil)
+       (defmacro capture (v) `(call/cc (lambda (k) (set! k2 k) ,v)))
+       (defun f () (+ 1 (capture 1)))
+       (define r (f))
+       (cond (< r 3) (k2 r) #t r)",
+      "(define k2 #nil) (+ 1 (call/cc (lambda (k) (set! k2 k) 1))) (k2 1 2)",
+      "(defmacro m (x) `(+ ,x 1)) (defun f (x) (m x)) (f 1) (defmacro m (x) `(* ,x 10)) (f 2)",
+      "(defun f (x) (m x)) (defmacro m (x) `(+ ,x 1)) (f 1) (defmacro m (x) `(* ,x 10)) (f 2)",
+      "(defmacro m (x) `(/ ,x 0)) (defun f (x) (+ 1 (m x))) (f 1)",
+      "(defmacro down (n) `(cond (= ,n 0) 0 #t (+ 1 (down (- ,n 1))))) (down 50)",
+      "(define-syntax swap! (syntax-rules () ((_ a b) (let ((t a)) (set! a b) (set! b t)))))
+       (defun f (x y) (swap! x y) (- x y)) (f 1 2)",
+      "(defun f (n) (eval `(+ ,n 1))) (f 1)",
+    ];
+
+    for program in programs {
+      assert_eq!(
+        eval_with(Backend::Bytecode, program),
+        eval_with(Backend::TreeWalker, program),
+        "{}",
+        program
+      );
+    }
+  }
+
+  #[test]
+  fn test_locals() {
+    let programs = [
+      "(let ((x 1)) (let ((x 2) (y x)) (+ (* 10 x) y)))",
+      "(defun f (x) (let ((y (+ x 1))) (lambda (z) (+ x y z)))) ((f 1) 10)",
+      "(defun counter () (let ((n 0)) (lambda () (set! n (+ n 1)) n))) (define c (counter)) (c) (c)",
+      "(let* ((x 1) (x (+ x 1)) ((a b) '(3 4))) (+ x a b))",
+      "(define z 1) (letrec ((a z) (z 2)) (+ a z))",
+      "(letrec ((a (begin (define w 5) 1)) (b 2)) (+ a b w))",
+      "(let* ((a (do (define q 3) 1)) (b 2)) (+ a b))",
+      "(defun f (x) (define y (* x 2)) (define x 0) (+ x y)) (f 5)",
+      "(defun f (x) (do (define x 7) x)) (f 1)",
+      "(defun f (x) (cond (> x 0) (begin (define r 1) r) #t x)) (f 1)",
+      "(defun f (a) (eval '(define b 2)) (+ a b)) (f 1)",
+      "(let loop ((i 0)) (define seen i) (cond (< i 3) (loop (+ i 1)) #t seen))",
+      "(defun f (&rest xs &key (k 1)) (list xs k)) (defun list (&rest xs) xs) (f :k 2)",
+      "(defun f (x) (set! x (+ x 1)) (set! y 1) x) (f 1)",
+      "(defun f (x) (list :x x)) (defun list (&rest xs) xs) (f 1)",
+    ];
+
+    for program in programs {
+      assert_eq!(
+        eval_with(Backend::Bytecode, program),
+        eval_with(Backend::TreeWalker, program),
+        "{}",
+        program
+      );
+    }
+  }
+
+  #[test]
+  fn test_runtime_defines() {
+    let prelude = "(defun list (&rest xs) xs) (defmacro def-it (name v) `(define ,name ,v))";
+    let programs = [
+      ("(let* ((a (def-it q 3)) (b 2)) (list a b))", "(#nil 2)"),
+      ("(letrec ((a (def-it q 3)) (b 2)) (list a b))", "(#nil 2)"),
+      (
+        "(defun f (x y) (eval '(define x 100)) (list x y)) (f 5 1)",
+        "(100 1)",
+      ),
+      ("(defun f (x) (def-it z (* x 2)) (list x z)) (f 4)", "(4 8)"),
+      (
+        "(defun f (x) (let ((y 1)) (eval '(define x 5)) (list x y))) (f 1)",
+        "(5 1)",
+      ),
+      (
+        "(defun f (x) (let ((y 1)) (def-it x 5) (set! x 6) x)) (f 1)",
+        "6",
+      ),
+      (
+        "(defun f (x) (let ((y 1)) (def-it x 5) (set! x 6)) x) (f 1)",
+        "1",
+      ),
+      (
+        "(defun f (n) (eval '(define n 0)) n) (list (f 1) (f 2))",
+        "(0 0)",
+      ),
+      (
+        "(let loop ((i 0)) (def-it j i) (cond (< i 2) (loop (+ i 1)) #t (list i j)))",
+        "(2 2)",
+      ),
+    ];
+
+    for (program, expected) in programs {
+      let program = format!("{} {}", prelude, program);
+      let expected = Ok(expected.to_string());
+      assert_eq!(
+        eval_with(Backend::TreeWalker, &program),
+        expected,
+        "{}",
+        program
+      );
+      assert_eq!(
+        eval_with(Backend::Bytecode, &program),
+        expected,
+        "{}",
+        program
+      );
+    }
+  }
+
+  #[test]
+  fn test_macros_expand_once() {
+    let programs = [
+      "(define n 0) (defmacro m (x) (set! n (+ n 1)) x) (defun f (x) (m x)) (f 1) (f 2) (f 3) n",
+      "(define n 0) (defun f (x) (m x)) (defmacro m (x) (set! n (+ n 1)) x) (f 1) (f 2) (f 3) n",
+      "(define n 0) (defmacro m (x) (set! n (+ n 1)) x)
+       (defun f (i) (cond (< i 3) (m (f (+ i 1))) #t n)) (f 0)",
+    ];
+
+    for program in programs {
+      assert_eq!(
+        eval_with(Backend::Bytecode, program),
+        Ok("1".to_string()),
+        "{}",
+        program
+      );
+    }
+  }
+}